@@ -0,0 +1,191 @@
+//! Bandwidth caps for the transfers, and the times of day they apply to.
+
+use std::{
+	sync::{
+		Arc,
+		atomic::{AtomicU64, Ordering},
+	},
+	time::Duration,
+};
+
+use chrono::{NaiveTime, TimeDelta};
+use tokio::{
+	sync::Mutex,
+	time::{Instant, sleep},
+};
+
+use crate::config::{AppConfig, BandwidthWindow};
+
+/// The bandwidth window matching the given time, if any.
+pub fn active_window(config: &AppConfig, t: NaiveTime) -> Option<&BandwidthWindow> {
+	config.bandwidth_windows.iter().find(|w| w.contains(t))
+}
+
+/// The bandwidth cap in KiB/s at the given time.
+pub fn bandwidth_limit(config: &AppConfig, t: NaiveTime) -> Option<u64> {
+	active_window(config, t)
+		.and_then(|w| w.limit_kib)
+		.or(config.bandwidth_limit_kib)
+}
+
+/// How long the syncs started by polling should wait, i.e. until the end of
+/// the bandwidth window deferring them.
+pub fn polling_deferral(config: &AppConfig, t: NaiveTime) -> Option<Duration> {
+	let window = active_window(config, t).filter(|w| w.defer_polling)?;
+	let left = window.end.0 - t;
+	let left = if left < TimeDelta::zero() {
+		left + TimeDelta::days(1)
+	} else {
+		left
+	};
+	left.to_std().ok()
+}
+
+/// Token bucket shared by the concurrent downloads of a sync job, holding
+/// at most one second worth of bytes.
+#[derive(Debug)]
+pub struct TokenBucket {
+	/// Bytes per second
+	rate: f64,
+	/// Available bytes, negative if the last download overdrew them, and
+	/// the time they were counted
+	tokens: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+	pub fn new(limit_kib: u64) -> Self {
+		let rate = (limit_kib * 1024) as f64;
+		Self {
+			rate,
+			tokens: Mutex::new((rate, Instant::now())),
+		}
+	}
+
+	/// Take `bytes` from the bucket, waiting until it is refilled if it is
+	/// overdrawn. The lock is held while waiting, so that the downloads are
+	/// served in turn.
+	pub async fn consume(&self, bytes: usize) {
+		let mut tokens = self.tokens.lock().await;
+		let now = Instant::now();
+		let refilled = tokens.0 + now.duration_since(tokens.1).as_secs_f64() * self.rate;
+		*tokens = (refilled.min(self.rate) - bytes as f64, now);
+		if tokens.0 < 0.0 {
+			sleep(Duration::from_secs_f64(-tokens.0 / self.rate)).await;
+		}
+	}
+}
+
+/// The bandwidth cap shared by the sync jobs of all repositories, so that
+/// they do not each use it in full. There is one per daemon, like the
+/// semaphore limiting the rsync instances.
+#[derive(Debug, Default)]
+pub struct SharedBandwidth {
+	/// rsync instances of the sync jobs transferring files
+	rsync_instances: Arc<AtomicU64>,
+	/// Token bucket of the metadata downloads, and the cap it is made for
+	bucket: std::sync::Mutex<Option<(u64, Arc<TokenBucket>)>>,
+}
+
+/// rsync instances of a sync job counted in [`SharedBandwidth`], until it is
+/// dropped.
+pub struct RsyncShare {
+	instances: u64,
+	total: Arc<AtomicU64>,
+}
+
+impl Drop for RsyncShare {
+	fn drop(&mut self) {
+		self.total.fetch_sub(self.instances, Ordering::Relaxed);
+	}
+}
+
+impl SharedBandwidth {
+	/// The token bucket of the metadata downloads under `limit`, replaced
+	/// if the cap changed.
+	pub fn bucket(&self, limit: Option<u64>) -> Option<Arc<TokenBucket>> {
+		let limit = limit?;
+		let mut bucket = self.bucket.lock().unwrap();
+		match &*bucket {
+			Some((l, b)) if *l == limit => Some(b.clone()),
+			_ => {
+				let b = Arc::new(TokenBucket::new(limit));
+				*bucket = Some((limit, b.clone()));
+				Some(b)
+			}
+		}
+	}
+
+	/// Count the rsync instances a sync job runs at the same time.
+	pub fn join(&self, instances: u64) -> RsyncShare {
+		self.rsync_instances.fetch_add(instances, Ordering::Relaxed);
+		RsyncShare {
+			instances,
+			total: self.rsync_instances.clone(),
+		}
+	}
+
+	/// The share of `limit` of an rsync instance starting now, among the
+	/// instances of all sync jobs, up to `max_transfers` of them. It can
+	/// not be changed once rsync runs, so the instances started before
+	/// another sync job joined keep their share.
+	pub fn rsync_limit(&self, limit: Option<u64>, max_transfers: Option<usize>) -> Option<u64> {
+		let instances = self
+			.rsync_instances
+			.load(Ordering::Relaxed)
+			.min(max_transfers.unwrap_or(usize::MAX) as u64)
+			.max(1);
+		limit.map(|l| (l / instances).max(1))
+	}
+}
+
+#[test]
+fn test_bandwidth_limit() {
+	let mut config = crate::config::test_config(
+		r#"
+bandwidth_limit_kib = 10240
+
+[[bandwidth_window]]
+start = "22:00"
+end = "06:00"
+defer_polling = true
+
+[[bandwidth_window]]
+start = "08:00"
+end = "20:00"
+limit_kib = 1024
+"#,
+	);
+	let at = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+	assert_eq!(bandwidth_limit(&config, at("12:00")), Some(1024));
+	assert_eq!(bandwidth_limit(&config, at("20:00")), Some(10240));
+	assert_eq!(bandwidth_limit(&config, at("23:30")), Some(10240));
+	assert!(active_window(&config, at("05:59")).is_some_and(|w| w.defer_polling));
+	assert!(active_window(&config, at("06:00")).is_none());
+	assert_eq!(
+		polling_deferral(&config, at("23:30")),
+		Some(Duration::from_secs(6 * 3600 + 1800))
+	);
+	assert_eq!(polling_deferral(&config, at("12:00")), None);
+	config.bandwidth_limit_kib = None;
+	assert_eq!(bandwidth_limit(&config, at("21:00")), None);
+}
+
+#[test]
+fn test_shared_bandwidth() {
+	let bandwidth = SharedBandwidth::default();
+	let a = bandwidth.join(2);
+	assert_eq!(bandwidth.rsync_limit(Some(1000), None), Some(500));
+	// Another repository transferring at the same time
+	let b = bandwidth.join(3);
+	assert_eq!(bandwidth.rsync_limit(Some(1000), None), Some(200));
+	assert_eq!(bandwidth.rsync_limit(Some(1000), Some(4)), Some(250));
+	drop(a);
+	assert_eq!(bandwidth.rsync_limit(Some(1000), None), Some(333));
+	drop(b);
+	assert_eq!(bandwidth.rsync_limit(Some(1000), None), Some(1000));
+	assert_eq!(bandwidth.rsync_limit(None, None), None);
+	let bucket = bandwidth.bucket(Some(1000)).unwrap();
+	assert!(Arc::ptr_eq(&bucket, &bandwidth.bucket(Some(1000)).unwrap()));
+	assert!(!Arc::ptr_eq(&bucket, &bandwidth.bucket(Some(500)).unwrap()));
+	assert!(bandwidth.bucket(None).is_none());
+}
@@ -1,32 +1,60 @@
 use std::{
 	env,
 	fs::read_to_string,
-	net::SocketAddr,
 	path::{Path, PathBuf},
 	sync::Arc,
+	time::Duration,
 };
 
-use aosc_mirror::{metadata::split_inrelease, server::Status, sync::do_sync_inner, *};
+use aosc_mirror::{
+	bandwidth::SharedBandwidth,
+	cancel::CancelToken,
+	events::{DEFAULT_EVENT_BUFFER_SIZE, EventLog, SyncEvent},
+	fsck::{queue_broken_files, verify_mirror},
+	gc::collect_garbage,
+	listener::{PeerAddr, TcpPeerListener, TlsListener, UnixPeerListener},
+	lock::MirrorLock,
+	metadata::split_inrelease,
+	metrics::Metrics,
+	plan::plan_sync,
+	reload::reload_config,
+	server::Status,
+	state::{PersistentState, save_state},
+	sync::{cancel_sync, do_sync_inner},
+	tls::{configure_client_tls, load_server_config},
+	upstream::healthy_upstreams,
+	*,
+};
 
 use anyhow::{Context, Result, anyhow, bail};
-use base64::prelude::*;
-use chrono::Utc;
+use chrono::{DateTime, Local, Utc};
+#[allow(unused_imports)]
 use clap::{Parser, Subcommand, command};
-use config::AppConfig;
-use ed25519_dalek::VerifyingKey;
-use log::{error, info};
+use config::{AppConfig, ListenAddr};
+use futures_util::{StreamExt, future::join_all};
+use log::{error, info, warn};
 use metadata::fetch_manifest;
 use reqwest::{Client, redirect::Policy};
-use server::build_server;
+use server::{build_repos_server, build_server};
+use systemd::ActivatedSocket;
 use tokio::{
 	fs::{rename, symlink},
 	net::TcpListener,
-	sync::RwLock,
+	signal::unix::{Signal, SignalKind, signal},
+	sync::{RwLock, Semaphore, watch},
 	task::{JoinHandle, JoinSet},
+	time::{Instant, sleep},
+};
+use url::Url;
+use verify::{
+	decode_pubkeys, decode_signing_key, init_pgp_keyringstore, sign_action,
+	verify_pgp_signature,
 };
-use verify::{init_pgp_keyringstore, verify_pgp_signature};
 
-use crate::{config::check_config, metadata::AptRepoReleaseInfo};
+use crate::{
+	config::{check_configs, format_config_errors, load_configs},
+	metadata::AptRepoReleaseInfo,
+};
 pub use server::SyncRequestBody;
 
 #[cfg(not(target_env = "msvc"))]
@@ -40,17 +68,91 @@ static GLOBAL: Jemalloc = Jemalloc;
 /// The Mirror Sync Client
 pub enum AppAction {
 	/// Perform the full sync
-	Sync,
+	Sync {
+		/// Only sync the repository with the given name, defaults to all
+		/// repositories in the config file
+		#[arg(short, long)]
+		repo: Option<String>,
+	},
+	/// Show what a sync would download and delete, without touching the
+	/// mirror
+	Plan {
+		/// Only plan for the repository with the given name, defaults to
+		/// all repositories in the config file
+		#[arg(short, long)]
+		repo: Option<String>,
+		/// Print the plan as JSON
+		#[arg(long)]
+		json: bool,
+		/// Directory to download the metadata to, kept afterwards.
+		/// Defaults to a temporary directory
+		#[arg(long)]
+		scratch_dir: Option<PathBuf>,
+	},
+	/// Check the published mirror for missing and corrupted files, and print
+	/// a report in JSON
+	Verify {
+		/// Only check the repository with the given name, defaults to all
+		/// repositories in the config file
+		#[arg(short, long)]
+		repo: Option<String>,
+		/// Also check the SHA256 checksums of the pool files
+		#[arg(long)]
+		sha256: bool,
+		/// Queue the broken files for re-download on the next sync
+		#[arg(long)]
+		queue: bool,
+	},
+	/// Remove the files not referenced by the published snapshot, without
+	/// contacting the upstream
+	Gc {
+		/// Only clean up the repository with the given name, defaults to
+		/// all repositories in the config file
+		#[arg(short, long)]
+		repo: Option<String>,
+		/// Only report what would be removed
+		#[arg(long)]
+		dry_run: bool,
+		/// Print the report as JSON
+		#[arg(long)]
+		json: bool,
+	},
 	/// Start the daemon and listen to the sync requests
 	Daemon,
+	/// Follow the sync events of a running daemon
+	Tail {
+		/// Path to the private key file of the origin server
+		#[arg(short, long)]
+		private_key: PathBuf,
+		/// URL of the events endpoint, defaults to the first listening
+		/// address in the config file
+		#[arg(short, long)]
+		url: Option<Url>,
+		/// Name of the repository to follow, if the config file has
+		/// [[repo]] tables
+		#[arg(short, long)]
+		repo: Option<String>,
+		/// Client certificate for mutual TLS (PEM)
+		#[arg(long)]
+		client_cert: Option<PathBuf>,
+		/// Private key of the client certificate (PEM, PKCS#8)
+		#[arg(long)]
+		client_key: Option<PathBuf>,
+		/// Additional CA certificate to trust (PEM)
+		#[arg(long)]
+		ca_cert: Option<PathBuf>,
+	},
 }
 
+/// Exit code when the running sync had to be rolled back on shutdown.
+const EXIT_SYNC_ROLLED_BACK: i32 = 2;
+
 #[derive(Parser)]
 #[command(version, about)]
 pub struct Cmdline {
 	#[arg(short = 'c', long = "config")]
-	/// Path to the config file
-	pub config_file: PathBuf,
+	/// Path to the config file, only optional for `tail` with --url
+	pub config_file: Option<PathBuf>,
 	#[command(subcommand)]
 	/// Action to execute
 	pub action: AppAction,
@@ -75,77 +177,207 @@ fn check_repo(root: &dyn AsRef<Path>, manifests: Vec<AptRepoReleaseInfo>) -> boo
 	true
 }
 
-async fn consume_handles(mut rx: JoinHandleReceiver) -> Result<()> {
-	while let Some(h) = rx.recv().await {
-		info!("New sync task spawned.");
-		std::mem::drop(h);
+/// Time to wait before reconnecting to the /events endpoint, doubled after
+/// each failed attempt up to [`TAIL_MAX_BACKOFF`].
+const TAIL_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const TAIL_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Connect to the /events endpoint and print the events as they come.
+/// Reconnects and resumes from the last received event if the connection
+/// is lost or fails, waiting longer after each failed attempt. Gives up if
+/// the request is refused (4xx), e.g. for an invalid signature.
+async fn tail(url: Url, private_key: PathBuf, client: Client) -> Result<()> {
+	let mut key = decode_signing_key(
+		&read_to_string(&private_key).context("Failed to read the private key file")?,
+	)?;
+	let mut last_id: u64 = 0;
+	let mut backoff = TAIL_MIN_BACKOFF;
+	loop {
+		let timestamp = Utc::now().timestamp();
+		let signature = sign_action(&mut key, "events", timestamp);
+		let mut req = client.get(url.clone()).query(&[
+			("timestamp", timestamp.to_string()),
+			("signature", signature),
+		]);
+		if last_id > 0 {
+			req = req.header("Last-Event-ID", last_id.to_string());
+		}
+		let res = match req.send().await {
+			Ok(res) if res.status().is_success() => Some(res),
+			Ok(res) => {
+				let status = res.status();
+				let body = res.text().await.unwrap_or_default();
+				let e = anyhow!(body)
+					.context(format!("{} returned {}", url, status));
+				if status.is_client_error() {
+					return Err(e);
+				}
+				warn!("{:#}", e);
+				None
+			}
+			Err(e) => {
+				warn!("Failed to connect to {}: {:#}", url, anyhow!(e));
+				None
+			}
+		};
+		let Some(res) = res else {
+			info!("Reconnecting in {} seconds ...", backoff.as_secs());
+			sleep(backoff).await;
+			backoff = (backoff * 2).min(TAIL_MAX_BACKOFF);
+			continue;
+		};
+		backoff = TAIL_MIN_BACKOFF;
+		let mut stream = res.bytes_stream();
+		let mut buf = Vec::new();
+		while let Some(chunk) = stream.next().await {
+			let chunk = match chunk {
+				Ok(c) => c,
+				Err(e) => {
+					warn!("Connection lost: {}", e);
+					break;
+				}
+			};
+			buf.extend_from_slice(&chunk);
+			while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+				let line = buf.drain(..=pos).collect::<Vec<_>>();
+				let line = String::from_utf8_lossy(&line);
+				let data = if let Some(d) = line.trim_end().strip_prefix("data:") {
+					d
+				} else {
+					continue;
+				};
+				let event: SyncEvent = match serde_json::from_str(data.trim_start())
+				{
+					Ok(e) => e,
+					Err(e) => {
+						warn!("Invalid event: {}", e);
+						continue;
+					}
+				};
+				last_id = event.id;
+				let time = DateTime::from_timestamp(event.time, 0)
+					.unwrap_or_default()
+					.with_timezone(&Local);
+				println!("[{}] {}", time.format("%Y-%m-%d %H:%M:%S"), event);
+			}
+		}
+		info!("Reconnecting in {} seconds ...", backoff.as_secs());
+		sleep(backoff).await;
+	}
+}
+
+/// Resolves once SIGTERM or SIGINT is received.
+async fn wait_for_signal() -> Result<()> {
+	let mut sigterm = signal(SignalKind::terminate())?;
+	let mut sigint = signal(SignalKind::interrupt())?;
+	tokio::select! {
+		_ = sigterm.recv() => info!("Received SIGTERM."),
+		_ = sigint.recv() => info!("Received SIGINT."),
 	}
 	Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-	env_logger::builder()
-		.filter_level(log::LevelFilter::Info)
-		.parse_default_env()
-		.init();
-	// console_subscriber::init();
-	info!("AOSC OS Mirror Sync Client");
-	info!("Please wait, while we perform some checks ...");
-	let cmdline = match Cmdline::try_parse() {
-		Result::Ok(args) => args,
-		Err(e) => {
-			// Do not let anyhow to handle this error
-			eprintln!("{}", e);
-			// let it handle this "error" instead
-			bail!("Invalid usage");
+/// Stop accepting sync requests, then let the running syncs finish within
+/// `shutdown_timeout` seconds. The syncs are rolled back if they take
+/// longer, or if another signal is received. Returns the exit code.
+async fn shutdown(states: &[Arc<RwLock<AppState>>], timeout: u64) -> Result<i32> {
+	let mut tasks = Vec::new();
+	for state in states {
+		let mut lock = state.write().await;
+		lock.shutting_down = true;
+		if lock.pending_timestamp.take().is_some() {
+			info!("Dropping the queued sync request.");
 		}
-	};
-	let config_file = &cmdline.config_file;
-	let argv0 = env::args().next().unwrap_or("sync-client".into());
-
-	let config: AppConfig = toml::from_str(&read_to_string(config_file)?)
-		.context("Unable to read the config file")?;
-	let config = Arc::new(config);
+		tasks.extend(lock.sync_task.clone());
+	}
+	let mut code = 0;
+	if !tasks.is_empty() {
+		info!(
+			"Waiting up to {} seconds for the running sync to finish ...",
+			timeout
+		);
+		let deadline = Instant::now() + Duration::from_secs(timeout);
+		let finished = join_all(tasks.into_iter().map(|t| t.finished()));
+		let rollback = tokio::select! {
+			_ = finished => false,
+			_ = tokio::time::sleep_until(deadline) => {
+				warn!("The sync did not finish in time.");
+				true
+			}
+			_ = wait_for_signal() => {
+				warn!("Received another signal, not waiting any more.");
+				true
+			}
+		};
+		if rollback {
+			for state in states {
+				if cancel_sync(state.clone(), "Interrupted by shutdown").await {
+					code = EXIT_SYNC_ROLLED_BACK;
+				}
+			}
+		}
+	}
+	for state in states {
+		save_state(&*state.read().await);
+	}
+	info!("Shutdown complete.");
+	Ok(code)
+}
 
-	let errors = check_config(&config);
-	if !errors.is_empty() {
-		let mut error_str = String::from("Error(s) found in config file:\n");
-		for e in errors {
-			// Build error string
-			let mut chain = 1;
-			error_str.push_str(&format!("- {}\n", e));
-			e.chain().skip(1).for_each(|c| {
-				error_str.push_str(&format!("{}> {}\n", "  ".repeat(chain), c));
-				chain += 1;
-			});
+/// Reload the configuration of every repository on each SIGHUP.
+async fn reload_on_sighup(states: Vec<Arc<RwLock<AppState>>>, mut sighup: Signal) {
+	while sighup.recv().await.is_some() {
+		info!("Received SIGHUP.");
+		if let Err(e) = systemd::notify_reloading() {
+			warn!("{:#}", e);
+		}
+		for state in &states {
+			if let Err(e) = reload_config(state).await {
+				error!("Failed to reload the configuration: {:#}", e);
+			}
+		}
+		if let Err(e) = systemd::notify("READY=1") {
+			warn!("{:#}", e);
 		}
-		error!("{}", error_str);
-		bail!("Error(s) found in the config file. Refer to the log above for details.")
 	}
+}
 
-	// Deserialize server public keys
-	let mut server_pubkeys = Vec::new();
-	for pubkey in &config.server_pubkeys {
-		let bytes = BASE64_STANDARD
-			.decode(pubkey)
-			.context("Failed to decode server public key as base64 text")?;
-		let bytes: [u8; 32] = bytes.try_into().map_err(|_| anyhow!("Unexpected length; Public keys must be 32 bytes long (that is 45 charaters in base64 with padding"))?;
-		let pubkey = VerifyingKey::from_bytes(&bytes)?;
-		server_pubkeys.push(pubkey);
+async fn consume_handles(mut rx: JoinHandleReceiver) -> Result<()> {
+	while let Some(h) = rx.recv().await {
+		info!("New sync task spawned.");
+		// Repositories are synced independently.
+		tokio::spawn(async move {
+			match h.await {
+				Ok(()) => {}
+				Err(e) if e.is_cancelled() => info!("Sync task cancelled."),
+				Err(e) => error!("Sync task panicked: {}", e),
+			}
+		});
 	}
-	let server_pubkeys = Arc::new(server_pubkeys);
+	Ok(())
+}
+
+/// Check the config and the upstream repository of one repository, then
+/// build its state. Returns the state and the release info of each suite.
+async fn init_repo(
+	config: Arc<AppConfig>,
+	config_file: &Path,
+	client: &Client,
+	sender: &JoinHandleSender,
+	transfers: &Arc<Semaphore>,
+	bandwidth: &Arc<SharedBandwidth>,
+) -> Result<(Arc<RwLock<AppState>>, Vec<AptRepoReleaseInfo>)> {
+	// Deserialize server public keys
+	let server_pubkeys = Arc::new(decode_pubkeys(&config.server_pubkeys)?);
 
 	// Initialize the APT trusted keystore.
 	let keyring_dir = &config.keyring_dir;
 	let keyring_store = init_pgp_keyringstore(keyring_dir).await?;
 	let keyring_store = Arc::new(keyring_store);
 
-	let base_url = config.http_url.clone();
-	let client = Client::builder()
-		.user_agent("Debian APT-HTTP/1.3 (3.0.1)")
-		.redirect(Policy::limited(10))
-		.build()?;
+	// With fallback upstreams, check the first healthy one.
+	let upstreams = healthy_upstreams(&config, client, &keyring_store).await?;
+	let base_url = upstreams[0].http_url.clone();
 	// Download the InRelease files before starting, and make sure it can be verified
 	// by the keys from the given keystore.
 	info!("Checking the validity of the repository metadata ...");
@@ -158,7 +390,7 @@ async fn main() -> Result<()> {
 				suite, &base_url
 			);
 			let (inrelease, release) =
-				fetch_manifest(base_url.clone(), suite.clone(), &client).await?;
+				fetch_manifest(base_url.clone(), suite.clone(), client).await?;
 			let info = if let Some(inrelease) = inrelease {
 				let (inrelease_body, inrelease_sig) = split_inrelease(&inrelease);
 				verify_pgp_signature(
@@ -200,16 +432,7 @@ async fn main() -> Result<()> {
 		}
 	}
 	if !errors.is_empty() {
-		let mut error_str = String::from("Error(s) found in config file:\n");
-		for e in errors {
-			let mut chain = 1;
-			error_str.push_str(&format!("- {}\n", e));
-			e.chain().skip(1).for_each(|c| {
-				error_str.push_str(&format!("{}> {}\n", "  ".repeat(chain), c));
-				chain += 1;
-			});
-		}
-		error!("{}", error_str);
+		error!("{}", format_config_errors(errors));
 		bail!(
 			"Your config file does not align with the upstream repository. See the log above for details."
 		)
@@ -220,6 +443,7 @@ async fn main() -> Result<()> {
 	// If dists/ is a directory, move it to dists-{cur_timestamp} and make a symlink to that.
 	let dists = config.mirror_root.join("dists");
 	if dists.exists() && !dists.is_symlink() && dists.is_dir() {
+		let _lock = MirrorLock::acquire(&config.mirror_root, "init", now)?;
 		info!("Replacing dists/ with a symlink to dists-{}/ ...", now - 1);
 		let new_name = config.mirror_root.join(format!("dists-{}", now - 1));
 		rename(&dists, &new_name)
@@ -232,56 +456,537 @@ async fn main() -> Result<()> {
 		))?;
 	}
 
-	let (tx, rx) = tokio::sync::mpsc::channel::<JoinHandle<()>>(100);
+	let saved_state = match PersistentState::load(&config.mirror_root) {
+		Ok(s) => s,
+		Err(e) => {
+			warn!("Unable to restore the sync state: {:#}", e);
+			None
+		}
+	};
+	let saved_state = saved_state.unwrap_or(PersistentState {
+		last_sync_timestamp: now,
+		last_request_timestamp: None,
+		last_sync_status: Status::Success,
+		last_sync_message: String::new(),
+		last_transfer: None,
+		last_upstream: None,
+		last_push: None,
+	});
+
 	// Mutable shared state to share across different async tasks.
 	let state = Arc::new(RwLock::new(AppState {
 		syncing: false,
+		pending_timestamp: None,
+		current_timestamp: None,
+		sync_task: None,
+		shutting_down: false,
 		config: config.clone(),
-		last_sync_timestamp: now,
-		last_sync_status: server::Status::Success,
-		last_sync_message: String::new(),
+		config_path: config_file.to_owned(),
+		last_sync_timestamp: saved_state.last_sync_timestamp,
+		last_request_timestamp: saved_state.last_request_timestamp,
+		last_sync_status: saved_state.last_sync_status,
+		last_sync_message: saved_state.last_sync_message,
+		last_transfer: saved_state.last_transfer,
+		last_upstream: saved_state.last_upstream,
+		last_push: saved_state.last_push,
 		server_pubkeys,
 		keyring_store,
-		client,
-		sender: tx.clone(),
+		metrics: Arc::new(Metrics::new()),
+		events: Arc::new(EventLog::new(DEFAULT_EVENT_BUFFER_SIZE)),
+		transfers: transfers.clone(),
+		bandwidth: bandwidth.clone(),
+		client: client.clone(),
+		sender: sender.clone(),
 	}));
+	Ok((state, manifests))
+}
+
+/// Print the plans of the repositories. The live tree is never touched, so
+/// this runs without the preparations in [`init_repo`].
+async fn plan(
+	configs: &[AppConfig],
+	repo: Option<&str>,
+	json: bool,
+	scratch_dir: Option<&Path>,
+	client: &Client,
+) -> Result<()> {
+	if let Some(name) = repo
+		&& !configs.iter().any(|c| c.name.as_deref() == Some(name))
+	{
+		bail!("No such repository: {}", name);
+	}
+	let scratch = match scratch_dir {
+		Some(dir) => dir.to_path_buf(),
+		None => env::temp_dir().join(format!("aosc-mirror-plan-{}", std::process::id())),
+	};
+	let mut plans = Vec::new();
+	let mut result = Ok(());
+	for config in configs {
+		if repo.is_some() && config.name.as_deref() != repo {
+			continue;
+		}
+		let scratch = match &config.name {
+			Some(name) => scratch.join(name),
+			None => scratch.clone(),
+		};
+		info!("Planning the sync in {} ...", scratch.display());
+		match plan_sync(config, client, &scratch).await {
+			Ok(plan) => plans.push(plan),
+			Err(e) => {
+				result = Err(e);
+				break;
+			}
+		}
+	}
+	if scratch_dir.is_none()
+		&& scratch.exists()
+		&& let Err(e) = std::fs::remove_dir_all(&scratch)
+	{
+		warn!("Unable to remove {}: {}", scratch.display(), e);
+	}
+	result?;
+	if json {
+		println!("{}", serde_json::to_string_pretty(&plans)?);
+	} else {
+		for plan in plans {
+			println!("{}", plan);
+		}
+	}
+	Ok(())
+}
+
+/// Check the repositories and print the reports. Fails if any file is
+/// broken.
+async fn verify(
+	configs: &[AppConfig],
+	repo: Option<&str>,
+	sha256: bool,
+	queue: bool,
+) -> Result<()> {
+	if let Some(name) = repo
+		&& !configs.iter().any(|c| c.name.as_deref() == Some(name))
+	{
+		bail!("No such repository: {}", name);
+	}
+	let mut reports = Vec::new();
+	let mut broken = 0;
+	for config in configs {
+		if repo.is_some() && config.name.as_deref() != repo {
+			continue;
+		}
+		if let Some(name) = &config.name {
+			info!("Checking repository '{}' ...", name);
+		}
+		let report = verify_mirror(config, sha256).await?;
+		if queue {
+			queue_broken_files(config, &report)?;
+		}
+		broken += report.broken.len();
+		reports.push(report);
+	}
+	println!("{}", serde_json::to_string_pretty(&reports)?);
+	if broken > 0 {
+		bail!("{} broken files found", broken);
+	}
+	Ok(())
+}
+
+/// Clean up the repositories and print the reports.
+async fn gc(configs: &[AppConfig], repo: Option<&str>, dry_run: bool, json: bool) -> Result<()> {
+	if let Some(name) = repo
+		&& !configs.iter().any(|c| c.name.as_deref() == Some(name))
+	{
+		bail!("No such repository: {}", name);
+	}
+	let mut reports = Vec::new();
+	for config in configs {
+		if repo.is_some() && config.name.as_deref() != repo {
+			continue;
+		}
+		if let Some(name) = &config.name {
+			info!("Cleaning up repository '{}' ...", name);
+		}
+		reports.push(collect_garbage(config, dry_run).await?);
+	}
+	if json {
+		println!("{}", serde_json::to_string_pretty(&reports)?);
+	} else {
+		for report in reports {
+			print!("{}", report);
+		}
+	}
+	Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+	env_logger::builder()
+		.filter_level(log::LevelFilter::Info)
+		.parse_default_env()
+		.init();
+	// console_subscriber::init();
+	info!("AOSC OS Mirror Sync Client");
+	info!("Please wait, while we perform some checks ...");
+	let cmdline = match Cmdline::try_parse() {
+		Result::Ok(args) => args,
+		Err(e) => {
+			// Do not let anyhow to handle this error
+			eprintln!("{}", e);
+			// let it handle this "error" instead
+			bail!("Invalid usage");
+		}
+	};
+	let argv0 = env::args().next().unwrap_or("sync-client".into());
+
+	if let AppAction::Tail {
+		private_key,
+		url,
+		repo,
+		client_cert,
+		client_key,
+		ca_cert,
+	} = cmdline.action
+	{
+		let url = match url {
+			Some(u) => u,
+			None => {
+				let config_file = cmdline
+					.config_file
+					.context("Either the config file or --url is required")?;
+				let configs = load_configs(&config_file)?;
+				let config = &configs[0];
+				let addr = config
+					.listen
+					.iter()
+					.find_map(|a| match a {
+						ListenAddr::Tcp(addr) => Some(addr),
+						ListenAddr::Unix(_) => None,
+					})
+					.context("No TCP listening addresses in the config file")?;
+				let scheme = if config.tls_cert.is_some() {
+					"https"
+				} else {
+					"http"
+				};
+				let prefix = match (repo, &config.name) {
+					(Some(repo), _) => format!("/repos/{}", repo),
+					(None, None) => String::new(),
+					(None, Some(_)) if configs.len() == 1 => {
+						format!(
+							"/repos/{}",
+							config.name.as_deref().unwrap()
+						)
+					}
+					(None, Some(_)) => {
+						bail!(
+							"Multiple repositories are configured, specify one with --repo"
+						)
+					}
+				};
+				Url::parse(&format!("{}://{}{}/events", scheme, addr, prefix))?
+			}
+		};
+		let client = configure_client_tls(
+			Client::builder().user_agent("aosc-mirror/0.1.0"),
+			client_cert.as_deref(),
+			client_key.as_deref(),
+			ca_cert.as_deref(),
+		)?
+		.build()?;
+		return tail(url, private_key, client).await;
+	}
+
+	let config_file = cmdline
+		.config_file
+		.as_deref()
+		.context("The config file is required, specify it with -c")?;
+	let configs = load_configs(config_file)?;
+	// Settings of the daemon itself are the same in every repository.
+	let config = Arc::new(configs[0].clone());
+
+	let errors = check_configs(&configs);
+	if !errors.is_empty() {
+		error!("{}", format_config_errors(errors));
+		bail!("Error(s) found in the config file. Refer to the log above for details.")
+	}
+
+	let client = Client::builder()
+		.user_agent("Debian APT-HTTP/1.3 (3.0.1)")
+		.redirect(Policy::limited(10))
+		.build()?;
+	if let AppAction::Plan {
+		repo,
+		json,
+		scratch_dir,
+	} = &cmdline.action
+	{
+		return plan(
+			&configs,
+			repo.as_deref(),
+			*json,
+			scratch_dir.as_deref(),
+			&client,
+		)
+		.await;
+	}
+	if let AppAction::Verify {
+		repo,
+		sha256,
+		queue,
+	} = &cmdline.action
+	{
+		return verify(&configs, repo.as_deref(), *sha256, *queue).await;
+	}
+	if let AppAction::Gc {
+		repo,
+		dry_run,
+		json,
+	} = &cmdline.action
+	{
+		return gc(&configs, repo.as_deref(), *dry_run, *json).await;
+	}
+	let (tx, rx) = tokio::sync::mpsc::channel::<JoinHandle<()>>(100);
+	let transfers = Arc::new(Semaphore::new(
+		config.max_transfers.unwrap_or(Semaphore::MAX_PERMITS),
+	));
+	let bandwidth = Arc::new(SharedBandwidth::default());
+	// Only prepare the requested repository for a one-shot sync.
+	let only_repo = match &cmdline.action {
+		AppAction::Sync { repo: Some(name) } => {
+			if !configs.iter().any(|c| c.name.as_ref() == Some(name)) {
+				bail!("No such repository: {}", name);
+			}
+			Some(name.clone())
+		}
+		_ => None,
+	};
+	let mut repos = Vec::new();
+	for config in configs {
+		if only_repo.is_some() && config.name != only_repo {
+			continue;
+		}
+		if let Some(name) = &config.name {
+			info!("Preparing repository '{}' ...", name);
+		}
+		let (state, manifests) = init_repo(
+			Arc::new(config),
+			config_file,
+			&client,
+			&tx,
+			&transfers,
+			&bandwidth,
+		)
+		.await?;
+		repos.push((state, manifests));
+	}
 	match cmdline.action {
 		AppAction::Daemon => {
 			info!("Checking the repository ...");
-			if !check_repo(&config.mirror_root, manifests) {
-				bail!("Looks like you don't have a full copy of the mirrored repository.\n".to_owned() +
-				"Please run the following command to initialize a full copy:\n\n" +
-				&format!("{} -c {} sync", argv0, config_file.display()));
-			};
+			let mut states = Vec::new();
+			for (state, manifests) in repos {
+				let config = state.read().await.config.clone();
+				if !check_repo(&config.mirror_root, manifests) {
+					let repo_arg = match &config.name {
+						Some(name) => format!(" --repo {}", name),
+						None => String::new(),
+					};
+					bail!("Looks like you don't have a full copy of the mirrored repository.\n".to_owned() +
+					"Please run the following command to initialize a full copy:\n\n" +
+					&format!("{} -c {} sync{}", argv0, config_file.display(), repo_arg));
+				};
+				states.push(state);
+			}
 			// Start the server
 			info!("Starting server ...");
 			tokio::spawn(async move { consume_handles(rx).await });
-			let s = build_server(state)
-				.into_make_service_with_connect_info::<SocketAddr>();
+			let router = if config.name.is_some() {
+				let mut repos = Vec::new();
+				for state in &states {
+					let name = state
+						.read()
+						.await
+						.config
+						.name
+						.clone()
+						.unwrap_or_default();
+					info!("Serving repository '{}' at /repos/{}", name, name);
+					repos.push((name, state.clone()));
+				}
+				build_repos_server(repos)
+			} else {
+				build_server(states[0].clone())
+			};
+			let s = router.into_make_service_with_connect_info::<PeerAddr>();
+			let tls_config = match (&config.tls_cert, &config.tls_key) {
+				(Some(cert), Some(key)) => {
+					info!("TLS is enabled.");
+					if config.tls_client_ca.is_some() {
+						info!("Clients must present a valid certificate.");
+					}
+					Some(load_server_config(
+						cert,
+						key,
+						config.tls_client_ca.as_deref(),
+					)?)
+				}
+				_ => None,
+			};
+			let (stop_tx, stop_rx) = watch::channel(false);
+			// Prefer the sockets passed by systemd over the configured ones.
+			let mut tcp_listeners = Vec::new();
+			let mut unix_listeners = Vec::new();
+			for socket in systemd::listen_fds()? {
+				match socket {
+					ActivatedSocket::Tcp(listener) => {
+						listener.set_nonblocking(true)?;
+						let listener = TcpListener::from_std(listener)?;
+						info!(
+							"Listening on {} (passed by systemd)",
+							listener.local_addr()?
+						);
+						tcp_listeners.push(listener);
+					}
+					ActivatedSocket::Unix(listener) => {
+						info!(
+							"Listening on {:?} (passed by systemd)",
+							listener.local_addr()?
+						);
+						unix_listeners.push(UnixPeerListener::from_std(
+							listener,
+						)?);
+					}
+				}
+			}
+			if tcp_listeners.is_empty() && unix_listeners.is_empty() {
+				for addr in &config.listen {
+					match addr {
+						ListenAddr::Tcp(addr) => {
+							let listener = TcpListener::bind(addr)
+								.await
+								.context(format!(
+									"Failed to bind to {}",
+									addr
+								))?;
+							tcp_listeners.push(listener);
+						}
+						ListenAddr::Unix(path) => {
+							unix_listeners.push(
+								UnixPeerListener::bind(
+									path,
+									config.unix_socket_mode,
+									config.unix_socket_owner
+										.as_deref(),
+									config.unix_socket_group
+										.as_deref(),
+								)?,
+							);
+						}
+					}
+					info!("Listening on {}", addr);
+				}
+			}
 			let mut tasks = JoinSet::new();
-			// let mut tasks = Vec::new();
-			for addr in &config.listen {
-				let listener = TcpListener::bind(addr)
-					.await
-					.context(format!("Failed to bind to {}", addr))?;
-				info!("Listening on {}", addr);
+			let stop = || {
+				let mut stop_rx = stop_rx.clone();
+				async move {
+					let _ = stop_rx.wait_for(|v| *v).await;
+				}
+			};
+			for listener in tcp_listeners {
+				let s = s.clone();
+				let stop = stop();
+				if let Some(tls_config) = &tls_config {
+					let listener =
+						TlsListener::new(listener, tls_config.clone())?;
+					tasks.spawn(async move {
+						axum::serve(listener, s)
+							.with_graceful_shutdown(stop)
+							.await
+					});
+				} else {
+					let listener = TcpPeerListener(listener);
+					tasks.spawn(async move {
+						axum::serve(listener, s)
+							.with_graceful_shutdown(stop)
+							.await
+					});
+				}
+			}
+			// Local peers are trusted to be on the same host, TLS is not
+			// needed on UNIX domain sockets.
+			for listener in unix_listeners {
 				let s = s.clone();
-				tasks.spawn(async move { axum::serve(listener, s).await });
+				let stop = stop();
+				tasks.spawn(async move {
+					axum::serve(listener, s).with_graceful_shutdown(stop).await
+				});
+			}
+			for state in &states {
+				tokio::spawn(poll::run_poller(state.clone()));
 			}
+			tokio::spawn(reload_on_sighup(
+				states.clone(),
+				signal(SignalKind::hangup())?,
+			));
 			info!("Sync server started, waiting for requests ...");
-			while let Some(r) = tasks.join_next().await {
-				r??;
+			systemd::notify("READY=1\nSTATUS=Idle, waiting for requests")?;
+			if let Some(interval) = systemd::watchdog_interval() {
+				info!("Pinging the systemd watchdog every {:?}.", interval);
+				tokio::spawn(systemd::run_watchdog(interval, states.clone()));
 			}
+			tokio::select! {
+				r = async {
+					while let Some(r) = tasks.join_next().await {
+						r??;
+					}
+					anyhow::Ok(())
+				} => {
+					r?;
+					return Ok(());
+				}
+				r = wait_for_signal() => r?,
+			}
+			info!("Shutting down, no longer accepting requests ...");
+			systemd::notify("STOPPING=1")?;
+			let _ = stop_tx.send(true);
+			let code = shutdown(&states, config.shutdown_timeout).await?;
+			// Long-lived connections (e.g. /events) would keep the
+			// listeners alive forever.
+			tasks.shutdown().await;
+			std::process::exit(code);
 		}
-		AppAction::Sync => {
-			do_sync_inner(state.clone(), now).await;
-			let lock = state.read().await;
-			if lock.last_sync_status == Status::Failed {
-				let e = anyhow!(lock.last_sync_message.clone())
-					.context("Sync job failed");
-				bail!(e);
+		AppAction::Sync { .. } => {
+			let mut failed = Vec::new();
+			for (state, _) in repos {
+				let config = state.read().await.config.clone();
+				if let Some(name) = &config.name {
+					info!("Syncing repository '{}' ...", name);
+				}
+				do_sync_inner(
+					state.clone(),
+					Utc::now().timestamp(),
+					&CancelToken::default(),
+				)
+				.await;
+				let lock = state.read().await;
+				if lock.last_sync_status == Status::Failed {
+					let e = anyhow!(lock.last_sync_message.clone())
+						.context("Sync job failed");
+					if config.name.is_none() {
+						bail!(e);
+					}
+					error!("{:#}", e);
+					failed.push(config.name.clone().unwrap_or_default());
+				}
+			}
+			if !failed.is_empty() {
+				bail!("Failed to sync the repositories: {}", failed.join(", "));
 			}
 		}
+		AppAction::Tail { .. }
+		| AppAction::Plan { .. }
+		| AppAction::Verify { .. }
+		| AppAction::Gc { .. } => unreachable!(),
 	}
 	Ok(())
 }
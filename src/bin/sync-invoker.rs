@@ -1,27 +1,31 @@
 use std::{
 	env,
-	fs::{File, create_dir_all},
-	io::BufWriter,
-	path::PathBuf,
-	sync::Arc,
+	fs::{File, create_dir_all, rename},
+	io::{BufWriter, Write},
+	path::{Path, PathBuf},
 	time::Duration,
 };
 
 use anyhow::{Context, Result, anyhow, bail};
-use aosc_mirror::server::SyncRequestBody;
+use aosc_mirror::{
+	invoke::{InvocationReport, RetryPolicy, invoke_endpoints, wait_for_syncs},
+	metrics::write_metric,
+	server::{SyncRequestBody, SyncRequestResponse},
+	tls::configure_client_tls,
+	verify::{decode_signing_key, sign_action},
+};
 use base64::prelude::*;
 use chrono::{Local, Utc};
+#[allow(unused_imports)]
 use clap::{Parser, Subcommand, command};
-use ed25519_dalek::{SECRET_KEY_LENGTH, SigningKey, ed25519::signature::SignerMut};
+use ed25519_dalek::{SECRET_KEY_LENGTH, SigningKey};
 use log::{error, info};
 use rand::{TryRngCore, rngs::OsRng};
 use reqwest::{Client, redirect::Policy};
-use serde::Serialize;
 use serde_json::json;
 use tokio::{
 	fs::read_to_string,
 	io::{AsyncReadExt, stdin},
-	task::JoinSet,
 };
 use url::Url;
 
@@ -51,9 +55,83 @@ enum Action {
 		/// Number of concurrent jobs
 		#[arg(short, long, default_value = "4")]
 		jobs: u8,
+		/// Times to retry an endpoint failing with a connection error, a
+		/// timeout or a server error
+		#[arg(long, default_value = "3")]
+		retries: u32,
+		/// Seconds to wait before the first retry, doubled for each retry
+		/// after it
+		#[arg(long, default_value = "1")]
+		backoff: u64,
+		/// Write metrics of this invocation to the given file, for the
+		/// node_exporter textfile collector
+		#[arg(short, long)]
+		metrics_file: Option<PathBuf>,
+		/// Wait for the syncs to finish, and add their results to the
+		/// report
+		#[arg(short, long)]
+		wait: bool,
+		/// Seconds to wait for the syncs to finish in total, with --wait
+		#[arg(long, default_value = "3600", requires = "wait")]
+		deadline: u64,
+		/// Client certificate for mutual TLS (PEM)
+		#[arg(long)]
+		client_cert: Option<PathBuf>,
+		/// Private key of the client certificate (PEM, PKCS#8)
+		#[arg(long)]
+		client_key: Option<PathBuf>,
+		/// Additional CA certificate to trust (PEM)
+		#[arg(long)]
+		ca_cert: Option<PathBuf>,
 		/// List of endpoints
 		endpoints: Option<Vec<Url>>,
 	},
+	/// Cancel the running sync on the given endpoints
+	Cancel {
+		/// Path to the private key file
+		#[arg(short, long)]
+		private_key: PathBuf,
+		/// Path to the list of endpoints to cancel
+		#[arg(short, long)]
+		endpoint_list: Option<PathBuf>,
+		#[arg(short = 'T', long, default_value = "10")]
+		/// Max connection time for a timeout
+		timeout: u32,
+		/// Client certificate for mutual TLS (PEM)
+		#[arg(long)]
+		client_cert: Option<PathBuf>,
+		/// Private key of the client certificate (PEM, PKCS#8)
+		#[arg(long)]
+		client_key: Option<PathBuf>,
+		/// Additional CA certificate to trust (PEM)
+		#[arg(long)]
+		ca_cert: Option<PathBuf>,
+		/// List of endpoints (the /cancel URLs)
+		endpoints: Option<Vec<Url>>,
+	},
+	/// Reload the configuration and keyrings on the given endpoints
+	Reload {
+		/// Path to the private key file
+		#[arg(short, long)]
+		private_key: PathBuf,
+		/// Path to the list of endpoints to reload
+		#[arg(short, long)]
+		endpoint_list: Option<PathBuf>,
+		#[arg(short = 'T', long, default_value = "10")]
+		/// Max connection time for a timeout
+		timeout: u32,
+		/// Client certificate for mutual TLS (PEM)
+		#[arg(long)]
+		client_cert: Option<PathBuf>,
+		/// Private key of the client certificate (PEM, PKCS#8)
+		#[arg(long)]
+		client_key: Option<PathBuf>,
+		/// Additional CA certificate to trust (PEM)
+		#[arg(long)]
+		ca_cert: Option<PathBuf>,
+		/// List of endpoints (the /reload URLs)
+		endpoints: Option<Vec<Url>>,
+	},
 }
 
 #[derive(Parser, Debug)]
@@ -64,69 +142,184 @@ struct Args {
 	action: Action,
 }
 
-#[derive(Serialize, PartialEq)]
-#[serde(tag = "status", rename_all = "lowercase")]
-enum ClientInvocationStatus {
-	Succeeded,
-	Failed { reason: String },
-}
-
-#[derive(Serialize)]
-struct ReportEntry {
-	endpoint: Url,
-	#[serde(flatten)]
-	status: ClientInvocationStatus,
-}
-
-#[derive(Serialize)]
-struct InvocationReport {
-	req_timestamp: i64,
-	start_timestamp: i64,
-	end_timestamp: i64,
-	num_clients: u32,
-	num_succeeded: u32,
-	num_failed: u32,
-	endpoints: Vec<ReportEntry>,
+/// Collect the endpoints from the list file and the command line.
+async fn read_endpoints(
+	endpoint_list: Option<PathBuf>,
+	endpoints: Option<Vec<Url>>,
+) -> Result<Vec<Url>> {
+	let mut endpoints_vec = Vec::new();
+	if let Some(l) = endpoint_list {
+		let endpoints_fd = read_to_string(l)
+			.await
+			.context("Failed to read the endpoints list file")?;
+		for endpoint in endpoints_fd.lines() {
+			let url = Url::parse(endpoint)
+				.context(format!("Failed to parse '{}' as a URL", endpoint))?;
+			endpoints_vec.push(url);
+		}
+	}
+	if let Some(l) = endpoints {
+		endpoints_vec.extend(l);
+	};
+	if endpoints_vec.is_empty() {
+		bail!("No endpoints specified");
+	}
+	Ok(endpoints_vec)
 }
 
-async fn invoke_queue(queue: Vec<Url>, client: Client, body: Arc<String>) -> Vec<ReportEntry> {
-	let mut status_list = Vec::new();
-	for endpoint in queue {
-		let body = body.as_ref();
-		let res = match client
+/// POST a request signed for the given action to each endpoint in turn.
+/// Returns the number of failed endpoints.
+async fn post_signed(
+	client: &Client,
+	mut private_key: SigningKey,
+	action: &str,
+	endpoints: Vec<Url>,
+) -> usize {
+	let mut num_failed = 0;
+	for endpoint in endpoints {
+		// Sign each request, cancelling or reloading might take a while.
+		let timestamp = Utc::now().timestamp();
+		let body = SyncRequestBody {
+			timestamp,
+			signature: sign_action(&mut private_key, action, timestamp),
+		};
+		let res = client
 			.post(endpoint.clone())
 			.header("Content-Type", "application/json")
-			.body(body.clone())
+			.body(json!(body).to_string())
 			.send()
-			.await
-		{
+			.await;
+		let res = match res {
 			Ok(r) => r,
 			Err(e) => {
 				error!("FAILED: {} ({})", endpoint, e);
-				let status = ClientInvocationStatus::Failed {
-					reason: e.to_string(),
-				};
-				status_list.push(ReportEntry { endpoint, status });
+				num_failed += 1;
 				continue;
 			}
 		};
-		match res.error_for_status_ref() {
-			Ok(_) => {
-				info!("SUCCEED: {}", endpoint);
-				let status = ClientInvocationStatus::Succeeded;
-				status_list.push(ReportEntry { endpoint, status });
-			}
-			Err(e) => {
-				error!("FAILED: {} ({})", endpoint, e);
-				let status = ClientInvocationStatus::Failed {
-					reason: e.to_string(),
-				};
-				status_list.push(ReportEntry { endpoint, status });
-			}
+		let code = res.status();
+		let text = res.text().await.unwrap_or_default();
+		let message = match serde_json::from_str::<SyncRequestResponse>(&text) {
+			Ok(r) => r.message,
+			Err(_) => text,
+		};
+		if code.is_success() {
+			info!("OK: {} ({})", endpoint, message);
+		} else {
+			error!("FAILED: {} ({}: {})", endpoint, code, message);
+			num_failed += 1;
 		}
-		tokio::time::sleep(Duration::from_millis(250)).await;
 	}
-	status_list
+	num_failed
+}
+
+/// Write the metrics to a temporary file first, then move it into place, so
+/// that the textfile collector never reads a partially written file.
+fn write_metrics_file(path: &Path, report: &InvocationReport) -> Result<()> {
+	let mut buf = String::new();
+	write_metric(
+		&mut buf,
+		"aosc_mirror_invoker_last_invocation_timestamp_seconds",
+		"gauge",
+		"UNIX timestamp of the last invocation.",
+		report.end_timestamp,
+	);
+	write_metric(
+		&mut buf,
+		"aosc_mirror_invoker_request_timestamp",
+		"gauge",
+		"Timestamp sent with the last sync request.",
+		report.req_timestamp,
+	);
+	write_metric(
+		&mut buf,
+		"aosc_mirror_invoker_duration_seconds",
+		"gauge",
+		"Time spent invoking all endpoints.",
+		report.end_timestamp - report.start_timestamp,
+	);
+	write_metric(
+		&mut buf,
+		"aosc_mirror_invoker_endpoints",
+		"gauge",
+		"Number of endpoints invoked.",
+		report.num_clients,
+	);
+	write_metric(
+		&mut buf,
+		"aosc_mirror_invoker_endpoints_succeeded",
+		"gauge",
+		"Number of endpoints accepted the sync request.",
+		report.num_succeeded,
+	);
+	write_metric(
+		&mut buf,
+		"aosc_mirror_invoker_endpoints_failed",
+		"gauge",
+		"Number of endpoints failed to accept the sync request.",
+		report.num_failed,
+	);
+	if let Some(num_synced) = report.num_synced {
+		write_metric(
+			&mut buf,
+			"aosc_mirror_invoker_endpoints_synced",
+			"gauge",
+			"Number of endpoints finished the sync successfully.",
+			num_synced,
+		);
+	}
+	let file_name = path
+		.file_name()
+		.context(format!("Invalid metrics file path {}", path.display()))?;
+	let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+	let mut fd = File::options()
+		.create(true)
+		.truncate(true)
+		.write(true)
+		.open(&tmp_path)?;
+	fd.write_all(buf.as_bytes())?;
+	fd.sync_all()?;
+	rename(&tmp_path, path)?;
+	Ok(())
+}
+
+/// Sign the given action (cancel or reload), then POST it to the endpoints.
+#[allow(clippy::too_many_arguments)]
+async fn post_action(
+	action: &str,
+	private_key: PathBuf,
+	endpoint_list: Option<PathBuf>,
+	timeout: u32,
+	client_cert: Option<PathBuf>,
+	client_key: Option<PathBuf>,
+	ca_cert: Option<PathBuf>,
+	endpoints: Option<Vec<Url>>,
+) -> Result<()> {
+	env_logger::builder()
+		.filter_level(log::LevelFilter::Info)
+		.parse_default_env()
+		.init();
+	let endpoints = read_endpoints(endpoint_list, endpoints).await?;
+	let private_key = decode_signing_key(
+		&read_to_string(private_key)
+			.await
+			.context("Failed to read the private key file")?,
+	)?;
+	let client = configure_client_tls(
+		Client::builder()
+			.timeout(Duration::from_secs(timeout.into()))
+			.redirect(Policy::limited(10))
+			.user_agent("aosc-mirror/0.1.0"),
+		client_cert.as_deref(),
+		client_key.as_deref(),
+		ca_cert.as_deref(),
+	)?
+	.build()?;
+	let num_failed = post_signed(&client, private_key, action, endpoints).await;
+	if num_failed > 0 {
+		bail!("Failed to {} on {} endpoint(s)", action, num_failed);
+	}
+	Ok(())
 }
 
 #[tokio::main]
@@ -181,103 +374,54 @@ async fn main() -> Result<()> {
 			timestamp,
 			report_dir,
 			jobs,
+			retries,
+			backoff,
 			timeout,
+			metrics_file,
+			wait,
+			deadline,
+			client_cert,
+			client_key,
+			ca_cert,
 			endpoints,
 		} => {
 			env_logger::builder()
 				.filter_level(log::LevelFilter::Info)
 				.parse_default_env()
 				.init();
-			let mut endpoints_vec = Vec::new();
-			if let Some(l) = endpoint_list {
-				let endpoints_fd = read_to_string(l)
-					.await
-					.context("Failed to read the endpoints list file")?;
-				for endpoint in endpoints_fd.lines() {
-					let url = Url::parse(endpoint).context(format!(
-						"Failed to parse '{}' as a URL",
-						endpoint
-					))?;
-					endpoints_vec.push(url);
-				}
-			}
-			if let Some(l) = endpoints {
-				endpoints_vec.extend(l);
-			};
-			if endpoints_vec.is_empty() {
-				bail!("No endpoints specified");
-			}
-
-			let private_key_fd = read_to_string(private_key)
-				.await
-				.context("Failed to read the private key file")?;
-			let key = BASE64_STANDARD.decode(private_key_fd.trim())?;
-			let bytes = key.try_into().map_err(|_| {
-				anyhow!("Unexpected length; Private keys must be 32 bytes long")
-			})?;
-			let mut private_key = SigningKey::from_bytes(&bytes);
-			let sig = BASE64_STANDARD.encode({
-				private_key
-					.sign(timestamp.to_string().as_bytes())
-					.to_bytes()
-			});
-			info!("Timestamp: {}", timestamp);
-			info!("Signature: {}", sig);
-			let req_body = Arc::new(
-				json!(SyncRequestBody {
-					timestamp,
-					signature: sig,
-				})
-				.to_string(),
-			);
+			let endpoints_vec = read_endpoints(endpoint_list, endpoints).await?;
 
-			let client = Client::builder()
-				.timeout(Duration::from_secs(timeout.into()))
-				.redirect(Policy::limited(10))
-				.user_agent("aosc-mirror/0.1.0")
-				.build()?;
-
-			let actual_num_jobs = endpoints_vec.len().clamp(1, jobs.into());
-			let mut queues = Vec::new();
-			for _ in 1..=actual_num_jobs {
-				let queue = Vec::<Url>::new();
-				queues.push(queue);
-			}
-			let len = endpoints_vec.len();
-			for (q_idx, endpoint) in endpoints_vec.into_iter().enumerate() {
-				let queue = queues.get_mut(q_idx).unwrap();
-				queue.push(endpoint);
-			}
-			info!(
-				"Invoking {} clients with {} parallel jobs",
-				len, actual_num_jobs
-			);
-			let start_timestamp = Utc::now().timestamp();
-			let mut tasks = JoinSet::new();
-			for queue in queues {
-				let client = client.clone();
-				let body = req_body.clone();
-				tasks.spawn(async move { invoke_queue(queue, client, body).await });
-			}
-			let mut results = Vec::new();
-			while let Some(t) = tasks.join_next().await {
-				results.extend(t?);
+			let mut private_key = decode_signing_key(
+				&read_to_string(private_key)
+					.await
+					.context("Failed to read the private key file")?,
+			)?;
+			let client = configure_client_tls(
+				Client::builder()
+					.timeout(Duration::from_secs(timeout.into()))
+					.redirect(Policy::limited(10))
+					.user_agent("aosc-mirror/0.1.0"),
+				client_cert.as_deref(),
+				client_key.as_deref(),
+				ca_cert.as_deref(),
+			)?
+			.build()?;
+			let mut report = invoke_endpoints(
+				&client,
+				&mut private_key,
+				timestamp,
+				endpoints_vec,
+				jobs.into(),
+				RetryPolicy {
+					retries,
+					backoff: Duration::from_secs(backoff),
+				},
+			)
+			.await?;
+			if wait {
+				wait_for_syncs(&client, &mut report, Duration::from_secs(deadline))
+					.await;
 			}
-			let report = InvocationReport {
-				req_timestamp: timestamp,
-				start_timestamp,
-				end_timestamp: Utc::now().timestamp(),
-				num_clients: len as u32,
-				num_succeeded: results
-					.iter()
-					.filter(|r| r.status == ClientInvocationStatus::Succeeded)
-					.count() as u32,
-				num_failed: results
-					.iter()
-					.filter(|r| r.status != ClientInvocationStatus::Succeeded)
-					.count() as u32,
-				endpoints: results,
-			};
 			info!("Done invoking clients. Generating report ...");
 			let localdate = Local::now();
 			let localdate = localdate.format("%Y%m%d").to_string();
@@ -294,8 +438,58 @@ async fn main() -> Result<()> {
 				.open(path)?;
 			let writer = BufWriter::new(fd);
 			serde_json::to_writer_pretty(writer, &report)?;
+			if let Some(path) = metrics_file {
+				info!("Writing metrics to {} ...", path.display());
+				write_metrics_file(&path, &report)
+					.context("Failed to write the metrics file")?;
+			}
 			info!("Generation complete. Program Finished.");
+			if report.num_failed > 0 {
+				bail!("Failed to invoke {} endpoint(s)", report.num_failed);
+			}
 			Ok(())
 		}
+		Action::Cancel {
+			private_key,
+			endpoint_list,
+			timeout,
+			client_cert,
+			client_key,
+			ca_cert,
+			endpoints,
+		} => {
+			post_action(
+				"cancel",
+				private_key,
+				endpoint_list,
+				timeout,
+				client_cert,
+				client_key,
+				ca_cert,
+				endpoints,
+			)
+			.await
+		}
+		Action::Reload {
+			private_key,
+			endpoint_list,
+			timeout,
+			client_cert,
+			client_key,
+			ca_cert,
+			endpoints,
+		} => {
+			post_action(
+				"reload",
+				private_key,
+				endpoint_list,
+				timeout,
+				client_cert,
+				client_key,
+				ca_cert,
+				endpoints,
+			)
+			.await
+		}
 	}
 }
@@ -0,0 +1,112 @@
+//! Cancellation of the running sync job, on request or on shutdown.
+
+use std::{future::Future, sync::Arc};
+
+use anyhow::{Result, bail};
+use tokio::sync::watch;
+
+/// Cancels a sync job. The blocking loops check it between the files, as
+/// they can not be aborted. The downloads stop as soon as it is cancelled.
+#[derive(Clone, Debug)]
+pub struct CancelToken(Arc<watch::Sender<Option<String>>>);
+
+impl Default for CancelToken {
+	fn default() -> Self {
+		Self(Arc::new(watch::Sender::new(None)))
+	}
+}
+
+impl CancelToken {
+	pub fn cancel(&self, reason: &str) {
+		self.0.send_replace(Some(reason.to_string()));
+	}
+
+	/// The reason of the cancellation, None if not cancelled.
+	pub fn reason(&self) -> Option<String> {
+		self.0.borrow().clone()
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.borrow().is_some()
+	}
+
+	/// Fails if the job is cancelled.
+	pub fn check(&self) -> Result<()> {
+		if let Some(reason) = self.reason() {
+			bail!("The sync job is cancelled: {}", reason);
+		}
+		Ok(())
+	}
+
+	/// Resolves once the job is cancelled.
+	pub async fn cancelled(&self) {
+		let mut rx = self.0.subscribe();
+		// The sender lives as long as self.
+		let _ = rx.wait_for(Option::is_some).await;
+	}
+
+	/// Run `fut` until it finishes or the job is cancelled, whichever comes
+	/// first. `fut` is dropped if cancelled.
+	pub async fn run<T>(&self, fut: impl Future<Output = Result<T>>) -> Result<T> {
+		tokio::select! {
+			r = fut => r,
+			_ = self.cancelled() => {
+				self.check()?;
+				unreachable!()
+			}
+		}
+	}
+}
+
+/// The running sync task, as seen by those who cancel it.
+#[derive(Clone, Debug)]
+pub struct SyncTask {
+	pub cancel: CancelToken,
+	/// Closed once the task exits
+	finished: watch::Receiver<()>,
+}
+
+impl SyncTask {
+	/// Returns the handle, and the guard for the task to hold until it
+	/// exits.
+	pub fn new() -> (Self, watch::Sender<()>) {
+		let (tx, finished) = watch::channel(());
+		let task = Self {
+			cancel: CancelToken::default(),
+			finished,
+		};
+		(task, tx)
+	}
+
+	/// Wait until the task exits.
+	pub async fn finished(mut self) {
+		// Fails once the guard is dropped, i.e. the task exited.
+		while self.finished.changed().await.is_ok() {}
+	}
+
+	/// Cancel the task, and wait until it cleans up and exits.
+	pub async fn cancel(self, reason: &str) {
+		self.cancel.cancel(reason);
+		self.finished().await;
+	}
+}
+
+#[tokio::test]
+async fn test_cancel_token() {
+	let (task, guard) = SyncTask::new();
+	let token = task.cancel.clone();
+	assert!(token.check().is_ok());
+	let job = tokio::spawn(async move {
+		let r = token
+			.run(async {
+				std::future::pending::<()>().await;
+				Ok(())
+			})
+			.await;
+		drop(guard);
+		r
+	});
+	task.cancel("Testing").await;
+	let e = job.await.unwrap().unwrap_err();
+	assert_eq!(e.to_string(), "The sync job is cancelled: Testing");
+}
@@ -1,14 +1,20 @@
 use std::{
-	fs::{self, File, create_dir_all, remove_file},
+	fmt::Display,
+	fs::{self, File, create_dir_all, read_to_string, remove_file},
 	net::SocketAddr,
-	path::PathBuf,
+	path::{Path, PathBuf},
+	str::FromStr,
 };
 
-use anyhow::{Context, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::NaiveTime;
+use croner::Cron;
 use log::warn;
 use serde::Deserialize;
 use url::Url;
 
+use crate::verify::decode_signing_key;
+
 #[derive(Copy, Deserialize, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum OperationMode {
@@ -17,12 +23,133 @@ pub enum OperationMode {
 	Debian,
 }
 
+/// Address to listen on, either `<address>:<port>` or `unix:<path>`.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+	Tcp(SocketAddr),
+	Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Some(path) = s.strip_prefix("unix:") {
+			let path = PathBuf::from(path);
+			if !path.is_absolute() {
+				bail!("Path of the UNIX domain socket must be absolute: '{}'", s);
+			}
+			return Ok(Self::Unix(path));
+		}
+		let addr = s
+			.parse()
+			.map_err(|_| anyhow!("Invalid listening address: '{}'", s))?;
+		Ok(Self::Tcp(addr))
+	}
+}
+
+impl TryFrom<String> for ListenAddr {
+	type Error = anyhow::Error;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl Display for ListenAddr {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+			ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+		}
+	}
+}
+
+/// Time of day in local time, written as `HH:MM`.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay(pub NaiveTime);
+
+impl FromStr for TimeOfDay {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		NaiveTime::parse_from_str(s, "%H:%M")
+			.map(Self)
+			.map_err(|_| anyhow!("Invalid time of day: '{}', expected HH:MM", s))
+	}
+}
+
+impl TryFrom<String> for TimeOfDay {
+	type Error = anyhow::Error;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl Display for TimeOfDay {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.0.format("%H:%M"))
+	}
+}
+
+/// A time of day with its own bandwidth settings, e.g. the peak hours.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BandwidthWindow {
+	pub start: TimeOfDay,
+	/// End of the window, might be earlier than the start to span midnight
+	pub end: TimeOfDay,
+	/// Bandwidth cap in KiB/s during the window, instead of
+	/// bandwidth_limit_kib
+	pub limit_kib: Option<u64>,
+	/// Postpone the syncs started by polling until the window ends
+	#[serde(default)]
+	pub defer_polling: bool,
+}
+
+impl BandwidthWindow {
+	pub fn contains(&self, t: NaiveTime) -> bool {
+		let (start, end) = (self.start.0, self.end.0);
+		if start <= end {
+			start <= t && t < end
+		} else {
+			t >= start || t < end
+		}
+	}
+}
+
+impl Display for BandwidthWindow {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}-{}", self.start, self.end)
+	}
+}
+
+/// A fallback upstream, tried if the one before it is unhealthy or fails.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+	/// The rsync (or SSH) URL to mirror
+	pub mirror_url: Url,
+	/// HTTP URL to fetch the metadata from
+	pub http_url: Url,
+}
+
+impl Display for Upstream {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.mirror_url)
+	}
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct AppConfig {
+	/// Name of the repository, set if the config file has [[repo]] tables
+	#[serde(default)]
+	pub name: Option<String>,
 	/// Hostname for the mirror, for projects/trace generation
 	pub hostname: String,
-	/// Listening address of the server
-	pub listen: Vec<SocketAddr>,
+	/// Listening addresses of the server
+	pub listen: Vec<ListenAddr>,
 	/// Server token
 	pub server_pubkeys: Vec<String>,
 	/// Testing mode, skips the signature check.
@@ -51,12 +178,121 @@ pub struct AppConfig {
 	pub archs: Vec<String>,
 	/// Number of parallel jobs
 	pub parallel_jobs: u8,
+	/// Seconds to wait for the running sync to finish on shutdown, before
+	/// rolling it back
+	#[serde(default = "default_shutdown_timeout")]
+	pub shutdown_timeout: u64,
+	/// TLS certificate chain of the sync server (PEM)
+	pub tls_cert: Option<PathBuf>,
+	/// TLS private key of the sync server (PEM)
+	pub tls_key: Option<PathBuf>,
+	/// CA certificates to verify the client certificates (PEM), enables
+	/// mutual TLS
+	pub tls_client_ca: Option<PathBuf>,
+	/// Permission bits of the UNIX domain sockets
+	pub unix_socket_mode: Option<u32>,
+	/// Owner of the UNIX domain sockets, user name or UID
+	pub unix_socket_owner: Option<String>,
+	/// Group of the UNIX domain sockets, group name or GID
+	pub unix_socket_group: Option<String>,
+	/// Maximum number of rsync instances running at the same time, shared
+	/// by all repositories
+	pub max_transfers: Option<usize>,
+	/// Check the upstream for changes every N seconds, in case a sync
+	/// request never arrives
+	pub poll_interval: Option<u64>,
+	/// Check the upstream for changes on a cron schedule, e.g. "0 */6 * * *"
+	pub poll_schedule: Option<String>,
+	/// Free space in MiB to leave on the file system of the mirror root,
+	/// syncs needing more than the rest are refused
+	#[serde(default)]
+	pub disk_reserve_mib: u64,
+	/// Maximum size of the mirror in MiB
+	pub quota_mib: Option<u64>,
+	/// Extra arguments of rsync, e.g. ["--timeout=600", "--delay-updates"]
+	#[serde(default)]
+	pub rsync_args: Vec<String>,
+	/// Paths relative to the mirror root, or prefixes of them, to compare by
+	/// their checksums (rsync --checksum)
+	#[serde(default)]
+	pub rsync_checksum_paths: Vec<String>,
+	/// Password of the rsync daemon, passed in RSYNC_PASSWORD
+	pub rsync_password: Option<String>,
+	/// File containing the password of the rsync daemon
+	pub rsync_password_file: Option<PathBuf>,
+	/// Private key for rsync over SSH
+	pub ssh_identity_file: Option<PathBuf>,
+	/// known_hosts file pinning the host key of the SSH upstream
+	pub ssh_known_hosts: Option<PathBuf>,
+	/// Bandwidth cap in KiB/s for the metadata downloads and rsync
+	pub bandwidth_limit_kib: Option<u64>,
+	/// Times of day with different bandwidth settings, the first matching
+	/// one applies
+	#[serde(default, rename = "bandwidth_window")]
+	pub bandwidth_windows: Vec<BandwidthWindow>,
+	/// Upstreams to fall back to, in order
+	#[serde(default, rename = "upstream")]
+	pub fallback_upstreams: Vec<Upstream>,
+	/// Private key to sign the sync requests to the downstream mirrors
+	pub downstream_private_key: Option<PathBuf>,
+	/// /do-sync URLs of the downstream mirrors, invoked after each
+	/// successful sync
+	#[serde(default)]
+	pub downstream_endpoints: Vec<Url>,
+	/// Seconds to wait for each downstream mirror
+	#[serde(default = "default_downstream_timeout")]
+	pub downstream_timeout: u64,
+}
+
+impl AppConfig {
+	/// All upstreams in the order they are tried, mirror_url and http_url
+	/// first.
+	pub fn upstreams(&self) -> Vec<Upstream> {
+		let mut upstreams = vec![Upstream {
+			mirror_url: self.mirror_url.clone(),
+			http_url: self.http_url.clone(),
+		}];
+		upstreams.extend(self.fallback_upstreams.iter().cloned());
+		upstreams
+	}
+
+	/// The config with the given upstream as mirror_url and http_url.
+	pub fn with_upstream(&self, upstream: &Upstream) -> Self {
+		let mut config = self.clone();
+		config.mirror_url = upstream.mirror_url.clone();
+		config.http_url = upstream.http_url.clone();
+		config
+	}
 }
 
+/// Settings of the daemon itself, shared by all repositories. They can only
+/// be set at the top level of the config file.
+const DAEMON_SETTINGS: &[&str] = &[
+	"listen",
+	"tls_cert",
+	"tls_key",
+	"tls_client_ca",
+	"unix_socket_mode",
+	"unix_socket_owner",
+	"unix_socket_group",
+	"shutdown_timeout",
+	"max_transfers",
+	"bandwidth_limit_kib",
+	"bandwidth_window",
+];
+
 fn default_false() -> bool {
 	false
 }
 
+fn default_shutdown_timeout() -> u64 {
+	300
+}
+
+fn default_downstream_timeout() -> u64 {
+	10
+}
+
 fn default_suites() -> Vec<String> {
 	vec!["stable".into()]
 }
@@ -73,32 +309,220 @@ fn default_archs() -> Vec<String> {
 	]
 }
 
-pub fn check_config(config: &AppConfig) -> Vec<anyhow::Error> {
+/// Read the config file. Each [[repo]] table is merged with the top-level
+/// settings into the config of one repository. Without [[repo]] tables, the
+/// whole file describes a single repository.
+pub fn load_configs(path: &Path) -> Result<Vec<AppConfig>> {
+	let content = read_to_string(path)
+		.context(format!("Unable to read the config file {}", path.display()))?;
+	let mut table: toml::Table =
+		toml::from_str(&content).context("Unable to read the config file")?;
+	let repos = match table.remove("repo") {
+		None => {
+			let config = table.try_into().context("Unable to read the config file")?;
+			return Ok(vec![config]);
+		}
+		Some(toml::Value::Array(repos)) if !repos.is_empty() => repos,
+		Some(_) => bail!("Invalid repo setting, expected one or more [[repo]] tables"),
+	};
+	let mut configs = Vec::new();
+	for (idx, repo) in repos.into_iter().enumerate() {
+		let toml::Value::Table(repo) = repo else {
+			bail!("Invalid repo setting, expected one or more [[repo]] tables");
+		};
+		if let Some(key) = repo.keys().find(|k| DAEMON_SETTINGS.contains(&k.as_str())) {
+			bail!(
+				"{} is shared by all repositories, set it at the top level (repo #{})",
+				key,
+				idx + 1
+			);
+		}
+		let mut merged = table.clone();
+		merged.extend(repo);
+		let config: AppConfig = merged
+			.try_into()
+			.context(format!("Unable to read the settings of repo #{}", idx + 1))?;
+		if config.name.is_none() {
+			bail!("Repo #{} has no name", idx + 1);
+		}
+		configs.push(config);
+	}
+	Ok(configs)
+}
+
+/// Format the errors found in the config file, one per line with their
+/// causes indented below.
+pub fn format_config_errors(errors: Vec<anyhow::Error>) -> String {
+	let mut error_str = String::from("Error(s) found in config file:\n");
+	for e in errors {
+		let mut chain = 1;
+		error_str.push_str(&format!("- {}\n", e));
+		e.chain().skip(1).for_each(|c| {
+			error_str.push_str(&format!("{}> {}\n", "  ".repeat(chain), c));
+			chain += 1;
+		});
+	}
+	error_str
+}
+
+/// Check the config of every repository, and that they do not step on each
+/// other.
+pub fn check_configs(configs: &[AppConfig]) -> Vec<anyhow::Error> {
 	let mut errors = Vec::new();
-	if !config.mirror_url.as_str().ends_with('/') {
+	for (idx, config) in configs.iter().enumerate() {
+		let name = match &config.name {
+			Some(name) => name,
+			None => {
+				errors.extend(check_config(config));
+				continue;
+			}
+		};
+		if name.is_empty()
+			|| name.starts_with('.')
+			|| !name.chars()
+				.all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+		{
+			errors.push(anyhow!(
+				"Invalid repository name '{}', only letters, digits, '.', '_' and '-' are allowed",
+				name
+			));
+		}
+		for other in &configs[..idx] {
+			if other.name == config.name {
+				errors.push(anyhow!("Duplicate repository name '{}'", name));
+			}
+			if other.mirror_root == config.mirror_root {
+				errors.push(anyhow!(
+					"Repositories '{}' and '{}' share the mirror root {}",
+					other.name.as_deref().unwrap_or_default(),
+					name,
+					config.mirror_root.display()
+				));
+			}
+		}
+		errors.extend(check_config(config)
+			.into_iter()
+			.map(|e| e.context(format!("In repository '{}'", name))));
+	}
+	errors
+}
+
+/// Check the URLs of an upstream.
+fn check_upstream(upstream: &Upstream) -> Vec<anyhow::Error> {
+	let mut errors = Vec::new();
+	if !upstream.mirror_url.as_str().ends_with('/') {
 		errors.push(anyhow!(
 			"Mirror URL must end with a slash, otherwise the path will be overridden"
 		));
 	}
-	if !config.http_url.as_str().ends_with('/') {
+	if !upstream.http_url.as_str().ends_with('/') {
 		errors.push(anyhow!(
 			"Mirror HTTP URL must end with a slash, otherwise the path will be overridden"
 		));
 	}
-	let scheme = config.mirror_url.scheme().to_lowercase();
-	if scheme != "rsync" {
+	let scheme = upstream.mirror_url.scheme().to_lowercase();
+	if scheme != "rsync" && scheme != "ssh" {
 		errors.push(anyhow!(
-			"Invalid mirror URL scheme: '{}'. Only rsync is supported",
+			"Invalid mirror URL scheme: '{}'. Only rsync and ssh are supported",
 			scheme
 		));
 	}
-	let scheme = config.http_url.scheme().to_lowercase();
+	if scheme == "ssh" && upstream.mirror_url.host_str().is_none_or(|h| h.is_empty()) {
+		errors.push(anyhow!("SSH mirror URL has no host"));
+	}
+	let scheme = upstream.http_url.scheme().to_lowercase();
 	if scheme != "http" && scheme != "https" {
 		errors.push(anyhow!(
 			"Invalid mirror HTTP URL: '{}', expected http or https",
 			scheme
 		));
 	}
+	errors
+}
+
+pub fn check_config(config: &AppConfig) -> Vec<anyhow::Error> {
+	let mut errors = Vec::new();
+	let upstreams = config.upstreams();
+	for (idx, upstream) in upstreams.iter().enumerate() {
+		if idx == 0 {
+			errors.extend(check_upstream(upstream));
+		} else {
+			errors.extend(check_upstream(upstream)
+				.into_iter()
+				.map(|e| e.context(format!("In upstream #{}", idx))));
+		}
+	}
+	let uses_scheme = |scheme: &str| upstreams.iter().any(|u| u.mirror_url.scheme() == scheme);
+	if !uses_scheme("ssh")
+		&& (config.ssh_identity_file.is_some() || config.ssh_known_hosts.is_some())
+	{
+		errors.push(anyhow!(
+			"ssh_identity_file and ssh_known_hosts only apply to ssh:// mirror URLs"
+		));
+	}
+	if !uses_scheme("rsync")
+		&& (config.rsync_password.is_some() || config.rsync_password_file.is_some())
+	{
+		errors.push(anyhow!(
+			"rsync_password and rsync_password_file only apply to rsync:// mirror URLs"
+		));
+	}
+	if config.rsync_password.is_some() && config.rsync_password_file.is_some() {
+		errors.push(anyhow!(
+			"rsync_password and rsync_password_file can not be used together"
+		));
+	}
+	for f in [
+		&config.rsync_password_file,
+		&config.ssh_identity_file,
+		&config.ssh_known_hosts,
+	]
+	.into_iter()
+	.flatten()
+	{
+		if !f.is_file() {
+			errors.push(anyhow!("File {} does not exist", f.display()));
+		}
+	}
+	for arg in &config.rsync_args {
+		if ["--files-from", "--bwlimit", "-e", "--rsh"]
+			.iter()
+			.any(|a| arg == a || arg.starts_with(&format!("{}=", a)))
+		{
+			errors.push(anyhow!(
+				"rsync argument '{}' is set by aosc-mirror and can not be overridden",
+				arg
+			));
+		}
+		// The old snapshots share the pool, and the upstream is not ours to
+		// change.
+		if arg == "--del"
+			|| arg.starts_with("--delete")
+			|| ["--remove-source-files", "--remove-sent-files"].contains(&arg.as_str())
+		{
+			errors.push(anyhow!(
+				"rsync argument '{}' removes files and is not allowed",
+				arg
+			));
+		}
+	}
+	if let Some(f) = &config.downstream_private_key {
+		let key = fs::read_to_string(f)
+			.map_err(anyhow::Error::from)
+			.and_then(|key| decode_signing_key(&key));
+		if let Err(e) = key {
+			errors.push(e.context(format!("Invalid private key file {}", f.display())));
+		}
+	} else if !config.downstream_endpoints.is_empty() {
+		errors.push(anyhow!(
+			"downstream_endpoints are set, but downstream_private_key is not"
+		));
+	}
+	for url in &config.downstream_endpoints {
+		if !["http", "https"].contains(&url.scheme()) {
+			errors.push(anyhow!("Invalid downstream endpoint {}", url));
+		}
+	}
 	if config.mirror_root.exists() && !config.mirror_root.is_dir() {
 		errors.push(anyhow!(
 			"Unable to use {} as mirror root since it is not a directory",
@@ -134,6 +558,75 @@ pub fn check_config(config: &AppConfig) -> Vec<anyhow::Error> {
 			errors.push(anyhow!("No keyring files are found"));
 		}
 	}
+	match (&config.tls_cert, &config.tls_key) {
+		(Some(cert), Some(key)) => {
+			for f in [cert, key] {
+				if !f.is_file() {
+					errors.push(anyhow!(
+						"TLS file {} does not exist",
+						f.display()
+					));
+				}
+			}
+		}
+		(None, None) => {
+			if config.tls_client_ca.is_some() {
+				errors.push(anyhow!(
+					"tls_client_ca is set, but TLS is not enabled; Set tls_cert and tls_key"
+				));
+			}
+		}
+		_ => errors.push(anyhow!("tls_cert and tls_key must be specified together")),
+	}
+	if let Some(ca) = &config.tls_client_ca
+		&& !ca.is_file()
+	{
+		errors.push(anyhow!(
+			"TLS client CA file {} does not exist",
+			ca.display()
+		));
+	}
+	for addr in &config.listen {
+		if let ListenAddr::Unix(path) = addr
+			&& !path.parent().is_some_and(|p| p.is_dir())
+		{
+			errors.push(anyhow!(
+				"Parent directory of the UNIX domain socket {} does not exist",
+				path.display()
+			));
+		}
+	}
+	if let Some(mode) = config.unix_socket_mode
+		&& mode > 0o777
+	{
+		errors.push(anyhow!("Invalid UNIX domain socket mode: {:o}", mode));
+	}
+	match (config.poll_interval, &config.poll_schedule) {
+		(Some(_), Some(_)) => errors.push(anyhow!(
+			"poll_interval and poll_schedule can not be used together"
+		)),
+		(Some(0), None) => errors.push(anyhow!("poll_interval must be greater than 0")),
+		(None, Some(schedule)) => {
+			if let Err(e) = Cron::new(schedule).parse() {
+				errors.push(anyhow!("Invalid poll_schedule '{}': {}", schedule, e));
+			}
+		}
+		_ => {}
+	}
+	if config.bandwidth_limit_kib == Some(0) {
+		errors.push(anyhow!("bandwidth_limit_kib must be greater than 0"));
+	}
+	for w in &config.bandwidth_windows {
+		if w.start == w.end {
+			errors.push(anyhow!("Bandwidth window {} is empty", w));
+		}
+		if w.limit_kib == Some(0) {
+			errors.push(anyhow!(
+				"limit_kib of bandwidth window {} must be greater than 0",
+				w
+			));
+		}
+	}
 	if config.server_pubkeys.is_empty() && !config.skip_verification {
 		errors.push(anyhow!("Public keys from mirror origin servers required"));
 	}
@@ -169,3 +662,96 @@ pub fn check_config(config: &AppConfig) -> Vec<anyhow::Error> {
 	}
 	errors
 }
+
+/// Settings of a repository to start the tests with.
+#[cfg(test)]
+const TEST_CONFIG: &str = r#"
+hostname = "localhost"
+listen = ["127.0.0.1:1234"]
+server_pubkeys = []
+skip_verification = true
+mode = "aosc"
+mirror_url = "rsync://repo.aosc.io/anthon/debs/"
+http_url = "https://repo.aosc.io/debs/"
+mirror_root = "/mirror"
+keyring_dir = "/etc/apt/trusted.gpg.d"
+parallel_jobs = 4
+"#;
+
+/// The config of the tests, with the settings in `extra` replacing those of
+/// [`TEST_CONFIG`].
+#[cfg(test)]
+pub fn test_config(extra: &str) -> AppConfig {
+	let mut table: toml::Table = toml::from_str(TEST_CONFIG).unwrap();
+	table.extend(toml::from_str::<toml::Table>(extra).unwrap());
+	table.try_into().unwrap()
+}
+
+#[test]
+fn test_load_configs() -> Result<()> {
+	let dir = crate::utils::test_dir("config")?;
+	let path = dir.join("config.toml");
+	fs::write(
+		&path,
+		format!(
+			"{}{}",
+			TEST_CONFIG,
+			r#"
+[[repo]]
+name = "aosc"
+mode = "aosc"
+mirror_url = "rsync://repo.aosc.io/anthon/debs/"
+http_url = "https://repo.aosc.io/debs/"
+mirror_root = "/mirror/aosc"
+
+[[repo]]
+name = "debian"
+mode = "debian"
+mirror_url = "rsync://ftp.debian.org/debian/"
+http_url = "https://deb.debian.org/debian/"
+mirror_root = "/mirror/debian"
+parallel_jobs = 8
+"#
+		),
+	)?;
+	let configs = load_configs(&path)?;
+	assert_eq!(configs.len(), 2);
+	assert_eq!(configs[0].name.as_deref(), Some("aosc"));
+	assert_eq!(configs[0].parallel_jobs, 4);
+	assert_eq!(configs[1].mode, OperationMode::Debian);
+	assert_eq!(configs[1].parallel_jobs, 8);
+	assert_eq!(configs[1].listen, configs[0].listen);
+	fs::write(
+		&path,
+		format!(
+			"{}{}",
+			TEST_CONFIG,
+			r#"
+[[repo]]
+name = "aosc"
+listen = ["127.0.0.1:1235"]
+"#
+		),
+	)?;
+	assert!(load_configs(&path).is_err());
+	fs::remove_dir_all(&dir)?;
+	Ok(())
+}
+
+#[test]
+fn test_parse_listen_addr() {
+	assert_eq!(
+		"127.0.0.1:1234".parse::<ListenAddr>().unwrap(),
+		ListenAddr::Tcp("127.0.0.1:1234".parse().unwrap())
+	);
+	assert_eq!(
+		"[::1]:1234".parse::<ListenAddr>().unwrap().to_string(),
+		"[::1]:1234"
+	);
+	assert_eq!(
+		"unix:/run/aosc-mirror.sock".parse::<ListenAddr>().unwrap(),
+		ListenAddr::Unix("/run/aosc-mirror.sock".into())
+	);
+	assert!("unix:aosc-mirror.sock".parse::<ListenAddr>().is_err());
+	assert!("localhost:1234".parse::<ListenAddr>().is_err());
+}
@@ -0,0 +1,126 @@
+use std::{
+	collections::VecDeque,
+	fmt::Display,
+	sync::{
+		Mutex,
+		atomic::{AtomicU64, Ordering},
+	},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use crate::{metrics::SyncPhase, server::Status};
+
+/// Number of events kept for clients connecting in the middle of a sync.
+pub const DEFAULT_EVENT_BUFFER_SIZE: usize = 1024;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SyncEventKind {
+	/// A sync job is started
+	Started { timestamp: i64 },
+	/// The sync job entered a new phase
+	Phase { phase: SyncPhase },
+	/// A file is downloaded
+	Download { path: String },
+	/// Something went wrong, but the sync job continues
+	Warning { message: String },
+	/// The sync job is finished
+	Finished { status: Status, message: String },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SyncEvent {
+	/// Sequence number of the event, used as the SSE event ID
+	pub id: u64,
+	/// UNIX timestamp of the event
+	pub time: i64,
+	#[serde(flatten)]
+	pub kind: SyncEventKind,
+}
+
+impl Display for SyncEvent {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.kind {
+			SyncEventKind::Started { timestamp } => {
+				write!(f, "Sync started (timestamp {})", timestamp)
+			}
+			SyncEventKind::Phase { phase } => write!(f, "Phase: {}", phase.as_str()),
+			SyncEventKind::Download { path } => write!(f, "Downloaded {}", path),
+			SyncEventKind::Warning { message } => write!(f, "WARNING: {}", message),
+			SyncEventKind::Finished { status, message } => {
+				write!(f, "Sync finished: {:?}{}", status, message)
+			}
+		}
+	}
+}
+
+/// Ring buffer of the recent sync events, fed by the sync pipeline.
+/// Live subscribers are notified through a broadcast channel.
+#[derive(Debug)]
+pub struct EventLog {
+	capacity: usize,
+	next_id: AtomicU64,
+	buffer: Mutex<VecDeque<SyncEvent>>,
+	sender: Sender<SyncEvent>,
+}
+
+impl EventLog {
+	pub fn new(capacity: usize) -> Self {
+		let (sender, _) = broadcast::channel(capacity.max(1));
+		Self {
+			capacity,
+			next_id: AtomicU64::new(1),
+			buffer: Mutex::new(VecDeque::with_capacity(capacity)),
+			sender,
+		}
+	}
+
+	pub fn push(&self, kind: SyncEventKind) {
+		let event = SyncEvent {
+			id: self.next_id.fetch_add(1, Ordering::Relaxed),
+			time: Utc::now().timestamp(),
+			kind,
+		};
+		let mut buffer = self.buffer.lock().unwrap();
+		if buffer.len() >= self.capacity {
+			buffer.pop_front();
+		}
+		buffer.push_back(event.clone());
+		// Nobody is listening - that's fine.
+		let _ = self.sender.send(event);
+	}
+
+	pub fn warn(&self, message: impl Into<String>) {
+		self.push(SyncEventKind::Warning {
+			message: message.into(),
+		});
+	}
+
+	/// Returns the buffered events newer than `last_id`, and a receiver for
+	/// the events to come. No events are lost or duplicated in between.
+	pub fn subscribe(&self, last_id: u64) -> (Vec<SyncEvent>, Receiver<SyncEvent>) {
+		let buffer = self.buffer.lock().unwrap();
+		let rx = self.sender.subscribe();
+		let backlog = buffer.iter().filter(|e| e.id > last_id).cloned().collect();
+		(backlog, rx)
+	}
+}
+
+#[test]
+fn test_event_log() {
+	let log = EventLog::new(2);
+	log.push(SyncEventKind::Started { timestamp: 1 });
+	let (backlog, mut rx) = log.subscribe(0);
+	assert_eq!(backlog.len(), 1);
+	log.warn("first");
+	log.warn("second");
+	assert_eq!(rx.try_recv().unwrap().id, 2);
+	let (backlog, _) = log.subscribe(0);
+	let ids = backlog.iter().map(|e| e.id).collect::<Vec<_>>();
+	assert_eq!(ids, vec![2, 3]);
+	let (backlog, _) = log.subscribe(2);
+	assert_eq!(backlog.len(), 1);
+}
@@ -0,0 +1,376 @@
+//! Offline consistency check of the published mirror, see
+//! `sync-client verify`.
+
+use std::{
+	collections::{HashMap, HashSet},
+	fs::{read_dir, read_to_string},
+	io::BufRead,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::task::JoinSet;
+
+use crate::{
+	cancel::CancelToken,
+	config::{AppConfig, OperationMode},
+	debian::collect_source_files,
+	events::{DEFAULT_EVENT_BUFFER_SIZE, EventLog},
+	lock::MirrorLock,
+	metadata::{
+		AptMetadataHashAlgm, AptRepoReleaseInfo, FileEntry, find_packages_file, get_files,
+		split_inrelease,
+	},
+	state::{RedownloadQueue, published_snapshot},
+	sync::unused_package_files,
+	utils::{checksum_file, get_reader},
+	verify::{PgpKeyringStore, init_pgp_keyringstore, verify_pgp_signature},
+};
+
+/// A file to check, with the path relative to the mirror root.
+struct Check {
+	path: String,
+	size: u64,
+	hash: Option<(AptMetadataHashAlgm, String)>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum BrokenFile {
+	Missing {
+		path: String,
+	},
+	SizeMismatch {
+		path: String,
+		expected: u64,
+		actual: u64,
+	},
+	ChecksumMismatch {
+		path: String,
+	},
+	/// InRelease or Release can not be parsed or verified
+	InvalidManifest {
+		path: String,
+		message: String,
+	},
+}
+
+impl BrokenFile {
+	pub fn path(&self) -> &str {
+		match self {
+			BrokenFile::Missing { path }
+			| BrokenFile::SizeMismatch { path, .. }
+			| BrokenFile::ChecksumMismatch { path }
+			| BrokenFile::InvalidManifest { path, .. } => path,
+		}
+	}
+}
+
+/// Result of checking the published snapshot of a repository.
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+	/// Name of the repository, if the config file has [[repo]] tables
+	pub repo: Option<String>,
+	/// Timestamp of the published dists-TIMESTAMP snapshot
+	pub snapshot: i64,
+	pub metadata_files_checked: usize,
+	pub pool_files_checked: usize,
+	pub broken: Vec<BrokenFile>,
+	/// Files in pool/ that nothing references, relative to the mirror root
+	pub orphans: Vec<PathBuf>,
+}
+
+/// Read and verify the manifest of a suite in the published snapshot.
+pub(crate) fn read_manifest(
+	suite_dir: &Path,
+	keyring_store: &PgpKeyringStore,
+) -> Result<AptRepoReleaseInfo> {
+	let inrelease = suite_dir.join("InRelease");
+	if inrelease.is_file() {
+		let content = read_to_string(&inrelease)?;
+		let (body, sig) = split_inrelease(&content);
+		verify_pgp_signature(&body, &sig, keyring_store)?;
+		return AptRepoReleaseInfo::parse_from(&body);
+	}
+	let release = read_to_string(suite_dir.join("Release"))?;
+	let sig = read_to_string(suite_dir.join("Release.gpg"))?;
+	verify_pgp_signature(&release, &sig, keyring_store)?;
+	AptRepoReleaseInfo::parse_from(&release)
+}
+
+/// SHA256 checksums of the pool files listed in a Packages file.
+fn packages_checksums(path: &Path, checksums: &mut HashMap<String, String>) -> Result<()> {
+	let reader = get_reader(&path)?;
+	let mut filename = None;
+	let mut sha256 = None;
+	for l in reader.lines() {
+		let l = l?;
+		if let Some(v) = l.strip_prefix("Filename: ") {
+			filename = Some(v.trim().to_string());
+		} else if let Some(v) = l.strip_prefix("SHA256: ") {
+			sha256 = Some(v.trim().to_string());
+		} else if l.is_empty()
+			&& let (Some(f), Some(h)) = (filename.take(), sha256.take())
+		{
+			checksums.insert(f, h);
+		}
+	}
+	if let (Some(f), Some(h)) = (filename, sha256) {
+		checksums.insert(f, h);
+	}
+	Ok(())
+}
+
+/// SHA256 checksums of the source files listed in a Sources file.
+fn sources_checksums(path: &Path, checksums: &mut HashMap<String, String>) -> Result<()> {
+	let reader = get_reader(&path)?;
+	let mut directory = String::new();
+	let mut files = Vec::new();
+	let mut in_checksums = false;
+	for l in reader.lines() {
+		let l = l?;
+		if in_checksums && l.starts_with(' ') {
+			let mut fields = l.split_whitespace();
+			if let (Some(hash), Some(name)) = (fields.next(), fields.nth(1)) {
+				files.push((name.to_string(), hash.to_string()));
+			}
+			continue;
+		}
+		in_checksums = l.starts_with("Checksums-Sha256:");
+		if let Some(v) = l.strip_prefix("Directory: ") {
+			directory = v.trim().to_string();
+		} else if l.is_empty() {
+			for (name, hash) in files.drain(..) {
+				checksums.insert(format!("{}/{}", directory, name), hash);
+			}
+			directory.clear();
+		}
+	}
+	for (name, hash) in files {
+		checksums.insert(format!("{}/{}", directory, name), hash);
+	}
+	Ok(())
+}
+
+fn check_file(root: &Path, c: Check) -> Option<BrokenFile> {
+	let full_path = root.join(&c.path);
+	let actual = match full_path.metadata() {
+		Ok(m) if m.is_file() => m.len(),
+		_ => return Some(BrokenFile::Missing { path: c.path }),
+	};
+	if actual != c.size {
+		return Some(BrokenFile::SizeMismatch {
+			path: c.path,
+			expected: c.size,
+			actual,
+		});
+	}
+	if let Some((algm, hash)) = c.hash
+		&& let Err(e) = checksum_file(algm, Arc::new(full_path), Arc::new(hash))
+	{
+		warn!("{}: {:#}", c.path, e);
+		return Some(BrokenFile::ChecksumMismatch { path: c.path });
+	}
+	None
+}
+
+/// Files in pool/ referenced by the given suites of the published snapshot.
+pub(crate) async fn published_files(
+	config: &AppConfig,
+	snapshot: i64,
+	suites: HashMap<String, Vec<String>>,
+) -> Result<Vec<FileEntry>> {
+	info!("Collecting the pool files ...");
+	let root = config.mirror_root.clone();
+	let archs = config.archs.clone();
+	let suites2 = suites.clone();
+	let mut files = tokio::task::spawn_blocking(move || {
+		get_files(root, suites2, archs, snapshot, &CancelToken::default())
+	})
+	.await??;
+	if config.mode == OperationMode::Debian && config.mirror_sources {
+		let dists = config.mirror_root.join(format!("dists-{}", snapshot));
+		files.extend(collect_source_files(dists, suites, config.parallel_jobs).await?);
+	}
+	Ok(files)
+}
+
+/// Check the files concurrently in `threads` threads.
+async fn check_files(root: &Path, checks: Vec<Check>, threads: u8) -> Result<Vec<BrokenFile>> {
+	let threads = checks.len().clamp(1, threads.max(1).into());
+	let mut queues = (0..threads).map(|_| Vec::new()).collect::<Vec<_>>();
+	for (idx, c) in checks.into_iter().enumerate() {
+		queues[idx % threads].push(c);
+	}
+	let mut tasks = JoinSet::new();
+	for queue in queues {
+		let root = root.to_path_buf();
+		tasks.spawn_blocking(move || {
+			queue.into_iter()
+				.filter_map(|c| check_file(&root, c))
+				.collect::<Vec<_>>()
+		});
+	}
+	let mut broken = Vec::new();
+	while let Some(r) = tasks.join_next().await {
+		broken.extend(r?);
+	}
+	broken.sort_by(|a, b| a.path().cmp(b.path()));
+	Ok(broken)
+}
+
+/// Check the published snapshot of the mirror: the metadata files against
+/// the Release files, and the pool files against the Packages (and Sources)
+/// files. Nothing is changed.
+pub async fn verify_mirror(config: &AppConfig, sha256: bool) -> Result<VerifyReport> {
+	let root = &config.mirror_root;
+	let snapshot = published_snapshot(root).context(
+		"No published snapshot found, the mirror must be synced by sync-client first",
+	)?;
+	let dists = root.join(format!("dists-{}", snapshot));
+	let keyring_store = init_pgp_keyringstore(&config.keyring_dir).await?;
+	let mut broken = Vec::new();
+
+	info!("Checking the metadata files in {} ...", dists.display());
+	let mut suites = HashMap::new();
+	let mut checks = Vec::new();
+	for entry in read_dir(&dists)? {
+		let entry = entry?;
+		// dists/<suite> might be a symbolic link to dists/<codename>.
+		if !entry.file_type()?.is_dir() {
+			continue;
+		}
+		let name = entry.file_name().to_string_lossy().into_owned();
+		let manifest = match read_manifest(&entry.path(), &keyring_store) {
+			Ok(m) => m,
+			Err(e) => {
+				broken.push(BrokenFile::InvalidManifest {
+					path: format!("dists/{}", name),
+					message: format!("{:#}", e),
+				});
+				continue;
+			}
+		};
+		let (algm, files) = manifest.metadata_files(config.mode)?;
+		for f in files {
+			checks.push(Check {
+				path: format!("dists/{}/{}", name, f.path.display()),
+				size: f.size as u64,
+				hash: Some((algm, f.hash.clone())),
+			});
+		}
+		suites.insert(name, manifest.components.clone());
+	}
+	let metadata_files_checked = checks.len();
+	broken.extend(check_files(root, checks, config.parallel_jobs).await?);
+
+	let files = published_files(config, snapshot, suites.clone()).await?;
+	let mut checksums = HashMap::new();
+	if sha256 {
+		for (suite, components) in &suites {
+			for component in components {
+				let dir = dists.join(suite).join(component);
+				for arch in &config.archs {
+					if let Some(p) = find_packages_file(&dir, arch) {
+						packages_checksums(&p, &mut checksums)?;
+					}
+				}
+				for name in ["source/Sources.gz", "source/Sources.xz"] {
+					if config.mirror_sources && dir.join(name).is_file() {
+						sources_checksums(&dir.join(name), &mut checksums)?;
+						break;
+					}
+				}
+			}
+		}
+	}
+	let known_files: HashSet<String> = files.iter().map(|f| f.path.clone()).collect();
+	let checks = files
+		.into_iter()
+		.map(|f| Check {
+			hash: checksums
+				.remove(&f.path)
+				.map(|h| (AptMetadataHashAlgm::SHA256, h)),
+			path: f.path,
+			size: f.size,
+		})
+		.collect::<Vec<_>>();
+	let pool_files_checked = checks.len();
+	info!("Checking {} pool files ...", pool_files_checked);
+	broken.extend(check_files(root, checks, config.parallel_jobs).await?);
+
+	info!("Looking for orphan files ...");
+	let events = EventLog::new(DEFAULT_EVENT_BUFFER_SIZE);
+	let orphans = unused_package_files(root, &known_files, &events);
+	if broken.is_empty() {
+		info!("No broken files found.");
+	} else {
+		warn!("{} broken files found.", broken.len());
+	}
+	Ok(VerifyReport {
+		repo: config.name.clone(),
+		snapshot,
+		metadata_files_checked,
+		pool_files_checked,
+		broken,
+		orphans,
+	})
+}
+
+/// Queue the broken files of the reports for re-download on the next sync.
+pub fn queue_broken_files(config: &AppConfig, report: &VerifyReport) -> Result<()> {
+	let _lock = MirrorLock::acquire(&config.mirror_root, "verify", Utc::now().timestamp())?;
+	let mut queue = RedownloadQueue::load(&config.mirror_root)?;
+	for f in &report.broken {
+		if let BrokenFile::InvalidManifest { path, .. } = f {
+			// Fetched again on every sync anyway.
+			info!("{} will be fetched again on the next sync.", path);
+			continue;
+		}
+		queue.paths.insert(f.path().to_string());
+	}
+	if queue.paths.is_empty() {
+		return Ok(());
+	}
+	queue.save(&config.mirror_root)?;
+	info!(
+		"{} files queued for re-download on the next sync.",
+		queue.paths.len()
+	);
+	Ok(())
+}
+
+#[test]
+fn test_metadata_checksums() -> Result<()> {
+	let dir = crate::utils::test_dir("fsck")?;
+	let packages = dir.join("Packages");
+	std::fs::write(
+		&packages,
+		"Package: foo\nVersion: 1\nFilename: pool/main/f/foo/foo_1_amd64.deb\nSize: 1000\nSHA256: aaaa\n\nPackage: bar\nFilename: pool/main/b/bar/bar_2_all.deb\nSHA256: bbbb\n",
+	)?;
+	let sources = dir.join("Sources");
+	std::fs::write(
+		&sources,
+		"Package: foo\nDirectory: pool/main/f/foo\nChecksums-Sha256:\n cccc 100 foo_1.dsc\n dddd 2000 foo_1.tar.xz\nFormat: 3.0 (native)\n\nPackage: baz\nChecksums-Sha256:\n eeee 200 baz_1.dsc\nDirectory: pool/main/b/baz\n",
+	)?;
+	let mut checksums = HashMap::new();
+	packages_checksums(&packages, &mut checksums)?;
+	sources_checksums(&sources, &mut checksums)?;
+	let expected = [
+		("pool/main/f/foo/foo_1_amd64.deb", "aaaa"),
+		("pool/main/b/bar/bar_2_all.deb", "bbbb"),
+		("pool/main/f/foo/foo_1.dsc", "cccc"),
+		("pool/main/f/foo/foo_1.tar.xz", "dddd"),
+		("pool/main/b/baz/baz_1.dsc", "eeee"),
+	];
+	assert_eq!(checksums.len(), expected.len());
+	for (path, hash) in expected {
+		assert_eq!(checksums.get(path).map(String::as_str), Some(hash));
+	}
+	std::fs::remove_dir_all(&dir)?;
+	Ok(())
+}
@@ -0,0 +1,236 @@
+//! Removing the files no longer referenced by the published snapshot,
+//! without contacting the upstream, see `sync-client gc`.
+
+use std::{
+	collections::{HashMap, HashSet},
+	fmt::Display,
+	fs::{read_dir, remove_dir_all, remove_file},
+	os::unix::fs::MetadataExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{
+	config::AppConfig,
+	events::{DEFAULT_EVENT_BUFFER_SIZE, EventLog},
+	fsck::{published_files, read_manifest},
+	lock::MirrorLock,
+	state::published_snapshot,
+	sync::{old_dists_dirs, unused_package_files},
+	utils::format_size,
+	verify::init_pgp_keyringstore,
+};
+
+/// What is (or would be, with --dry-run) removed from the mirror.
+#[derive(Debug, Serialize)]
+pub struct GcReport {
+	/// Name of the repository, if the config file has [[repo]] tables
+	pub repo: Option<String>,
+	/// Timestamp of the published dists-TIMESTAMP snapshot
+	pub snapshot: i64,
+	pub dry_run: bool,
+	/// Unpublished dists-TIMESTAMP directories, relative to the mirror root
+	pub dists_dirs: Vec<PathBuf>,
+	/// Leftover file lists in .tmp, relative to the mirror root
+	pub temp_files: Vec<PathBuf>,
+	/// Files in pool/ that nothing references, relative to the mirror root
+	pub pool_files: Vec<PathBuf>,
+	/// Disk space freed, files with hard links kept elsewhere not included
+	pub reclaimed_bytes: u64,
+}
+
+impl Display for GcReport {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if let Some(name) = &self.repo {
+			write!(f, "Repository '{}': ", name)?;
+		}
+		writeln!(
+			f,
+			"{} {} dists directories, {} temporary files and {} pool files, {} reclaimed.",
+			if self.dry_run {
+				"Would remove"
+			} else {
+				"Removed"
+			},
+			self.dists_dirs.len(),
+			self.temp_files.len(),
+			self.pool_files.len(),
+			format_size(self.reclaimed_bytes)
+		)?;
+		for path in self
+			.dists_dirs
+			.iter()
+			.chain(&self.temp_files)
+			.chain(&self.pool_files)
+		{
+			writeln!(f, "  {}", path.display())?;
+		}
+		Ok(())
+	}
+}
+
+/// Counts the bytes freed by removing a set of files. A file is only freed
+/// once all of its hard links are removed.
+#[derive(Default)]
+struct Reclaimed {
+	/// (device, inode) -> (links, links removed, size)
+	inodes: HashMap<(u64, u64), (u64, u64, u64)>,
+}
+
+impl Reclaimed {
+	fn add(&mut self, path: &Path) {
+		let m = match path.symlink_metadata() {
+			Ok(m) if m.is_file() => m,
+			_ => return,
+		};
+		let e = self
+			.inodes
+			.entry((m.dev(), m.ino()))
+			.or_insert((m.nlink(), 0, m.len()));
+		e.1 += 1;
+	}
+
+	fn add_tree(&mut self, dir: &Path) {
+		for entry in walkdir::WalkDir::new(dir).into_iter().flatten() {
+			if entry.file_type().is_file() {
+				self.add(entry.path());
+			}
+		}
+	}
+
+	fn bytes(&self) -> u64 {
+		self.inodes
+			.values()
+			.filter(|(links, removed, _)| removed >= links)
+			.map(|(_, _, size)| size)
+			.sum()
+	}
+}
+
+/// Remove the unpublished dists-TIMESTAMP directories, the leftover file
+/// lists and the pool files not referenced by the published snapshot. With
+/// `dry_run`, only report what would be removed.
+pub async fn collect_garbage(config: &AppConfig, dry_run: bool) -> Result<GcReport> {
+	let root = &config.mirror_root;
+	let _lock = if dry_run {
+		None
+	} else {
+		Some(MirrorLock::acquire(root, "gc", Utc::now().timestamp())?)
+	};
+	let snapshot = published_snapshot(root).context(
+		"No published snapshot found, the mirror must be synced by sync-client first",
+	)?;
+	let dists = root.join(format!("dists-{}", snapshot));
+	let keyring_store = init_pgp_keyringstore(&config.keyring_dir).await?;
+	// Removing files based on a broken manifest would wipe the pool.
+	let mut suites = HashMap::new();
+	for entry in read_dir(&dists)? {
+		let entry = entry?;
+		if !entry.file_type()?.is_dir() {
+			continue;
+		}
+		let manifest = read_manifest(&entry.path(), &keyring_store).context(format!(
+			"Invalid manifest in {}, run verify and sync again",
+			entry.path().display()
+		))?;
+		suites.insert(
+			entry.file_name().to_string_lossy().into_owned(),
+			manifest.components,
+		);
+	}
+	let known_files: HashSet<String> = published_files(config, snapshot, suites)
+		.await?
+		.into_iter()
+		.map(|f| f.path)
+		.collect();
+
+	info!("Looking for unused files ...");
+	let mut reclaimed = Reclaimed::default();
+	let mut dists_dirs = Vec::new();
+	for dir in old_dists_dirs(root, snapshot)? {
+		reclaimed.add_tree(&dir);
+		dists_dirs.push(dir.strip_prefix(root)?.to_path_buf());
+	}
+	let mut temp_files = Vec::new();
+	let tmp_dir = root.join(".tmp");
+	if tmp_dir.is_dir() {
+		for entry in walkdir::WalkDir::new(&tmp_dir).into_iter().flatten() {
+			if entry.file_type().is_file() {
+				reclaimed.add(entry.path());
+				temp_files.push(entry.path().strip_prefix(root)?.to_path_buf());
+			}
+		}
+	}
+	let events = EventLog::new(DEFAULT_EVENT_BUFFER_SIZE);
+	let pool_files = unused_package_files(root, &known_files, &events);
+	for rel in &pool_files {
+		reclaimed.add(&root.join(rel));
+	}
+	let reclaimed_bytes = reclaimed.bytes();
+
+	if !dry_run {
+		for dir in &dists_dirs {
+			info!("Removing {} ...", dir.display());
+			remove_dir_all(root.join(dir))
+				.context(format!("Unable to remove directory {}", dir.display()))?;
+		}
+		if tmp_dir.is_dir() {
+			info!("Removing temporary directory ...");
+			remove_dir_all(&tmp_dir).context(format!(
+				"Failed to remove the temporary directory at {}",
+				tmp_dir.display()
+			))?;
+		}
+		for rel in &pool_files {
+			if let Err(e) = remove_file(root.join(rel)) {
+				warn!("Unable to remove {}: {}", rel.display(), e);
+				continue;
+			}
+			info!("Removed {}", rel.display());
+		}
+		info!("Reclaimed {}.", format_size(reclaimed_bytes));
+	}
+	Ok(GcReport {
+		repo: config.name.clone(),
+		snapshot,
+		dry_run,
+		dists_dirs,
+		temp_files,
+		pool_files,
+		reclaimed_bytes,
+	})
+}
+
+#[test]
+fn test_reclaimed() -> Result<()> {
+	let dir = crate::utils::test_dir("gc")?;
+	// An old snapshot, sharing a file with the published one
+	let old = dir.join("dists-1");
+	let published = dir.join("dists-2");
+	std::fs::create_dir_all(&old)?;
+	std::fs::create_dir_all(&published)?;
+	std::fs::write(old.join("Release"), "old")?;
+	std::fs::write(old.join("Packages"), "shared")?;
+	std::fs::hard_link(old.join("Packages"), published.join("Packages"))?;
+	// A pool file with a hard link elsewhere in the pool
+	std::fs::write(dir.join("a.deb"), "package")?;
+	std::fs::hard_link(dir.join("a.deb"), dir.join("b.deb"))?;
+
+	let mut reclaimed = Reclaimed::default();
+	reclaimed.add_tree(&old);
+	reclaimed.add(&dir.join("a.deb"));
+	// Only Release is freed, the others are still linked.
+	assert_eq!(reclaimed.bytes(), 3);
+	reclaimed.add(&dir.join("b.deb"));
+	assert_eq!(reclaimed.bytes(), 3 + 7);
+	// Missing files and directories count for nothing.
+	reclaimed.add(&dir.join("missing.deb"));
+	reclaimed.add(&published);
+	assert_eq!(reclaimed.bytes(), 3 + 7);
+	std::fs::remove_dir_all(&dir)?;
+	Ok(())
+}
@@ -0,0 +1,522 @@
+//! Sending signed sync requests to the downstream mirrors, by
+//! `sync-invoker invoke` and by sync-client after each successful sync.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use base64::prelude::*;
+use chrono::Utc;
+use ed25519_dalek::{SigningKey, ed25519::signature::SignerMut};
+use futures_util::future::join_all;
+use log::{error, info, warn};
+use reqwest::{Client, StatusCode, redirect::Policy};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+	fs::read_to_string,
+	task::JoinSet,
+	time::{Instant, sleep},
+};
+use url::Url;
+
+use crate::{
+	config::AppConfig,
+	server::{Status, SyncRequestBody, SyncRequestResponse, SyncStatusResponse},
+	verify::decode_signing_key,
+};
+
+/// Number of downstream mirrors invoked at the same time by sync-client.
+const DOWNSTREAM_JOBS: usize = 4;
+/// Seconds between two rounds of /status requests while waiting for the
+/// syncs.
+const WAIT_POLL_INTERVAL: u64 = 10;
+/// Longest delay between two attempts to invoke an endpoint.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum ClientInvocationStatus {
+	Succeeded,
+	/// Refused by the endpoint, not retried
+	Rejected {
+		reason: String,
+	},
+	/// Still failing after the retries
+	Failed {
+		reason: String,
+	},
+}
+
+/// A failed attempt to invoke an endpoint, retried afterwards.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Retry {
+	pub timestamp: i64,
+	pub reason: String,
+}
+
+/// How the requests failing with a transient error are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+	/// Retries after the first attempt
+	pub retries: u32,
+	/// Delay before the first retry, doubled for each retry after it
+	pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self {
+			retries: 3,
+			backoff: Duration::from_secs(1),
+		}
+	}
+}
+
+impl RetryPolicy {
+	/// Delay before the retry after `retried` retries.
+	fn delay(&self, retried: u32) -> Duration {
+		self.backoff
+			.saturating_mul(1 << retried.min(16))
+			.min(MAX_BACKOFF)
+	}
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncResultStatus {
+	Success,
+	Failed,
+	Cancelled,
+	/// The sync did not finish before the deadline
+	Timeout,
+}
+
+/// How the sync started by the request ended, as reported by the /status
+/// endpoint of the downstream mirror.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SyncResult {
+	pub status: SyncResultStatus,
+	/// Seconds from the invocation to the end of the sync
+	pub duration: Option<i64>,
+	/// Error message of a failed sync
+	pub message: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReportEntry {
+	pub endpoint: Url,
+	#[serde(flatten)]
+	pub status: ClientInvocationStatus,
+	/// Failed attempts before the last one
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub retries: Vec<Retry>,
+	/// Set by `sync-invoker invoke --wait`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub sync: Option<SyncResult>,
+}
+
+impl ReportEntry {
+	/// Whether the request was accepted, but the result of the sync is not
+	/// known yet.
+	fn waiting(&self) -> bool {
+		self.status == ClientInvocationStatus::Succeeded && self.sync.is_none()
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InvocationReport {
+	pub req_timestamp: i64,
+	pub start_timestamp: i64,
+	pub end_timestamp: i64,
+	pub num_clients: u32,
+	pub num_succeeded: u32,
+	pub num_failed: u32,
+	/// Number of endpoints whose sync succeeded, with --wait
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub num_synced: Option<u32>,
+	pub endpoints: Vec<ReportEntry>,
+}
+
+/// What became of one attempt to invoke an endpoint.
+enum Attempt {
+	Accepted,
+	/// The endpoint refused the request (4xx), e.g. for a bad signature
+	Rejected(String),
+	/// Connection errors, timeouts and server errors, worth retrying
+	Transient(String),
+}
+
+async fn invoke_once(client: &Client, endpoint: &Url, body: &str) -> Attempt {
+	let res = match client
+		.post(endpoint.clone())
+		.header("Content-Type", "application/json")
+		.body(body.to_string())
+		.send()
+		.await
+	{
+		Ok(r) => r,
+		Err(e) => return Attempt::Transient(e.to_string()),
+	};
+	let code = res.status();
+	if code.is_success() {
+		return Attempt::Accepted;
+	}
+	let text = res.text().await.unwrap_or_default();
+	let reason = match serde_json::from_str::<SyncRequestResponse>(&text) {
+		Ok(r) => format!("{}: {}", code, r.message),
+		Err(_) => code.to_string(),
+	};
+	match code {
+		StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => {
+			Attempt::Transient(reason)
+		}
+		_ if code.is_client_error() => Attempt::Rejected(reason),
+		_ => Attempt::Transient(reason),
+	}
+}
+
+async fn invoke_queue(
+	queue: Vec<Url>,
+	client: Client,
+	body: Arc<String>,
+	retry: RetryPolicy,
+) -> Vec<ReportEntry> {
+	let mut status_list = Vec::new();
+	for endpoint in queue {
+		let mut retries = Vec::new();
+		let status = loop {
+			let timestamp = Utc::now().timestamp();
+			match invoke_once(&client, &endpoint, &body).await {
+				Attempt::Accepted => {
+					info!("SUCCEED: {}", endpoint);
+					break ClientInvocationStatus::Succeeded;
+				}
+				Attempt::Rejected(reason) => {
+					error!("REJECTED: {} ({})", endpoint, reason);
+					break ClientInvocationStatus::Rejected { reason };
+				}
+				Attempt::Transient(reason)
+					if retries.len() < retry.retries as usize =>
+				{
+					let delay = retry.delay(retries.len() as u32);
+					warn!(
+						"FAILED: {} ({}), retrying in {:?} ...",
+						endpoint, reason, delay
+					);
+					retries.push(Retry { timestamp, reason });
+					sleep(delay).await;
+				}
+				Attempt::Transient(reason) => {
+					error!("FAILED: {} ({})", endpoint, reason);
+					break ClientInvocationStatus::Failed { reason };
+				}
+			}
+		};
+		status_list.push(ReportEntry {
+			endpoint,
+			status,
+			retries,
+			sync: None,
+		});
+		sleep(Duration::from_millis(250)).await;
+	}
+	status_list
+}
+
+/// Send the sync request for `timestamp` to the endpoints, with up to `jobs`
+/// endpoints invoked at the same time.
+pub async fn invoke_endpoints(
+	client: &Client,
+	private_key: &mut SigningKey,
+	timestamp: i64,
+	endpoints: Vec<Url>,
+	jobs: usize,
+	retry: RetryPolicy,
+) -> Result<InvocationReport> {
+	let sig = BASE64_STANDARD.encode({
+		private_key
+			.sign(timestamp.to_string().as_bytes())
+			.to_bytes()
+	});
+	info!("Timestamp: {}", timestamp);
+	info!("Signature: {}", sig);
+	let req_body = Arc::new(
+		json!(SyncRequestBody {
+			timestamp,
+			signature: sig,
+		})
+		.to_string(),
+	);
+
+	let actual_num_jobs = endpoints.len().clamp(1, jobs.max(1));
+	let mut queues = vec![Vec::<Url>::new(); actual_num_jobs];
+	let len = endpoints.len();
+	for (q_idx, endpoint) in endpoints.into_iter().enumerate() {
+		queues[q_idx % actual_num_jobs].push(endpoint);
+	}
+	info!(
+		"Invoking {} clients with {} parallel jobs",
+		len, actual_num_jobs
+	);
+	let start_timestamp = Utc::now().timestamp();
+	let mut tasks = JoinSet::new();
+	for queue in queues {
+		let client = client.clone();
+		let body = req_body.clone();
+		tasks.spawn(async move { invoke_queue(queue, client, body, retry).await });
+	}
+	let mut results = Vec::new();
+	while let Some(t) = tasks.join_next().await {
+		results.extend(t?);
+	}
+	Ok(InvocationReport {
+		req_timestamp: timestamp,
+		start_timestamp,
+		end_timestamp: Utc::now().timestamp(),
+		num_clients: len as u32,
+		num_succeeded: results
+			.iter()
+			.filter(|r| r.status == ClientInvocationStatus::Succeeded)
+			.count() as u32,
+		num_failed: results
+			.iter()
+			.filter(|r| r.status != ClientInvocationStatus::Succeeded)
+			.count() as u32,
+		num_synced: None,
+		endpoints: results,
+	})
+}
+
+/// Check the sync started by the request with `timestamp` on `endpoint` (the
+/// /do-sync URL), sent at `since`. Returns None if it is not finished yet.
+async fn check_sync(
+	client: &Client,
+	endpoint: &Url,
+	timestamp: i64,
+	since: i64,
+) -> Result<Option<SyncResult>> {
+	// /do-sync and /status are siblings, also under /repos/<name>/.
+	let url = endpoint.join("status")?;
+	let text = client
+		.get(url.clone())
+		.send()
+		.await?
+		.error_for_status()?
+		.text()
+		.await?;
+	let status: SyncStatusResponse =
+		serde_json::from_str(&text).context(format!("Invalid response from {}", url))?;
+	// A request arriving during a sync is queued, and syncing stays true
+	// until the follow-up sync finishes. Queued follow-up syncs serve the
+	// newest request, so their timestamps are never older than ours.
+	let served = match status.last_request_timestamp {
+		Some(t) => t >= timestamp,
+		// Older versions do not report it. A sync finishing in the same
+		// second as the request was sent might predate it.
+		None => status.last_sync_timestamp > since,
+	};
+	if status.syncing || !served {
+		return Ok(None);
+	}
+	let result = match status.last_sync_status {
+		Status::Success => SyncResultStatus::Success,
+		Status::Cancelled => SyncResultStatus::Cancelled,
+		Status::Failed => SyncResultStatus::Failed,
+		Status::Queued => return Ok(None),
+	};
+	Ok(Some(SyncResult {
+		status: result,
+		duration: Some(status.last_sync_timestamp - since),
+		message: status.last_sync_message,
+	}))
+}
+
+/// Poll the /status endpoints of the downstream mirrors that accepted the
+/// request, until their syncs finish or the deadline passes, and record the
+/// results in the report.
+pub async fn wait_for_syncs(client: &Client, report: &mut InvocationReport, deadline: Duration) {
+	let deadline = Instant::now() + deadline;
+	let since = report.start_timestamp;
+	let timestamp = report.req_timestamp;
+	let mut last_errors = vec![None; report.endpoints.len()];
+	loop {
+		let pending: Vec<_> = report
+			.endpoints
+			.iter()
+			.enumerate()
+			.filter(|(_, e)| e.waiting())
+			.map(|(idx, e)| (idx, e.endpoint.clone()))
+			.collect();
+		info!("Waiting for {} downstream syncs ...", pending.len());
+		let results =
+			join_all(pending.iter().map(|(_, endpoint)| {
+				check_sync(client, endpoint, timestamp, since)
+			}))
+			.await;
+		for ((idx, endpoint), result) in pending.into_iter().zip(results) {
+			match result {
+				Ok(Some(sync)) => {
+					match sync.status {
+						SyncResultStatus::Success => {
+							info!("SYNCED: {}", endpoint)
+						}
+						_ => error!(
+							"SYNC FAILED: {} ({})",
+							endpoint, sync.message
+						),
+					}
+					report.endpoints[idx].sync = Some(sync);
+				}
+				Ok(None) => last_errors[idx] = None,
+				Err(e) => {
+					warn!("Unable to get the status of {}: {:#}", endpoint, e);
+					last_errors[idx] = Some(format!("{:#}", e));
+				}
+			}
+		}
+		if !report.endpoints.iter().any(ReportEntry::waiting)
+			|| Instant::now() + Duration::from_secs(WAIT_POLL_INTERVAL) > deadline
+		{
+			break;
+		}
+		sleep(Duration::from_secs(WAIT_POLL_INTERVAL)).await;
+	}
+	for (entry, error) in report.endpoints.iter_mut().zip(last_errors) {
+		if !entry.waiting() {
+			continue;
+		}
+		error!("TIMEOUT: {}", entry.endpoint);
+		entry.sync = Some(SyncResult {
+			status: SyncResultStatus::Timeout,
+			duration: None,
+			message: error.unwrap_or_else(|| {
+				"The sync did not finish before the deadline".into()
+			}),
+		});
+	}
+	report.num_synced = Some(report
+		.endpoints
+		.iter()
+		.filter(|e| {
+			e.sync.as_ref()
+				.is_some_and(|s| s.status == SyncResultStatus::Success)
+		})
+		.count() as u32);
+}
+
+/// Pass the sync request for the snapshot just published on to the
+/// downstream mirrors in the config. Returns None if there are none.
+pub async fn push_downstream(
+	config: &AppConfig,
+	timestamp: i64,
+) -> Result<Option<InvocationReport>> {
+	let Some(key_file) = &config.downstream_private_key else {
+		return Ok(None);
+	};
+	if config.downstream_endpoints.is_empty() {
+		return Ok(None);
+	}
+	let mut private_key = decode_signing_key(
+		&read_to_string(key_file)
+			.await
+			.context("Failed to read the private key file")?,
+	)?;
+	let client = Client::builder()
+		.timeout(Duration::from_secs(config.downstream_timeout))
+		.redirect(Policy::limited(10))
+		.user_agent("aosc-mirror/0.1.0")
+		.build()?;
+	info!("Invoking the downstream mirrors ...");
+	let report = invoke_endpoints(
+		&client,
+		&mut private_key,
+		timestamp,
+		config.downstream_endpoints.clone(),
+		DOWNSTREAM_JOBS,
+		RetryPolicy::default(),
+	)
+	.await?;
+	info!(
+		"{} of {} downstream mirrors accepted the sync request.",
+		report.num_succeeded, report.num_clients
+	);
+	Ok(Some(report))
+}
+
+#[test]
+fn test_retry_delay() {
+	let retry = RetryPolicy {
+		retries: 20,
+		backoff: Duration::from_secs(2),
+	};
+	assert_eq!(retry.delay(0), Duration::from_secs(2));
+	assert_eq!(retry.delay(3), Duration::from_secs(16));
+	assert_eq!(retry.delay(19), MAX_BACKOFF);
+}
+
+#[tokio::test]
+async fn test_push_downstream() -> Result<()> {
+	use axum::{Json, Router, extract::Path, http::StatusCode, routing::post};
+
+	use crate::verify::verify_request_signature;
+
+	let key = SigningKey::from_bytes(&[7; 32]);
+	let other = SigningKey::from_bytes(&[8; 32]);
+	let dir = crate::utils::test_dir("push")?;
+	let key_file = dir.join("privkey");
+	std::fs::write(&key_file, BASE64_STANDARD.encode(key.to_bytes()))?;
+	let mut config = crate::config::test_config("");
+	// Nothing to push to
+	assert!(push_downstream(&config, 100).await?.is_none());
+
+	// The "ok" mirror trusts the key, the "other" one does not.
+	let keys = [
+		("ok", key.verifying_key()),
+		("other", other.verifying_key()),
+	];
+	let app = Router::new().route(
+		"/{mirror}/do-sync",
+		post(
+			move |Path(mirror): Path<String>, Json(body): Json<SyncRequestBody>| async move {
+				let key = keys.iter().find(|(name, _)| *name == mirror).unwrap().1;
+				match verify_request_signature(
+					&body.timestamp.to_string(),
+					&body.signature,
+					&vec![key],
+				) {
+					Ok(_) if body.timestamp == 100 => StatusCode::OK,
+					_ => StatusCode::BAD_REQUEST,
+				}
+			},
+		),
+	);
+	let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+	let addr = listener.local_addr()?;
+	tokio::spawn(async move { axum::serve(listener, app).await });
+	config.downstream_private_key = Some(key_file);
+	config.downstream_endpoints = vec![
+		format!("http://{}/ok/do-sync", addr).parse()?,
+		format!("http://{}/other/do-sync", addr).parse()?,
+	];
+	let report = push_downstream(&config, 100)
+		.await?
+		.context("Expected a report")?;
+	assert_eq!(report.req_timestamp, 100);
+	assert_eq!((report.num_succeeded, report.num_failed), (1, 1));
+	// The endpoints are listed in the order they are done.
+	let status = |mirror: &str| {
+		let prefix = format!("/{}/", mirror);
+		let entry = report
+			.endpoints
+			.iter()
+			.find(|e| e.endpoint.path().starts_with(&prefix));
+		entry.unwrap().status.clone()
+	};
+	assert_eq!(status("ok"), ClientInvocationStatus::Succeeded);
+	assert!(matches!(
+		status("other"),
+		ClientInvocationStatus::Rejected { .. }
+	));
+	std::fs::remove_dir_all(&dir)?;
+	Ok(())
+}
@@ -1,20 +1,44 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use ed25519_dalek::VerifyingKey;
 use reqwest::Client;
 use tokio::{
-	sync::mpsc::{Receiver, Sender},
+	sync::{
+		Semaphore,
+		mpsc::{Receiver, Sender},
+	},
 	task::JoinHandle,
 };
 
-use crate::{config::AppConfig, server::Status, verify::PgpKeyringStore};
+use crate::{
+	bandwidth::SharedBandwidth, cancel::SyncTask, config::AppConfig, events::EventLog,
+	invoke::InvocationReport, metrics::Metrics, rsync::TransferStats, server::Status,
+	verify::PgpKeyringStore,
+};
 
 pub mod aosc;
+pub mod bandwidth;
+pub mod cancel;
 pub mod config;
 pub mod debian;
+pub mod events;
+pub mod fsck;
+pub mod gc;
+pub mod invoke;
+pub mod listener;
+pub mod lock;
 pub mod metadata;
+pub mod metrics;
+pub mod plan;
+pub mod poll;
+pub mod reload;
+pub mod rsync;
 pub mod server;
+pub mod state;
 pub mod sync;
+pub mod systemd;
+pub mod tls;
+pub mod upstream;
 pub mod utils;
 pub mod verify;
 
@@ -25,13 +49,71 @@ pub type JoinHandleReceiver = Receiver<JoinHandle<()>>;
 pub struct AppState {
 	// Status flags
 	pub syncing: bool,
+	/// Timestamp of the newest request arrived during the running sync
+	pub pending_timestamp: Option<i64>,
+	/// Timestamp of the running sync job
+	pub current_timestamp: Option<i64>,
+	/// Handle to cancel the running sync task
+	pub sync_task: Option<SyncTask>,
+	/// Set once a termination signal is received
+	pub shutting_down: bool,
 	pub config: Arc<AppConfig>,
+	/// Path of the config file, read again on reload
+	pub config_path: PathBuf,
 	pub last_sync_timestamp: i64,
+	/// Timestamp of the sync request the last sync job served
+	pub last_request_timestamp: Option<i64>,
 	pub last_sync_status: Status,
 	pub last_sync_message: String,
+	/// Figures of the rsync transfer of the last sync, None if it failed or
+	/// had nothing to download
+	pub last_transfer: Option<TransferStats>,
+	/// mirror_url of the upstream the last successful sync used
+	pub last_upstream: Option<String>,
+	/// Results of the sync requests sent to the downstream mirrors after the
+	/// last successful sync
+	pub last_push: Option<InvocationReport>,
 	pub keyring_store: Arc<PgpKeyringStore>,
 	pub server_pubkeys: Arc<Vec<VerifyingKey>>,
+	pub metrics: Arc<Metrics>,
+	pub events: Arc<EventLog>,
+	/// Limits the rsync instances running at the same time, shared by all
+	/// repositories
+	pub transfers: Arc<Semaphore>,
+	/// The bandwidth cap, shared by all repositories
+	pub bandwidth: Arc<SharedBandwidth>,
 	// reqwest uses Arc internally.
 	pub client: Client,
 	pub sender: JoinHandleSender,
 }
+
+/// The state of a daemon for the tests, not syncing. The sync tasks can not
+/// be sent to the consumer.
+#[cfg(test)]
+pub fn test_state(config: AppConfig) -> Arc<tokio::sync::RwLock<AppState>> {
+	let (sender, _) = tokio::sync::mpsc::channel(1);
+	Arc::new(tokio::sync::RwLock::new(AppState {
+		syncing: false,
+		pending_timestamp: None,
+		current_timestamp: None,
+		sync_task: None,
+		shutting_down: false,
+		config: Arc::new(config),
+		config_path: PathBuf::new(),
+		last_sync_timestamp: 0,
+		last_request_timestamp: None,
+		last_sync_status: Status::Success,
+		last_sync_message: String::new(),
+		last_transfer: None,
+		last_upstream: None,
+		last_push: None,
+		keyring_store: Arc::new(PgpKeyringStore::new()),
+		server_pubkeys: Arc::new(Vec::new()),
+		metrics: Arc::new(Metrics::new()),
+		events: Arc::new(EventLog::new(events::DEFAULT_EVENT_BUFFER_SIZE)),
+		transfers: Arc::new(Semaphore::new(1)),
+		bandwidth: Arc::default(),
+		client: Client::new(),
+		sender,
+	}))
+}
@@ -0,0 +1,277 @@
+use std::{
+	ffi::CString,
+	fmt::Display,
+	fs::{Permissions, remove_file, set_permissions, symlink_metadata},
+	io,
+	net::SocketAddr,
+	os::unix::{
+		fs::{FileTypeExt, PermissionsExt, chown},
+		net::UnixStream as StdUnixStream,
+	},
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use axum::{
+	extract::connect_info::Connected,
+	serve::{IncomingStream, Listener},
+};
+use log::{debug, warn};
+use tokio::{
+	net::{TcpListener, TcpStream, UnixListener, UnixStream, unix::UCred},
+	sync::mpsc,
+	time::{sleep, timeout},
+};
+use tokio_rustls::{TlsAcceptor, rustls::ServerConfig, server::TlsStream};
+
+/// Clients taking longer than this to finish the TLS handshake are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address of the connected peer, used as the connect info of the server.
+#[derive(Clone, Debug)]
+pub enum PeerAddr {
+	Tcp(SocketAddr),
+	Tls(SocketAddr),
+	/// Credentials of the peer process, if available
+	Unix(Option<UCred>),
+}
+
+impl Display for PeerAddr {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+			PeerAddr::Tls(addr) => write!(f, "{} (TLS)", addr),
+			PeerAddr::Unix(Some(cred)) => {
+				write!(f, "local process (uid {}, gid {}", cred.uid(), cred.gid())?;
+				if let Some(pid) = cred.pid() {
+					write!(f, ", pid {}", pid)?;
+				}
+				write!(f, ")")
+			}
+			PeerAddr::Unix(None) => write!(f, "local process"),
+		}
+	}
+}
+
+/// Plain TCP listener.
+pub struct TcpPeerListener(pub TcpListener);
+
+impl Listener for TcpPeerListener {
+	type Io = TcpStream;
+	type Addr = PeerAddr;
+
+	async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+		let (io, addr) = Listener::accept(&mut self.0).await;
+		(io, PeerAddr::Tcp(addr))
+	}
+
+	fn local_addr(&self) -> io::Result<Self::Addr> {
+		Ok(PeerAddr::Tcp(self.0.local_addr()?))
+	}
+}
+
+impl Connected<IncomingStream<'_, TcpPeerListener>> for PeerAddr {
+	fn connect_info(stream: IncomingStream<'_, TcpPeerListener>) -> Self {
+		stream.remote_addr().clone()
+	}
+}
+
+/// TCP listener performing TLS handshakes in the background, so that a slow
+/// client does not block the others.
+pub struct TlsListener {
+	local_addr: SocketAddr,
+	rx: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+	pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> io::Result<Self> {
+		let local_addr = listener.local_addr()?;
+		let acceptor = TlsAcceptor::from(config);
+		let (tx, rx) = mpsc::channel(64);
+		tokio::spawn(async move {
+			loop {
+				let accepted = tokio::select! {
+					r = listener.accept() => r,
+					// The server is shut down.
+					_ = tx.closed() => break,
+				};
+				let (stream, addr) = match accepted {
+					Ok(s) => s,
+					Err(e) => {
+						warn!("Failed to accept a connection: {}", e);
+						sleep(Duration::from_millis(100)).await;
+						continue;
+					}
+				};
+				let acceptor = acceptor.clone();
+				let tx = tx.clone();
+				tokio::spawn(async move {
+					match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+						.await
+					{
+						Ok(Ok(s)) => {
+							let _ = tx.send((s, addr)).await;
+						}
+						Ok(Err(e)) => debug!(
+							"TLS handshake with {} failed: {}",
+							addr, e
+						),
+						Err(_) => debug!(
+							"TLS handshake with {} timed out",
+							addr
+						),
+					}
+				});
+			}
+		});
+		Ok(Self { local_addr, rx })
+	}
+}
+
+impl Listener for TlsListener {
+	type Io = TlsStream<TcpStream>;
+	type Addr = PeerAddr;
+
+	async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+		match self.rx.recv().await {
+			Some((io, addr)) => (io, PeerAddr::Tls(addr)),
+			// Never happens since the sender lives as long as the receiver.
+			None => std::future::pending().await,
+		}
+	}
+
+	fn local_addr(&self) -> io::Result<Self::Addr> {
+		Ok(PeerAddr::Tls(self.local_addr))
+	}
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for PeerAddr {
+	fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+		stream.remote_addr().clone()
+	}
+}
+
+/// Resolve a user or group name with the given lookup function, numeric
+/// IDs are returned as is.
+fn resolve_id(name: &str, lookup: impl FnOnce(&CString) -> Option<u32>) -> Result<u32> {
+	if let Ok(id) = name.parse() {
+		return Ok(id);
+	}
+	let cname = CString::new(name)?;
+	lookup(&cname).context(format!("No such user or group: {}", name))
+}
+
+/// Listener on a UNIX domain socket. Peer credentials are used as the
+/// connect info.
+pub struct UnixPeerListener {
+	listener: UnixListener,
+	/// Socket file to remove when the listener is dropped
+	path: Option<PathBuf>,
+}
+
+impl UnixPeerListener {
+	/// Bind to the given path and set the permissions of the socket file.
+	/// A stale socket file left by a previous run is replaced.
+	pub fn bind(
+		path: &Path,
+		mode: Option<u32>,
+		owner: Option<&str>,
+		group: Option<&str>,
+	) -> Result<Self> {
+		if let Ok(meta) = symlink_metadata(path) {
+			if !meta.file_type().is_socket() {
+				bail!("{} exists and is not a socket", path.display());
+			}
+			if StdUnixStream::connect(path).is_ok() {
+				bail!("{} is in use by another process", path.display());
+			}
+			remove_file(path).context(format!(
+				"Failed to remove the stale socket {}",
+				path.display()
+			))?;
+		}
+		let listener = UnixListener::bind(path)
+			.context(format!("Failed to bind to {}", path.display()))?;
+		let listener = Self {
+			listener,
+			path: Some(path.to_owned()),
+		};
+		if let Some(mode) = mode {
+			set_permissions(path, Permissions::from_mode(mode))
+				.context(format!("Failed to set the mode of {}", path.display()))?;
+		}
+		// SAFETY: getpwnam(3) and getgrnam(3) are only called during the
+		// startup, the returned pointers are checked and used immediately.
+		let uid = owner
+			.map(|o| {
+				resolve_id(o, |n| unsafe {
+					libc::getpwnam(n.as_ptr()).as_ref().map(|p| p.pw_uid)
+				})
+			})
+			.transpose()?;
+		let gid = group
+			.map(|g| {
+				resolve_id(g, |n| unsafe {
+					libc::getgrnam(n.as_ptr()).as_ref().map(|g| g.gr_gid)
+				})
+			})
+			.transpose()?;
+		if uid.is_some() || gid.is_some() {
+			chown(path, uid, gid).context(format!(
+				"Failed to change the owner of {}",
+				path.display()
+			))?;
+		}
+		Ok(listener)
+	}
+
+	/// Take a listening socket passed by systemd. The socket file is left
+	/// alone when the listener is dropped.
+	pub fn from_std(listener: std::os::unix::net::UnixListener) -> io::Result<Self> {
+		listener.set_nonblocking(true)?;
+		Ok(Self {
+			listener: UnixListener::from_std(listener)?,
+			path: None,
+		})
+	}
+}
+
+impl Drop for UnixPeerListener {
+	fn drop(&mut self) {
+		if let Some(path) = &self.path {
+			let _ = remove_file(path);
+		}
+	}
+}
+
+impl Listener for UnixPeerListener {
+	type Io = UnixStream;
+	type Addr = PeerAddr;
+
+	async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+		loop {
+			match self.listener.accept().await {
+				Ok((io, _)) => {
+					let cred = io.peer_cred().ok();
+					return (io, PeerAddr::Unix(cred));
+				}
+				Err(e) => {
+					warn!("Failed to accept a connection: {}", e);
+					sleep(Duration::from_millis(100)).await;
+				}
+			}
+		}
+	}
+
+	fn local_addr(&self) -> io::Result<Self::Addr> {
+		Ok(PeerAddr::Unix(None))
+	}
+}
+
+impl Connected<IncomingStream<'_, UnixPeerListener>> for PeerAddr {
+	fn connect_info(stream: IncomingStream<'_, UnixPeerListener>) -> Self {
+		stream.remote_addr().clone()
+	}
+}
@@ -0,0 +1,142 @@
+//! Lock file in the state directory, to keep several processes (e.g. the
+//! daemon and a `sync-client sync` run by cron) from changing the same
+//! mirror at once.
+
+use std::{
+	fmt::Display,
+	fs::{File, create_dir_all},
+	io::{Read, Seek, Write},
+	os::fd::AsRawFd,
+	path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::state::state_dir;
+
+/// Who holds the lock, written to the lock file.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LockHolder {
+	pub pid: u32,
+	/// Timestamp of the sync job, or the start time of other commands
+	pub run_id: i64,
+	pub command: String,
+	pub since: i64,
+}
+
+impl Display for LockHolder {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let since = DateTime::<Utc>::from_timestamp(self.since, 0)
+			.map(|t| t.with_timezone(&Local).to_string())
+			.unwrap_or_default();
+		write!(
+			f,
+			"'{}' (PID {}, run {}) since {}",
+			self.command, self.pid, self.run_id, since
+		)
+	}
+}
+
+/// An exclusive flock(2) on `.aosc-mirror/lock`, released when dropped or
+/// when the process exits.
+pub struct MirrorLock {
+	file: File,
+}
+
+fn read_holder(file: &mut File) -> Option<LockHolder> {
+	let mut content = String::new();
+	file.rewind().ok()?;
+	file.read_to_string(&mut content).ok()?;
+	serde_json::from_str(&content).ok()
+}
+
+impl MirrorLock {
+	/// Take the lock of the mirror without waiting. Fails with the holder
+	/// in the message if another process holds it.
+	pub fn acquire(mirror_root: &Path, command: &str, run_id: i64) -> Result<Self> {
+		let dir = state_dir(&mirror_root);
+		create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+		let path = dir.join("lock");
+		let mut file = File::options()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(&path)
+			.context(format!("Failed to open the lock file {}", path.display()))?;
+		// SAFETY: The file descriptor is valid while the file is open.
+		if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+			let e = std::io::Error::last_os_error();
+			if e.kind() != std::io::ErrorKind::WouldBlock {
+				return Err(e)
+					.context(format!("Failed to lock {}", path.display()));
+			}
+			match read_holder(&mut file) {
+				Some(holder) => bail!(
+					"The mirror at {} is locked by {}",
+					mirror_root.display(),
+					holder
+				),
+				None => bail!(
+					"The mirror at {} is locked by another process",
+					mirror_root.display()
+				),
+			}
+		}
+		// The kernel releases the lock when its holder exits, but the
+		// record is only emptied on a clean release. Anything left belongs
+		// to a process that crashed while changing the mirror.
+		if let Some(holder) = read_holder(&mut file) {
+			warn!(
+				"{} did not exit cleanly, the mirror might be left half changed. Replacing its record.",
+				holder
+			);
+		}
+		let holder = LockHolder {
+			pid: std::process::id(),
+			run_id,
+			command: command.to_string(),
+			since: Utc::now().timestamp(),
+		};
+		file.set_len(0)?;
+		file.rewind()?;
+		file.write_all(serde_json::to_string(&holder)?.as_bytes())?;
+		file.sync_all()?;
+		Ok(Self { file })
+	}
+}
+
+impl Drop for MirrorLock {
+	fn drop(&mut self) {
+		// Closing the file releases the lock.
+		if let Err(e) = self.file.set_len(0) {
+			warn!("Failed to clear the lock file: {}", e);
+		}
+	}
+}
+
+#[test]
+fn test_mirror_lock() -> Result<()> {
+	let root = crate::utils::test_dir("lock")?;
+	let lock = MirrorLock::acquire(&root, "sync", 1)?;
+	// flock(2) locks conflict even within a process, as long as the lock
+	// file is opened again.
+	let e = MirrorLock::acquire(&root, "gc", 2).err().unwrap();
+	assert!(e.to_string().contains("'sync'"));
+	drop(lock);
+	let lock = MirrorLock::acquire(&root, "gc", 3)?;
+	drop(lock);
+	// The record of a crashed process is replaced.
+	let path = state_dir(&root).join("lock");
+	std::fs::write(&path, r#"{"pid":1,"run_id":4,"command":"sync","since":0}"#)?;
+	let mut lock = MirrorLock::acquire(&root, "verify", 5)?;
+	let holder = read_holder(&mut lock.file).unwrap();
+	assert_eq!((holder.command.as_str(), holder.run_id), ("verify", 5));
+	drop(lock);
+	assert_eq!(std::fs::read_to_string(&path)?, "");
+	std::fs::remove_dir_all(&root)?;
+	Ok(())
+}
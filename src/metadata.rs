@@ -1,11 +1,21 @@
-use std::{collections::HashMap, io::BufRead, path::PathBuf, sync::Arc};
+use std::{
+	collections::{BTreeSet, HashMap, HashSet},
+	io::BufRead,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
 
 use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, FixedOffset};
 use deb822_lossless::Deb822;
 use futures_util::StreamExt;
 use log::{debug, info};
-use reqwest::Client;
+use reqwest::{
+	Client, StatusCode,
+	header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+};
 use sequoia_openpgp::types::HashAlgorithm;
+use serde::{Deserialize, Serialize};
 use tokio::{
 	fs::{File, create_dir_all, symlink},
 	io::{AsyncWriteExt, BufWriter, copy},
@@ -15,8 +25,12 @@ use tokio::{
 use url::Url;
 
 use crate::{
+	bandwidth::TokenBucket,
+	cancel::CancelToken,
 	config::OperationMode,
-	utils::{checksum_file, get_reader},
+	events::{EventLog, SyncEventKind},
+	state::SuiteChecksums,
+	utils::{checksum_file_cached, get_reader, link_or_copy, remove_existing},
 };
 
 const MAGIC: &str = "-----BEGIN PGP SIGNED MESSAGE-----";
@@ -25,7 +39,7 @@ const SIG_MAGIC: &str = "-----BEGIN PGP SIGNATURE-----";
 // The only thing we are interested in the Packages file is the path of the
 // deb package and its size (for fast delta scanning).
 // Integrity are verified by rsync.
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize)]
 pub struct FileEntry {
 	pub path: String,
 	pub size: u64,
@@ -33,7 +47,7 @@ pub struct FileEntry {
 
 pub type PackageFileList = Vec<FileEntry>;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum AptMetadataHashAlgm {
 	// Used by Debian and Ubuntu
 	MD5,
@@ -93,7 +107,8 @@ pub struct AptRepoReleaseInfo {
 	pub suite: String,
 	pub codename: String,
 	// pub description: String,
-	// pub date: DateTime<FixedOffset>,
+	/// None if the Date field is missing or invalid
+	pub date: Option<DateTime<FixedOffset>>,
 	pub archs: Vec<String>,
 	pub components: Vec<String>,
 	pub acquire_by_hash: bool,
@@ -114,8 +129,9 @@ impl AptRepoReleaseInfo {
 		let suite = p.get("Suite").context("Expected keys not found")?;
 		let codename = p.get("Codename").context("Expected keys not found")?;
 		// let description = p.get("Description").unwrap();
-		// let date = p.get("Date").unwrap().replace("UTC", "+0000");
-		// let date = DateTime::parse_from_rfc2822(&date)?;
+		let date = p.get("Date").and_then(|d| {
+			DateTime::parse_from_rfc2822(&d.replace("UTC", "+0000")).ok()
+		});
 		let archs = p
 			.get("Architectures")
 			.context("Expected keys not found")?
@@ -164,13 +180,50 @@ impl AptRepoReleaseInfo {
 			suite,
 			codename,
 			// description,
-			// date,
+			date,
 			archs,
 			components,
 			acquire_by_hash,
 			metadata_info,
 		})
 	}
+
+	/// Returns the metadata files to download in the given mode, along with
+	/// the hash algorithm to verify them.
+	pub fn metadata_files(
+		&self,
+		mode: OperationMode,
+	) -> Result<(AptMetadataHashAlgm, Vec<&AptMetadataFileEntry>)> {
+		// Prefer SHA256/SHA512 over SHA1 and MD5.
+		let f = self.metadata_info.iter().find(|x| {
+			x.hash_algo == AptMetadataHashAlgm::SHA256
+				|| x.hash_algo == AptMetadataHashAlgm::SHA512
+		});
+		let info = if let Some(info) = f {
+			info
+		} else if let Some(info) = self.metadata_info.first() {
+			info
+		} else {
+			bail!("No file hash info is found!");
+		};
+		let files = info
+			.files
+			.iter()
+			.filter(|f| {
+				mode != OperationMode::Debian
+					|| f.path.extension().is_some_and(|x| {
+						["gz", "xz", "bz2"].contains(&x.to_str().unwrap())
+					}) || f.path.file_name().is_some_and(|x| x == "Release")
+			})
+			.collect();
+		Ok((info.hash_algo, files))
+	}
+
+	/// Returns the total size of the metadata files to download, in bytes.
+	pub fn metadata_size(&self, mode: OperationMode) -> Result<u64> {
+		let (_, files) = self.metadata_files(mode)?;
+		Ok(files.iter().map(|f| f.size as u64).sum())
+	}
 }
 
 #[inline]
@@ -188,42 +241,191 @@ pub async fn fetch_manifest(
 	client: &Client,
 ) -> Result<(Option<String>, Option<(String, String)>)> {
 	info!("Fetching APT repository manifests ...");
-	let url_in_release = base_url.join(format!("dists/{}/InRelease", suite).as_str())?;
-	let url_release = base_url.join(format!("dists/{}/Release", suite).as_str())?;
+	match fetch_manifest_conditional(&base_url, &suite, client, None, None)
+		.await
+		.context("Failed to fetch the repository manifest files")?
+	{
+		ManifestFetch::Fetched {
+			inrelease, release, ..
+		} => Ok((inrelease, release)),
+		ManifestFetch::Unchanged => unreachable!("No validators are sent"),
+	}
+}
+
+/// HTTP validators of a fetched manifest file, sent back with conditional
+/// requests.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Validators {
+	pub etag: Option<String>,
+	pub last_modified: Option<String>,
+}
+
+/// Validators of the InRelease and Release files of a suite.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SuiteValidators {
+	pub inrelease: Option<Validators>,
+	pub release: Option<Validators>,
+}
+
+pub enum ManifestFetch {
+	/// Neither InRelease nor Release changed since the last fetch
+	Unchanged,
+	Fetched {
+		inrelease: Option<String>,
+		release: Option<(String, String)>,
+		validators: SuiteValidators,
+	},
+}
 
-	// Check InRelease first
-	debug!("Trying {} ...", &url_in_release);
-	let req = client.head(url_in_release).build()?;
-	let res = client
-		.execute(req)
+enum ConditionalFetch {
+	NotModified,
+	NotFound,
+	Fetched(String, Validators),
+}
+
+async fn fetch_conditional(
+	url: Url,
+	client: &Client,
+	validators: Option<&Validators>,
+) -> Result<ConditionalFetch> {
+	let mut req = client.get(url.clone());
+	if let Some(v) = validators {
+		if let Some(etag) = &v.etag {
+			req = req.header(IF_NONE_MATCH, etag);
+		}
+		if let Some(last_modified) = &v.last_modified {
+			req = req.header(IF_MODIFIED_SINCE, last_modified);
+		}
+	}
+	let res = req
+		.send()
 		.await
-		.context("Failed to get infromation of the manifest")?;
-	let mut inrelease = None;
-	if res.status().is_success() {
-		inrelease = fetch_InRelease(base_url.clone(), suite.clone(), client)
-			.await
-			.context("Failed to fetch the repository manifest files")?;
-	} else {
-		debug!("InRelease file doesn't exist, trying {} ...", &url_release);
+		.context(format!("Failed to fetch {}", url))?;
+	let status = res.status();
+	if status == StatusCode::NOT_MODIFIED {
+		return Ok(ConditionalFetch::NotModified);
+	}
+	if status.is_server_error() {
+		bail!("Failed to fetch {}: {}", url, status);
 	}
-	let req = client.head(url_release).build()?;
-	let res = client
-		.execute(req)
+	// Like the HEAD requests did, anything else unsuccessful means the file
+	// is not there.
+	if !status.is_success() {
+		return Ok(ConditionalFetch::NotFound);
+	}
+	let header = |name| {
+		res.headers()
+			.get(name)
+			.and_then(|v| v.to_str().ok())
+			.map(|v| v.to_string())
+	};
+	let validators = Validators {
+		etag: header(ETAG),
+		last_modified: header(LAST_MODIFIED),
+	};
+	let body = res
+		.text()
 		.await
-		.context("Failed to get infromation of the manifest")?;
-	let mut release = None;
-	if res.status().is_success() {
-		release = fetch_Release(base_url.clone(), suite.clone(), client)
-			.await
-			.context("Failed to fetch the repository manifest files")?;
-	} else if inrelease.is_none() {
-		bail!(
+		.context("Failed to decode response body as UTF-8 text")?;
+	Ok(ConditionalFetch::Fetched(body, validators))
+}
+
+/// Like [`fetch_manifest`], but sends the validators of the last fetch with
+/// the requests. Returns [`ManifestFetch::Unchanged`] if the server replies
+/// with 304 Not Modified. If only one of InRelease and Release changed, the
+/// other one is taken from `cached`, the directory of the files fetched last
+/// time.
+pub async fn fetch_manifest_conditional(
+	base_url: &Url,
+	suite: &str,
+	client: &Client,
+	previous: Option<&SuiteValidators>,
+	cached: Option<&Path>,
+) -> Result<ManifestFetch> {
+	debug!("Fetching the manifests of suite {} ...", suite);
+	let url_in_release = base_url.join(format!("dists/{}/InRelease", suite).as_str())?;
+	let url_release = base_url.join(format!("dists/{}/Release", suite).as_str())?;
+	let url_release_sig = base_url.join(format!("dists/{}/Release.gpg", suite).as_str())?;
+	let mut inrelease = fetch_conditional(
+		url_in_release.clone(),
+		client,
+		previous.and_then(|p| p.inrelease.as_ref()),
+	)
+	.await?;
+	let mut release = fetch_conditional(
+		url_release.clone(),
+		client,
+		previous.and_then(|p| p.release.as_ref()),
+	)
+	.await?;
+	match (&inrelease, &release) {
+		(ConditionalFetch::NotModified, ConditionalFetch::NotModified)
+		| (ConditionalFetch::NotModified, ConditionalFetch::NotFound)
+		| (ConditionalFetch::NotFound, ConditionalFetch::NotModified) => {
+			return Ok(ManifestFetch::Unchanged);
+		}
+		(ConditionalFetch::NotFound, ConditionalFetch::NotFound) => bail!(
 			"Specified repository at '{}' has neither InRelease or Release file. Can not continue.",
 			base_url
-		);
+		),
+		_ => {}
 	}
-
-	Ok((inrelease, release))
+	// Only one of them changed, reuse the other one, or fetch it again as a
+	// whole if it is not there.
+	let read_cached = |name: &str| {
+		let path = cached.map(|dir| dir.join(name));
+		async move { tokio::fs::read_to_string(path?).await.ok() }
+	};
+	if let ConditionalFetch::NotModified = inrelease {
+		inrelease = match read_cached("InRelease").await {
+			Some(body) => {
+				debug!("InRelease of suite {} is unchanged, reusing it.", suite);
+				let v = previous.and_then(|p| p.inrelease.clone());
+				ConditionalFetch::Fetched(body, v.unwrap_or_default())
+			}
+			None => fetch_conditional(url_in_release.clone(), client, None).await?,
+		};
+	}
+	let mut release_sig = None;
+	if let ConditionalFetch::NotModified = release {
+		release = match (
+			read_cached("Release").await,
+			read_cached("Release.gpg").await,
+		) {
+			(Some(body), Some(sig)) => {
+				debug!("Release of suite {} is unchanged, reusing it.", suite);
+				release_sig = Some(sig);
+				let v = previous.and_then(|p| p.release.clone());
+				ConditionalFetch::Fetched(body, v.unwrap_or_default())
+			}
+			_ => fetch_conditional(url_release, client, None).await?,
+		};
+	}
+	let mut validators = SuiteValidators::default();
+	let inrelease = match inrelease {
+		ConditionalFetch::Fetched(body, v) => {
+			check_inrelease_magic(&body, &url_in_release)?;
+			validators.inrelease = Some(v);
+			Some(body)
+		}
+		_ => None,
+	};
+	let release = match release {
+		ConditionalFetch::Fetched(body, v) => {
+			let sig = match release_sig {
+				Some(sig) => sig,
+				None => fetch_to_string(url_release_sig, client).await?,
+			};
+			validators.release = Some(v);
+			Some((body, sig))
+		}
+		_ => None,
+	};
+	Ok(ManifestFetch::Fetched {
+		inrelease,
+		release,
+		validators,
+	})
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -233,11 +435,16 @@ async fn download_metadata_inner(
 	algm: AptMetadataHashAlgm,
 	timestamp: i64,
 	dst: PathBuf,
+	snapshot_root: PathBuf,
 	suite: String,
 	client: Client,
 	total_files: u32,
+	events: Arc<EventLog>,
+	checksums: Arc<Mutex<SuiteChecksums>>,
+	limiter: Option<Arc<TokenBucket>>,
+	redownload: Arc<HashSet<PathBuf>>,
 ) -> Result<()> {
-	let tmp_dst = Arc::new(dst.join(format!("dists-{}/{}", timestamp, &suite)));
+	let tmp_dst = Arc::new(snapshot_root.join(format!("dists-{}/{}", timestamp, &suite)));
 	let dst = Arc::new(dst.join(format!("dists/{}/", &suite)));
 	for f in queue {
 		let rel_path = Arc::new(f.0);
@@ -247,38 +454,35 @@ async fn download_metadata_inner(
 		let tmpdist_local_file = Arc::new(tmp_dst.join(rel_path.as_path()));
 		let dir = tmpdist_local_file.parent().context("Invalid path")?;
 		create_dir_all(dir).await?;
-		if local_file.is_file() {
+		if local_file.is_file() && !redownload.contains(rel_path.as_ref()) {
 			let path = local_file.clone();
 			let hash = hash.clone();
-			if tokio::task::spawn_blocking(move || checksum_file(algm, path, hash))
-				.await?
-				.is_ok()
+			let checksums = checksums.clone();
+			if tokio::task::spawn_blocking(move || {
+				checksum_file_cached(algm, path, hash, &checksums)
+			})
+			.await?
+			.is_ok()
 			{
-				let _ = tokio::task::spawn_blocking(move || {
+				tokio::task::spawn_blocking(move || {
 					info!(
 						"[{}/{}] '{}' is up to date.",
 						f.1.0,
 						total_files,
 						rel_path.display()
 					);
-					std::fs::copy(
-						local_file.as_path(),
-						tmpdist_local_file.as_path(),
+					link_or_copy(
+						local_file.as_ref(),
+						tmpdist_local_file.as_ref(),
 					)
-					.context(format!(
-						"Failed to copy '{}' to '{}'",
-						local_file.display(),
-						tmpdist_local_file.display()
-					))
 				})
 				.await??;
 				continue;
 			};
 		}
+		remove_existing(tmpdist_local_file.as_ref())?;
 		let dst_fd = File::options()
-			.create(true)
-			.append(false)
-			.truncate(true)
+			.create_new(true)
 			.write(true)
 			.open(tmpdist_local_file.clone().as_path())
 			.await?;
@@ -291,11 +495,15 @@ async fn download_metadata_inner(
 		let mut stream = res.bytes_stream();
 		while let Some(c) = stream.next().await {
 			let chunk = c?;
+			if let Some(limiter) = &limiter {
+				limiter.consume(chunk.len()).await;
+			}
 			copy(&mut &chunk[..], &mut writer).await?;
 		}
 		writer.flush().await?;
+		let checksums = checksums.clone();
 		let handle = tokio::task::spawn_blocking(move || {
-			checksum_file(algm, tmpdist_local_file, hash)
+			checksum_file_cached(algm, tmpdist_local_file, hash, &checksums)
 		});
 		handle.await??;
 		info!(
@@ -304,59 +512,96 @@ async fn download_metadata_inner(
 			total_files,
 			rel_path.display()
 		);
+		events.push(SyncEventKind::Download {
+			path: format!("dists/{}/{}", suite, rel_path.display()),
+		});
 	}
 	Ok(())
 }
 
+/// The queued files of a suite, relative to dists/<suite>.
+fn suite_redownload(
+	redownload: &BTreeSet<String>,
+	suite: &str,
+	codename: &str,
+) -> HashSet<PathBuf> {
+	redownload
+		.iter()
+		.filter_map(|p| {
+			p.strip_prefix(&format!("dists/{}/", suite))
+				.or_else(|| p.strip_prefix(&format!("dists/{}/", codename)))
+		})
+		.map(PathBuf::from)
+		.collect()
+}
+
+/// Returns the total size of the metadata files [`download_metadata_files`]
+/// is going to download, in bytes. The files in `dst` verified before with
+/// the same checksum are linked into the snapshot instead, so they take no
+/// space.
+pub fn metadata_download_size(
+	manifest: &AptRepoReleaseInfo,
+	mode: OperationMode,
+	dst: &Path,
+	checksums: &SuiteChecksums,
+	redownload: &BTreeSet<String>,
+) -> Result<u64> {
+	let redownload = suite_redownload(redownload, &manifest.suite, &manifest.codename);
+	let dir = dst.join(format!("dists/{}/", manifest.suite));
+	let (algm, files) = manifest.metadata_files(mode)?;
+	let size = files
+		.iter()
+		.filter(|f| {
+			redownload.contains(&f.path)
+				|| !std::fs::metadata(dir.join(&f.path)).is_ok_and(|m| {
+					m.is_file() && checksums.is_known(&m, algm, &f.hash)
+				})
+		})
+		.map(|f| f.size as u64)
+		.sum();
+	Ok(size)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn download_metadata_files(
 	base_url: &Url,
 	manifest: &AptRepoReleaseInfo,
 	dst: PathBuf,
+	snapshot_root: PathBuf,
 	timestamp: i64,
 	mode: OperationMode,
 	parallel_jobs: u32,
 	client: &Client,
+	events: &Arc<EventLog>,
+	checksums: &Arc<Mutex<SuiteChecksums>>,
+	limiter: Option<&Arc<TokenBucket>>,
+	redownload: &BTreeSet<String>,
+	cancel: &CancelToken,
 ) -> Result<()> {
 	let suite = &manifest.suite;
 	let codename = &manifest.codename;
+	let redownload = Arc::new(suite_redownload(redownload, suite, codename));
 	// Create a symbolic link with the name of dists/<suite>, points to dists/<codename>.
 	// Align with debmirror(8).
 	if mode == OperationMode::Debian && codename != suite {
-		let symlink_file = dst.join(format!("dists-{}/{}", timestamp, suite));
+		let symlink_file = snapshot_root.join(format!("dists-{}/{}", timestamp, suite));
 		debug!(
 			"Creating symbolic link {} -> {}",
 			symlink_file.display(),
 			codename
 		);
-		create_dir_all(dst.join(format!("dists-{}/{}/", timestamp, codename))).await?;
+		create_dir_all(snapshot_root.join(format!("dists-{}/{}/", timestamp, codename)))
+			.await?;
 		symlink(codename, symlink_file).await?;
 	}
 	let base_url = base_url.join(&format!("dists/{}/", suite))?;
 	let mut queues = (0..parallel_jobs)
 		.map(|_| HashMap::<PathBuf, (u32, String)>::new())
 		.collect::<Vec<_>>();
-	// Prefer SHA256/SHA512 over SHA1 and MD5.
-	let f = &manifest.metadata_info.iter().find(|x| {
-		x.hash_algo == AptMetadataHashAlgm::SHA256
-			|| x.hash_algo == AptMetadataHashAlgm::SHA512
-	});
-	let info = if let Some(info) = f {
-		info
-	} else if let Some(info) = manifest.metadata_info.first() {
-		info
-	} else {
-		bail!("No file hash info is found!");
-	};
+	let (hash_algo, files) = manifest.metadata_files(mode)?;
 
 	let mut idx: u32 = 0;
-	for f in &info.files {
-		if mode == OperationMode::Debian
-			&& (!f.path.extension().is_some_and(|x| {
-				["gz", "xz", "bz2"].contains(&x.to_str().unwrap())
-			}) && f.path.file_name().is_some_and(|x| x != "Release"))
-		{
-			continue;
-		}
+	for f in files {
 		let q_idx = (idx % parallel_jobs) as usize;
 		let queue = queues
 			.get_mut(q_idx)
@@ -372,44 +617,53 @@ pub async fn download_metadata_files(
 	for (i, q) in queues.into_iter().enumerate() {
 		let base_url = base_url.clone();
 		let dst = dst.clone();
+		let snapshot_root = snapshot_root.clone();
 		let client = client.clone();
 		let suite = suite.clone();
-		let algo = info.hash_algo;
+		let algo = hash_algo;
+		let events = events.clone();
+		let checksums = checksums.clone();
+		let limiter = limiter.cloned();
+		let redownload = redownload.clone();
 		debug!("Spawning thread {} with {} files", i, q.len());
 		handles.spawn(async move {
 			download_metadata_inner(
-				base_url, q, algo, timestamp, dst, suite, client, idx,
+				base_url,
+				q,
+				algo,
+				timestamp,
+				dst,
+				snapshot_root,
+				suite,
+				client,
+				idx,
+				events,
+				checksums,
+				limiter,
+				redownload,
 			)
 			.await
 			.context("Unable to download metadata files")
 		});
 	}
-	while let Some(r) = handles.join_next().await {
-		r??;
+	let joined = cancel
+		.run(async {
+			while let Some(r) = handles.join_next().await {
+				r??;
+			}
+			Ok(())
+		})
+		.await;
+	if joined.is_err() {
+		// Nothing may be left writing to the snapshot.
+		handles.shutdown().await;
 	}
+	joined?;
 	info!("Finished downloading metadata.");
 	Ok(())
 }
 
-#[allow(non_snake_case)]
-async fn fetch_Release(
-	base_url: Url,
-	suite: String,
-	client: &Client,
-) -> Result<Option<(String, String)>> {
-	let url_Release = base_url.join(format!("dists/{}/Release", suite).as_str())?;
-	let url_Release_sig = base_url.join(format!("dists/{}/Release.gpg", suite).as_str())?;
-
-	let body = fetch_to_string(url_Release, client).await?;
-	let sig = fetch_to_string(url_Release_sig, client).await?;
-	Ok(Some((body, sig)))
-}
-
-#[allow(non_snake_case)]
-async fn fetch_InRelease(base_url: Url, suite: String, client: &Client) -> Result<Option<String>> {
-	let url = base_url.join(format!("dists/{}/InRelease", suite).as_str())?;
-
-	let buf = fetch_to_string(url.clone(), client).await?;
+fn check_inrelease_magic(buf: &str, url: &Url) -> Result<()> {
 	let magic = buf
 		.lines()
 		.next()
@@ -420,8 +674,7 @@ async fn fetch_InRelease(base_url: Url, suite: String, client: &Client) -> Resul
 			url
 		);
 	}
-
-	Ok(Some(buf))
+	Ok(())
 }
 
 pub fn split_inrelease(content: &dyn AsRef<str>) -> (String, String) {
@@ -469,6 +722,18 @@ pub fn split_inrelease(content: &dyn AsRef<str>) -> (String, String) {
 	(body, sig)
 }
 
+/// Returns the Packages file of the given architecture in the directory of
+/// a component, preferring the compressed ones.
+pub fn find_packages_file(component_dir: &Path, arch: &str) -> Option<PathBuf> {
+	[
+		component_dir.join(format!("binary-{}/Packages.gz", arch)),
+		component_dir.join(format!("binary-{}/Packages.xz", arch)),
+		component_dir.join(format!("binary-{}/Packages", arch)),
+	]
+	.into_iter()
+	.find(|p| p.exists() && p.is_file())
+}
+
 /// Collect the files to mirror, for each architecture in each suite.
 /// This may take a few seconds. So please use [`tokio::task::spawn_blocking`].
 pub fn get_files(
@@ -476,6 +741,7 @@ pub fn get_files(
 	suites: HashMap<String, Vec<String>>,
 	archs: Vec<String>,
 	timestamp: i64,
+	cancel: &CancelToken,
 ) -> Result<PackageFileList> {
 	info!("Collecting files from {} dists ...", suites.len());
 	let mut files = Vec::with_capacity(75_000 * suites.len());
@@ -484,15 +750,9 @@ pub fn get_files(
 			let temp_dists_dir = mirror_root
 				.join(format!("dists-{}/{}/{}/", timestamp, suite.0, component));
 			for arch in &archs {
-				let packages_path = vec![
-					temp_dists_dir.join(format!("binary-{}/Packages.gz", arch)),
-					temp_dists_dir.join(format!("binary-{}/Packages.xz", arch)),
-					temp_dists_dir.join(format!("binary-{}/Packages", arch)),
-				];
-				let packages_path = packages_path
-					.into_iter()
-					.find(|p| p.exists() && p.is_file());
-				let packages_path = if let Some(p) = packages_path {
+				let packages_path = if let Some(p) =
+					find_packages_file(&temp_dists_dir, arch)
+				{
 					info!("Parsing {}", p.display());
 					p
 				} else {
@@ -520,6 +780,7 @@ pub fn get_files(
 							.context("Invalid Size: value")?;
 					}
 					if l.is_empty() {
+						cancel.check()?;
 						files.push(FileEntry {
 							path: ent_path.clone(),
 							size: ent_size,
@@ -563,3 +824,126 @@ async fn test_fetch_manifest() -> Result<()> {
 	eprintln!("{:#?}", repo_info);
 	Ok(())
 }
+
+#[tokio::test]
+async fn test_fetch_manifest_conditional() -> Result<()> {
+	use axum::{
+		Router,
+		extract::Path,
+		http::{HeaderMap, StatusCode},
+		routing::get,
+	};
+
+	// InRelease is unchanged, Release is updated to "Release 2".
+	let app = Router::new().route(
+		"/dists/stable/{file}",
+		get(|Path(file): Path<String>, headers: HeaderMap| async move {
+			let (etag, body) = match file.as_str() {
+				"InRelease" => ("\"i1\"", format!("{}\nupstream\n", MAGIC)),
+				"Release" => ("\"r2\"", "Release 2".into()),
+				"Release.gpg" => ("\"s2\"", "Signature 2".into()),
+				"Forbidden" | "Broken" => {
+					let status = match file.as_str() {
+						"Forbidden" => StatusCode::FORBIDDEN,
+						_ => StatusCode::INTERNAL_SERVER_ERROR,
+					};
+					return (status, HeaderMap::new(), String::new());
+				}
+				_ => {
+					return (
+						StatusCode::NOT_FOUND,
+						HeaderMap::new(),
+						String::new(),
+					);
+				}
+			};
+			let mut res_headers = HeaderMap::new();
+			res_headers.insert(ETAG, etag.parse().unwrap());
+			if headers.get(IF_NONE_MATCH).is_some_and(|v| v == etag) {
+				return (StatusCode::NOT_MODIFIED, res_headers, String::new());
+			}
+			(StatusCode::OK, res_headers, body)
+		}),
+	);
+	let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+	let base_url: Url = format!("http://{}/", listener.local_addr()?).parse()?;
+	tokio::spawn(async move { axum::serve(listener, app).await });
+	let client = Client::new();
+	let validators = |inrelease: &str, release: &str| SuiteValidators {
+		inrelease: Some(Validators {
+			etag: Some(inrelease.into()),
+			last_modified: None,
+		}),
+		release: Some(Validators {
+			etag: Some(release.into()),
+			last_modified: None,
+		}),
+	};
+	let dir = crate::utils::test_dir("manifests")?;
+	let cached = format!("{}\ncached\n", MAGIC);
+	std::fs::write(dir.join("InRelease"), &cached)?;
+
+	let unchanged = validators("\"i1\"", "\"r2\"");
+	let fetched = fetch_manifest_conditional(
+		&base_url,
+		"stable",
+		&client,
+		Some(&unchanged),
+		Some(&dir),
+	)
+	.await?;
+	assert!(matches!(fetched, ManifestFetch::Unchanged));
+
+	let previous = validators("\"i1\"", "\"r1\"");
+	let fetched = fetch_manifest_conditional(
+		&base_url,
+		"stable",
+		&client,
+		Some(&previous),
+		Some(&dir),
+	)
+	.await?;
+	let ManifestFetch::Fetched {
+		inrelease,
+		release,
+		validators,
+	} = fetched
+	else {
+		panic!("Release is changed");
+	};
+	// The unchanged InRelease is reused, with its validators.
+	assert_eq!(inrelease.as_deref(), Some(cached.as_str()));
+	assert_eq!(
+		validators.inrelease.unwrap().etag.as_deref(),
+		Some("\"i1\"")
+	);
+	assert_eq!(
+		release,
+		Some(("Release 2".to_string(), "Signature 2".to_string()))
+	);
+	assert_eq!(validators.release.unwrap().etag.as_deref(), Some("\"r2\""));
+
+	// Without the copy, it is fetched again.
+	std::fs::remove_file(dir.join("InRelease"))?;
+	let fetched = fetch_manifest_conditional(
+		&base_url,
+		"stable",
+		&client,
+		Some(&previous),
+		Some(&dir),
+	)
+	.await?;
+	let ManifestFetch::Fetched { inrelease, .. } = fetched else {
+		panic!("Release is changed");
+	};
+	assert_eq!(inrelease, Some(format!("{}\nupstream\n", MAGIC)));
+
+	// Only server errors fail, the other errors mean the file is missing.
+	let url = base_url.join("dists/stable/Forbidden")?;
+	let fetched = fetch_conditional(url, &client, None).await?;
+	assert!(matches!(fetched, ConditionalFetch::NotFound));
+	let url = base_url.join("dists/stable/Broken")?;
+	assert!(fetch_conditional(url, &client, None).await.is_err());
+	std::fs::remove_dir_all(&dir)?;
+	Ok(())
+}
@@ -0,0 +1,356 @@
+use std::{
+	collections::HashMap,
+	fmt::Write,
+	path::Path,
+	sync::{
+		Mutex,
+		atomic::{AtomicI64, AtomicU64, Ordering},
+	},
+	time::Duration,
+};
+
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::disk_usage;
+
+/// Upper bounds (in seconds) of the sync duration histogram buckets.
+const DURATION_BUCKETS: [f64; 10] = [
+	1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0,
+];
+
+/// Phases of a sync job, each one is timed separately.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPhase {
+	/// Fetching manifests and downloading metadata files
+	Metadata,
+	/// Parsing Packages/Sources to collect the files to mirror
+	Collect,
+	/// Comparing the collected files against the local tree
+	Scan,
+	/// Running rsync
+	Transfer,
+	/// Removing unused files and old snapshots
+	Cleanup,
+}
+
+impl SyncPhase {
+	pub const ALL: [SyncPhase; 5] = [
+		SyncPhase::Metadata,
+		SyncPhase::Collect,
+		SyncPhase::Scan,
+		SyncPhase::Transfer,
+		SyncPhase::Cleanup,
+	];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			SyncPhase::Metadata => "metadata",
+			SyncPhase::Collect => "collect",
+			SyncPhase::Scan => "scan",
+			SyncPhase::Transfer => "transfer",
+			SyncPhase::Cleanup => "cleanup",
+		}
+	}
+}
+
+/// Reasons to reject a sync request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+	InvalidSignature,
+	InternalError,
+}
+
+impl RejectReason {
+	pub const ALL: [RejectReason; 2] =
+		[RejectReason::InvalidSignature, RejectReason::InternalError];
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			RejectReason::InvalidSignature => "invalid_signature",
+			RejectReason::InternalError => "internal_error",
+		}
+	}
+}
+
+#[derive(Default, Clone, Debug)]
+struct Histogram {
+	buckets: [u64; DURATION_BUCKETS.len()],
+	sum: f64,
+	count: u64,
+}
+
+impl Histogram {
+	fn observe(&mut self, value: f64) {
+		for (idx, bound) in DURATION_BUCKETS.iter().enumerate() {
+			if value <= *bound {
+				self.buckets[idx] += 1;
+			}
+		}
+		self.sum += value;
+		self.count += 1;
+	}
+}
+
+/// Counters and gauges exposed in the Prometheus text format.
+#[derive(Default, Debug)]
+pub struct Metrics {
+	pub syncs_started: AtomicU64,
+	pub syncs_succeeded: AtomicU64,
+	pub syncs_failed: AtomicU64,
+	pub syncs_cancelled: AtomicU64,
+	/// Sync requests arrived during a running sync
+	pub requests_queued: AtomicU64,
+	/// Sync jobs started by the poller, after finding the upstream changed
+	pub syncs_polled: AtomicU64,
+	/// UNIX timestamp of the last successful sync
+	pub last_success_timestamp: AtomicI64,
+	pub bytes_transferred: AtomicU64,
+	pub files_transferred: AtomicU64,
+	pub files_deleted: AtomicU64,
+	/// Error and warning lines printed by rsync
+	pub rsync_errors: AtomicU64,
+	/// UNIX timestamp of the newest Release Date in the published snapshot
+	pub release_date: AtomicI64,
+	rejected: Mutex<HashMap<RejectReason, u64>>,
+	durations: Mutex<HashMap<SyncPhase, Histogram>>,
+}
+
+impl Metrics {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn reject(&self, reason: RejectReason) {
+		let mut rejected = self.rejected.lock().unwrap();
+		*rejected.entry(reason).or_default() += 1;
+	}
+
+	pub fn observe_phase(&self, phase: SyncPhase, duration: Duration) {
+		let mut durations = self.durations.lock().unwrap();
+		durations
+			.entry(phase)
+			.or_default()
+			.observe(duration.as_secs_f64());
+	}
+
+	/// Render all metrics in the Prometheus text exposition format.
+	/// Disk usage is read from the file system containing `mirror_root` on
+	/// every call.
+	pub fn render(&self, mirror_root: &dyn AsRef<Path>) -> String {
+		let mut buf = String::with_capacity(4096);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_syncs_started_total",
+			"counter",
+			"Number of sync jobs started.",
+			self.syncs_started.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_syncs_succeeded_total",
+			"counter",
+			"Number of sync jobs finished successfully.",
+			self.syncs_succeeded.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_syncs_failed_total",
+			"counter",
+			"Number of failed sync jobs.",
+			self.syncs_failed.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_syncs_cancelled_total",
+			"counter",
+			"Number of cancelled sync jobs.",
+			self.syncs_cancelled.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_queued_requests_total",
+			"counter",
+			"Number of sync requests queued during a running sync.",
+			self.requests_queued.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_polled_syncs_total",
+			"counter",
+			"Number of sync jobs started by polling the upstream.",
+			self.syncs_polled.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_last_success_timestamp_seconds",
+			"gauge",
+			"UNIX timestamp of the last successful sync.",
+			self.last_success_timestamp.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_transferred_bytes_total",
+			"counter",
+			"Number of bytes transferred from the upstream.",
+			self.bytes_transferred.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_transferred_files_total",
+			"counter",
+			"Number of files transferred from the upstream.",
+			self.files_transferred.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_deleted_files_total",
+			"counter",
+			"Number of unused files removed from the mirror.",
+			self.files_deleted.load(Ordering::Relaxed),
+		);
+		write_metric(
+			&mut buf,
+			"aosc_mirror_rsync_errors_total",
+			"counter",
+			"Number of errors and warnings printed by rsync.",
+			self.rsync_errors.load(Ordering::Relaxed),
+		);
+
+		let rejected = self.rejected.lock().unwrap();
+		write_header(
+			&mut buf,
+			"aosc_mirror_rejected_requests_total",
+			"counter",
+			"Number of rejected sync requests.",
+		);
+		for reason in RejectReason::ALL {
+			let _ = writeln!(
+				buf,
+				"aosc_mirror_rejected_requests_total{{reason=\"{}\"}} {}",
+				reason.as_str(),
+				rejected.get(&reason).copied().unwrap_or_default()
+			);
+		}
+		drop(rejected);
+
+		let durations = self.durations.lock().unwrap();
+		write_header(
+			&mut buf,
+			"aosc_mirror_sync_duration_seconds",
+			"histogram",
+			"Time spent in each phase of a sync job.",
+		);
+		for phase in SyncPhase::ALL {
+			let h = durations.get(&phase).cloned().unwrap_or_default();
+			for (bound, count) in DURATION_BUCKETS.iter().zip(h.buckets) {
+				let _ = writeln!(
+					buf,
+					"aosc_mirror_sync_duration_seconds_bucket{{phase=\"{}\",le=\"{}\"}} {}",
+					phase.as_str(),
+					bound,
+					count
+				);
+			}
+			let _ = writeln!(
+				buf,
+				"aosc_mirror_sync_duration_seconds_bucket{{phase=\"{}\",le=\"+Inf\"}} {}",
+				phase.as_str(),
+				h.count
+			);
+			let _ = writeln!(
+				buf,
+				"aosc_mirror_sync_duration_seconds_sum{{phase=\"{}\"}} {}",
+				phase.as_str(),
+				h.sum
+			);
+			let _ = writeln!(
+				buf,
+				"aosc_mirror_sync_duration_seconds_count{{phase=\"{}\"}} {}",
+				phase.as_str(),
+				h.count
+			);
+		}
+		drop(durations);
+
+		let release_date = self.release_date.load(Ordering::Relaxed);
+		if release_date > 0 {
+			write_metric(
+				&mut buf,
+				"aosc_mirror_release_date_timestamp_seconds",
+				"gauge",
+				"Date of the newest Release file in the published snapshot.",
+				release_date,
+			);
+			write_metric(
+				&mut buf,
+				"aosc_mirror_release_lag_seconds",
+				"gauge",
+				"Seconds since the Date of the published Release file.",
+				Utc::now().timestamp() - release_date,
+			);
+		}
+
+		match disk_usage(mirror_root) {
+			Ok((total, free)) => {
+				write_metric(
+					&mut buf,
+					"aosc_mirror_filesystem_size_bytes",
+					"gauge",
+					"Size of the file system containing the mirror root.",
+					total,
+				);
+				write_metric(
+					&mut buf,
+					"aosc_mirror_filesystem_avail_bytes",
+					"gauge",
+					"Space available to unprivileged users on the file system containing the mirror root.",
+					free,
+				);
+			}
+			Err(e) => {
+				warn!("Unable to get the disk usage of the mirror root: {}", e);
+			}
+		}
+		buf
+	}
+}
+
+pub fn write_header(buf: &mut String, name: &str, kind: &str, help: &str) {
+	let _ = writeln!(buf, "# HELP {} {}", name, help);
+	let _ = writeln!(buf, "# TYPE {} {}", name, kind);
+}
+
+/// Write a metric without labels, along with its HELP and TYPE lines.
+pub fn write_metric(
+	buf: &mut String,
+	name: &str,
+	kind: &str,
+	help: &str,
+	value: impl std::fmt::Display,
+) {
+	write_header(buf, name, kind, help);
+	let _ = writeln!(buf, "{} {}", name, value);
+}
+
+#[test]
+fn test_render_metrics() {
+	let metrics = Metrics::new();
+	metrics.syncs_started.fetch_add(2, Ordering::Relaxed);
+	metrics.reject(RejectReason::InvalidSignature);
+	metrics.observe_phase(SyncPhase::Transfer, Duration::from_secs(20));
+	let text = metrics.render(&std::env::temp_dir());
+	assert!(text.contains("aosc_mirror_syncs_started_total 2\n"));
+	assert!(text
+		.contains("aosc_mirror_rejected_requests_total{reason=\"invalid_signature\"} 1\n"));
+	assert!(text.contains(
+		"aosc_mirror_sync_duration_seconds_bucket{phase=\"transfer\",le=\"15\"} 0\n"
+	));
+	assert!(text.contains(
+		"aosc_mirror_sync_duration_seconds_bucket{phase=\"transfer\",le=\"30\"} 1\n"
+	));
+	assert!(text.contains("aosc_mirror_sync_duration_seconds_count{phase=\"transfer\"} 1\n"));
+	assert!(!text.contains("aosc_mirror_release_lag_seconds"));
+}
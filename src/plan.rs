@@ -0,0 +1,154 @@
+//! Dry runs of the sync jobs, to see what a sync would download and remove
+//! without touching the mirror.
+
+use std::{
+	collections::BTreeSet,
+	fmt::Display,
+	fs::read_dir,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use reqwest::Client;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+
+use crate::{
+	cancel::CancelToken,
+	config::AppConfig,
+	events::{DEFAULT_EVENT_BUFFER_SIZE, EventLog},
+	metadata::FileEntry,
+	metrics::Metrics,
+	state::RedownloadQueue,
+	sync::{SyncJob, old_dists_dirs, prepare_sync, suites_to_sync, unused_package_files},
+	utils::format_size,
+	verify::init_pgp_keyringstore,
+};
+
+/// What a sync would change in the mirror.
+#[derive(Debug, Serialize)]
+pub struct SyncPlan {
+	/// Name of the repository, if the config file has [[repo]] tables
+	pub repo: Option<String>,
+	/// Suites (and codenames) not in the published snapshot yet
+	pub suites_added: Vec<String>,
+	/// Suites (and codenames) in the published snapshot, but no longer synced
+	pub suites_removed: Vec<String>,
+	pub download: Vec<FileEntry>,
+	pub download_bytes: u64,
+	/// Paths relative to the mirror root, old snapshots included
+	pub delete: Vec<PathBuf>,
+}
+
+impl Display for SyncPlan {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.repo {
+			Some(name) => writeln!(f, "Plan for repository '{}':", name)?,
+			None => writeln!(f, "Plan:")?,
+		}
+		let list = |v: &[String]| {
+			if v.is_empty() {
+				"(none)".to_string()
+			} else {
+				v.join(", ")
+			}
+		};
+		writeln!(f, "  Suites added:      {}", list(&self.suites_added))?;
+		writeln!(f, "  Suites removed:    {}", list(&self.suites_removed))?;
+		writeln!(
+			f,
+			"  Files to download: {} ({})",
+			self.download.len(),
+			format_size(self.download_bytes)
+		)?;
+		writeln!(f, "  Files to delete:   {}", self.delete.len())?;
+		if !self.download.is_empty() {
+			writeln!(f, "\nFiles to download:")?;
+			for file in &self.download {
+				writeln!(f, "  {} ({})", file.path, format_size(file.size))?;
+			}
+		}
+		if !self.delete.is_empty() {
+			writeln!(f, "\nFiles to delete:")?;
+			for path in &self.delete {
+				writeln!(f, "  {}", path.display())?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Names of the directories in the published dists/ directory.
+fn published_suites(mirror_root: &Path) -> Result<BTreeSet<String>> {
+	let dists = mirror_root.join("dists");
+	if !dists.is_dir() {
+		return Ok(BTreeSet::new());
+	}
+	let mut suites = BTreeSet::new();
+	for entry in read_dir(&dists)? {
+		let entry = entry?;
+		if entry.path().is_dir() {
+			suites.insert(entry.file_name().to_string_lossy().into_owned());
+		}
+	}
+	Ok(suites)
+}
+
+/// Run a sync job up to the scan of the package files, with the metadata
+/// downloaded into `scratch`. Neither the mirror nor its states are changed.
+pub async fn plan_sync(config: &AppConfig, client: &Client, scratch: &Path) -> Result<SyncPlan> {
+	let keyring_store = init_pgp_keyringstore(&config.keyring_dir).await?;
+	let suites = suites_to_sync(config, client, scratch.to_path_buf()).await?;
+	let metrics = Metrics::new();
+	let events = Arc::new(EventLog::new(DEFAULT_EVENT_BUFFER_SIZE));
+	let transfers = Arc::new(Semaphore::new(1));
+	let bandwidth = Arc::default();
+	let timestamp = Utc::now().timestamp();
+	let j = SyncJob {
+		http_url: &config.http_url,
+		mode: config.mode,
+		mirror_sources: config.mirror_sources,
+		suites,
+		archs: config.archs.clone(),
+		dst: &config.mirror_root,
+		threads: config.parallel_jobs,
+		timestamp,
+		keyring_store: &keyring_store,
+		client,
+		metrics: &metrics,
+		events: &events,
+		transfers: &transfers,
+		bandwidth: &bandwidth,
+		disk_reserve: 0,
+		quota: None,
+		config,
+		scratch: Some(scratch),
+		redownload: RedownloadQueue::load(&config.mirror_root)?.paths,
+		cancel: CancelToken::default(),
+	};
+	let prepared = prepare_sync(&j).await?;
+
+	let root = &config.mirror_root;
+	let mut delete = Vec::new();
+	for dir in old_dists_dirs(root, timestamp)? {
+		delete.push(dir.strip_prefix(root)?.to_path_buf());
+	}
+	delete.extend(unused_package_files(root, &prepared.known_files, &events));
+
+	let published = published_suites(root)?;
+	let mut synced = BTreeSet::new();
+	for manifest in &prepared.manifests {
+		synced.insert(manifest.suite.clone());
+		synced.insert(manifest.codename.clone());
+	}
+	Ok(SyncPlan {
+		repo: config.name.clone(),
+		suites_added: synced.difference(&published).cloned().collect(),
+		suites_removed: published.difference(&synced).cloned().collect(),
+		download_bytes: prepared.delta.iter().map(|f| f.size).sum(),
+		download: prepared.delta,
+		delete,
+	})
+}
@@ -0,0 +1,179 @@
+//! Polling the upstream on a schedule, as a fallback of the sync requests
+//! pushed by the origin server.
+
+use std::{
+	sync::{Arc, atomic::Ordering},
+	time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{Local, Utc};
+use croner::Cron;
+use log::{debug, error, info, warn};
+use tokio::{fs::read_to_string, sync::RwLock, time::sleep};
+
+use crate::{
+	AppState,
+	bandwidth::polling_deferral,
+	config::AppConfig,
+	metadata::{
+		AptRepoReleaseInfo, ManifestFetch, fetch_manifest_conditional, split_inrelease,
+	},
+	state::{ManifestCache, published_snapshot, state_dir},
+	sync::{start_sync, suites_to_sync},
+	utils::sha256_hex,
+};
+
+/// Returns how long to wait until the next poll, or None if polling is not
+/// enabled.
+fn next_poll(config: &AppConfig) -> Result<Option<Duration>> {
+	if let Some(secs) = config.poll_interval {
+		return Ok(Some(Duration::from_secs(secs)));
+	}
+	let schedule = match &config.poll_schedule {
+		Some(s) => s,
+		None => return Ok(None),
+	};
+	let cron = Cron::new(schedule).parse()?;
+	let now = Local::now();
+	let next = cron.find_next_occurrence(&now, false)?;
+	Ok(Some((next - now).to_std().unwrap_or_default()))
+}
+
+/// Compare the InRelease (or Release) file of each suite (or topic) on the
+/// upstream with the published one. Returns true if any of them changed.
+async fn upstream_changed(config: &AppConfig, client: &reqwest::Client) -> Result<bool> {
+	let cache = match published_snapshot(&config.mirror_root) {
+		Some(ts) => ManifestCache::load(&config.mirror_root, ts)?.unwrap_or_default(),
+		None => ManifestCache::default(),
+	};
+	// Keep the topic manifest of the upstream away from the published one.
+	let suites =
+		suites_to_sync(config, client, state_dir(&config.mirror_root).join("poll")).await?;
+	for suite in &suites {
+		let previous = cache.suites.get(suite);
+		let published = config.mirror_root.join("dists").join(suite);
+		let fetched = fetch_manifest_conditional(
+			&config.http_url,
+			suite,
+			client,
+			previous,
+			Some(&published),
+		)
+		.await?;
+		let (inrelease, release) = match fetched {
+			ManifestFetch::Unchanged => continue,
+			ManifestFetch::Fetched {
+				inrelease, release, ..
+			} => (inrelease, release),
+		};
+		let (name, content, body) = match (inrelease, release) {
+			(Some(inrelease), _) => {
+				let (body, _) = split_inrelease(&inrelease);
+				("InRelease", inrelease, body)
+			}
+			(None, Some((release, _))) => ("Release", release.clone(), release),
+			(None, None) => continue,
+		};
+		let path = config.mirror_root.join("dists").join(suite).join(name);
+		let local = match read_to_string(&path).await {
+			Ok(s) => s,
+			Err(_) => {
+				info!("{} is not published yet.", path.display());
+				return Ok(true);
+			}
+		};
+		if sha256_hex(content.as_bytes())? == sha256_hex(local.as_bytes())? {
+			continue;
+		}
+		let upstream_date = AptRepoReleaseInfo::parse_from(&body)?.date;
+		let local_body = if name == "InRelease" {
+			split_inrelease(&local).0
+		} else {
+			local
+		};
+		let local_date = AptRepoReleaseInfo::parse_from(&local_body)
+			.context(format!("Failed to parse {}", path.display()))?
+			.date;
+		// Without the dates, any change counts.
+		if let (Some(upstream_date), Some(local_date)) = (upstream_date, local_date)
+			&& upstream_date < local_date
+		{
+			warn!(
+				"Upstream {} of suite {} ({}) is older than the published one ({}), ignoring.",
+				name, suite, upstream_date, local_date
+			);
+			continue;
+		}
+		info!("Upstream {} of suite {} changed.", name, suite);
+		return Ok(true);
+	}
+	Ok(false)
+}
+
+/// Check the upstream once, and start a sync if it changed.
+async fn poll_once(s: &Arc<RwLock<AppState>>) -> Result<()> {
+	let lock = s.read().await;
+	if lock.syncing || lock.shutting_down {
+		return Ok(());
+	}
+	let config = lock.config.clone();
+	let client = lock.client.clone();
+	drop(lock);
+	debug!("Polling the upstream for changes ...");
+	// Ask the fallback upstreams if the ones before them do not answer.
+	let upstreams = config.upstreams();
+	let mut changed = Ok(false);
+	for (idx, upstream) in upstreams.iter().enumerate() {
+		changed = upstream_changed(&config.with_upstream(upstream), &client).await;
+		match &changed {
+			Err(e) if idx + 1 < upstreams.len() => {
+				warn!("Unable to poll upstream {}: {:#}", upstream, e);
+			}
+			_ => break,
+		}
+	}
+	if !changed? {
+		debug!("The mirror is up to date.");
+		return Ok(());
+	}
+	let mut lock = s.write().await;
+	// A sync request might arrive in the meantime.
+	if lock.syncing || lock.shutting_down {
+		return Ok(());
+	}
+	info!("Starting a sync job for the upstream changes ...");
+	lock.metrics.syncs_polled.fetch_add(1, Ordering::Relaxed);
+	start_sync(s.clone(), &mut lock, Utc::now().timestamp())
+		.await
+		.context("Unable to start the sync job")?;
+	Ok(())
+}
+
+/// Poll the upstream forever according to the schedule in the config.
+/// Returns immediately if polling is not enabled.
+pub async fn run_poller(s: Arc<RwLock<AppState>>) {
+	loop {
+		let config = s.read().await.config.clone();
+		let delay = match next_poll(&config) {
+			Ok(Some(d)) => d,
+			Ok(None) => return,
+			Err(e) => {
+				error!("Invalid poll schedule: {:#}", e);
+				return;
+			}
+		};
+		sleep(delay).await;
+		let config = s.read().await.config.clone();
+		if let Some(delay) = polling_deferral(&config, Local::now().time()) {
+			info!(
+				"Polling is deferred during the bandwidth window, waiting {} minutes ...",
+				delay.as_secs().div_ceil(60)
+			);
+			sleep(delay).await;
+		}
+		if let Err(e) = poll_once(&s).await {
+			warn!("Failed to poll the upstream: {:#}", e);
+		}
+	}
+}
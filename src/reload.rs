@@ -0,0 +1,124 @@
+//! Reloading the configuration and the keyrings without restarting the
+//! daemon, triggered by SIGHUP or the /reload endpoint.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use axum::{
+	Json,
+	extract::{ConnectInfo, State},
+	http::Response,
+};
+use log::{error, info, warn};
+use tokio::sync::RwLock;
+
+use crate::{
+	AppState,
+	config::{AppConfig, check_configs, format_config_errors, load_configs},
+	listener::PeerAddr,
+	metrics::RejectReason,
+	server::{Status, SyncRequestBody, SyncRequestResponse, reject},
+	verify::{decode_pubkeys, init_pgp_keyringstore, verify_action_signature},
+};
+
+/// Settings only applied on startup. Changes to them are ignored on reload.
+fn keep_static_settings(old: &AppConfig, new: &mut AppConfig) {
+	macro_rules! keep {
+		($($field:ident),*) => {
+			$(
+				if old.$field != new.$field {
+					warn!(
+						"Changing {} requires a restart, keeping the current value.",
+						stringify!($field)
+					);
+					new.$field = old.$field.clone();
+				}
+			)*
+		};
+	}
+	keep!(
+		listen,
+		mirror_root,
+		tls_cert,
+		tls_key,
+		tls_client_ca,
+		unix_socket_mode,
+		unix_socket_owner,
+		unix_socket_group,
+		max_transfers,
+		poll_interval,
+		poll_schedule
+	);
+}
+
+/// Read the config file again, then swap the config, the APT keyring and the
+/// public keys of the origin servers. Nothing is changed if any of them is
+/// invalid. The running sync job keeps using its own snapshot.
+pub async fn reload_config(s: &Arc<RwLock<AppState>>) -> Result<()> {
+	let lock = s.read().await;
+	let path = lock.config_path.clone();
+	let old = lock.config.clone();
+	drop(lock);
+	info!("Reloading the configuration from {} ...", path.display());
+	// Repositories can not be added or removed without restarting.
+	let mut config = load_configs(&path)?
+		.into_iter()
+		.find(|c| c.name == old.name)
+		.context(match &old.name {
+			Some(name) => {
+				format!("Repository '{}' is removed from the config file", name)
+			}
+			None => "The config file now has [[repo]] tables, restart to apply".into(),
+		})?;
+	let errors = check_configs(std::slice::from_ref(&config));
+	if !errors.is_empty() {
+		error!("{}", format_config_errors(errors));
+		bail!("Error(s) found in the config file. Refer to the log above for details.");
+	}
+	keep_static_settings(&old, &mut config);
+	let server_pubkeys = decode_pubkeys(&config.server_pubkeys)?;
+	let keyring_store = init_pgp_keyringstore(&config.keyring_dir).await?;
+	let mut lock = s.write().await;
+	lock.config = Arc::new(config);
+	lock.server_pubkeys = Arc::new(server_pubkeys);
+	lock.keyring_store = Arc::new(keyring_store);
+	if lock.syncing {
+		info!("The running sync job keeps using the previous configuration.");
+	}
+	info!("Configuration reloaded.");
+	Ok(())
+}
+
+pub async fn reload(
+	ConnectInfo(addr): ConnectInfo<PeerAddr>,
+	State(s): State<Arc<RwLock<AppState>>>,
+	Json(payload): Json<SyncRequestBody>,
+) -> Response<String> {
+	info!("Got reload request from {}", addr);
+	let lock = s.read().await;
+	if !lock.config.skip_verification
+		&& let Err(e) = verify_action_signature(
+			"reload",
+			payload.timestamp,
+			&payload.signature,
+			&lock.server_pubkeys,
+		) {
+		info!("Got invalid signature, rejecting: {}", e);
+		lock.metrics.reject(RejectReason::InvalidSignature);
+		return reject(400, "Invalid signature");
+	}
+	drop(lock);
+	match reload_config(&s).await {
+		Ok(()) => {
+			let res = SyncRequestResponse {
+				status: Status::Success,
+				message: "Configuration reloaded".into(),
+			};
+			Response::new(serde_json::to_string_pretty(&res).unwrap())
+		}
+		Err(e) => {
+			error!("Failed to reload the configuration: {:#}", e);
+			reject(500, format!("Failed to reload the configuration: {:#}", e))
+		}
+	}
+}
@@ -0,0 +1,326 @@
+//! Building the rsync command lines from the config: extra options, the
+//! rsync daemon authentication and rsync over SSH.
+
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::config::AppConfig;
+
+/// Quote an argument of the remote shell command given to `rsync -e`.
+fn quote(s: &str) -> String {
+	format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// How to run rsync against the upstream of a repository.
+#[derive(Clone)]
+pub struct RsyncCommand {
+	/// `rsync://` URL, or `[user@]host:path` for rsync over SSH
+	source: String,
+	/// Options added to every instance
+	args: Vec<String>,
+	/// Passed in RSYNC_PASSWORD
+	password: Option<String>,
+	/// Path prefixes of the files transferred with --checksum
+	checksum_paths: Vec<String>,
+}
+
+// The password must not end up in the logs.
+impl std::fmt::Debug for RsyncCommand {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("RsyncCommand")
+			.field("source", &self.source)
+			.field("args", &self.args)
+			.field("checksum_paths", &self.checksum_paths)
+			.finish_non_exhaustive()
+	}
+}
+
+impl RsyncCommand {
+	pub fn new(config: &AppConfig) -> Result<Self> {
+		let url = &config.mirror_url;
+		let mut args = Vec::new();
+		let source = match url.scheme() {
+			"rsync" => {
+				if let Some(f) = &config.rsync_password_file {
+					args.push(format!("--password-file={}", f.display()));
+				}
+				url.to_string()
+			}
+			"ssh" => {
+				let host = url.host_str().context("SSH mirror URL has no host")?;
+				let mut ssh = vec!["ssh".to_string(), "-o BatchMode=yes".into()];
+				if let Some(port) = url.port() {
+					ssh.push(format!("-p {}", port));
+				}
+				if let Some(f) = &config.ssh_identity_file {
+					ssh.push(format!("-i {}", quote(&f.to_string_lossy())));
+					ssh.push("-o IdentitiesOnly=yes".into());
+				}
+				if let Some(f) = &config.ssh_known_hosts {
+					ssh.push(format!(
+						"-o UserKnownHostsFile={}",
+						quote(&f.to_string_lossy())
+					));
+					ssh.push("-o StrictHostKeyChecking=yes".into());
+				}
+				args.push("-e".into());
+				args.push(ssh.join(" "));
+				match url.username() {
+					"" => format!("{}:{}", host, url.path()),
+					user => format!("{}@{}:{}", user, host, url.path()),
+				}
+			}
+			scheme => bail!("Unsupported mirror URL scheme: {}", scheme),
+		};
+		args.extend(config.rsync_args.iter().cloned());
+		Ok(Self {
+			source,
+			args,
+			password: config.rsync_password.clone(),
+			checksum_paths: config.rsync_checksum_paths.clone(),
+		})
+	}
+
+	/// Whether the file (relative to the mirror root) should be compared by
+	/// its checksum instead of its size and modification time.
+	pub fn needs_checksum(&self, path: &str) -> bool {
+		self.checksum_paths
+			.iter()
+			.any(|p| path.starts_with(p.as_str()))
+	}
+
+	/// The command transferring the files listed in `file_list` to
+	/// `dst_root`.
+	pub fn command(
+		&self,
+		dst_root: &Path,
+		file_list: &Path,
+		bwlimit: Option<u64>,
+		checksum: bool,
+	) -> Command {
+		let mut cmd = Command::new("rsync");
+		cmd.args(["-R", "-r", "-v", "--no-motd", "--stats"]);
+		cmd.arg(format!("--out-format={}%n", FILE_PREFIX));
+		if let Some(limit) = bwlimit {
+			cmd.arg(format!("--bwlimit={}", limit));
+		}
+		if checksum {
+			cmd.arg("--checksum");
+		}
+		cmd.args(&self.args);
+		if let Some(password) = &self.password {
+			cmd.env("RSYNC_PASSWORD", password);
+		}
+		cmd.arg(format!("--files-from={}", file_list.display()));
+		cmd.arg(&self.source);
+		cmd.arg(dst_root);
+		cmd
+	}
+}
+
+/// Errors kept per sync job, the rest are only logged.
+const MAX_ERRORS: usize = 100;
+
+/// An error (or warning) printed by rsync.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RsyncError {
+	/// The file the message is about, if any
+	pub path: Option<String>,
+	pub message: String,
+}
+
+/// Figures of a transfer, from the `--stats` summaries of the rsync
+/// instances.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct TransferStats {
+	pub files_transferred: u64,
+	/// Total size of the transferred files
+	pub transferred_size: u64,
+	/// Bytes received from the upstream, after delta transfer and
+	/// compression
+	pub bytes_received: u64,
+	pub bytes_sent: u64,
+	/// Total size of the files compared with the upstream
+	pub total_size: u64,
+	/// total_size divided by the bytes sent and received
+	pub speedup: f64,
+	pub errors: Vec<RsyncError>,
+}
+
+impl TransferStats {
+	/// Record an error, unless there are too many of them already.
+	pub fn push_error(&mut self, error: RsyncError) {
+		if self.errors.len() < MAX_ERRORS {
+			self.errors.push(error);
+		}
+	}
+
+	/// Add the figures of another rsync instance.
+	pub fn add(&mut self, other: TransferStats) {
+		self.files_transferred += other.files_transferred;
+		self.transferred_size += other.transferred_size;
+		self.bytes_received += other.bytes_received;
+		self.bytes_sent += other.bytes_sent;
+		self.total_size += other.total_size;
+		let traffic = self.bytes_received + self.bytes_sent;
+		if traffic > 0 {
+			self.speedup = self.total_size as f64 / traffic as f64;
+		}
+		let room = MAX_ERRORS.saturating_sub(self.errors.len());
+		self.errors.extend(other.errors.into_iter().take(room));
+	}
+
+	/// Parse a line of the `--stats` summary, returns false if it is not
+	/// one of the figures we are interested in.
+	fn parse_summary(&mut self, line: &str) -> bool {
+		let Some((key, value)) = line.split_once(": ") else {
+			return false;
+		};
+		let field = match key {
+			"Number of regular files transferred" => &mut self.files_transferred,
+			"Total file size" => &mut self.total_size,
+			"Total transferred file size" => &mut self.transferred_size,
+			"Total bytes sent" => &mut self.bytes_sent,
+			"Total bytes received" => &mut self.bytes_received,
+			_ => return false,
+		};
+		// Thousands separators depend on the locale.
+		let number = value
+			.split_whitespace()
+			.next()
+			.unwrap_or_default()
+			.replace([',', '.'], "");
+		if let Ok(n) = number.parse() {
+			*field = n;
+		}
+		true
+	}
+}
+
+/// Prefix of the lines of the rsync output reporting a transferred file, see
+/// `--out-format`. The rest of the lines are messages or the summary.
+const FILE_PREFIX: &str = "file: ";
+
+/// Parser of the output of an rsync instance.
+#[derive(Default)]
+pub struct RsyncOutput {
+	pub stats: TransferStats,
+}
+
+impl RsyncOutput {
+	/// Parse a line of stdout. Returns the path of the file if the line
+	/// reports a transferred one.
+	pub fn parse_stdout(&mut self, line: &str) -> Option<String> {
+		let line = line.trim_end();
+		let Some(path) = line.strip_prefix(FILE_PREFIX) else {
+			self.stats.parse_summary(line);
+			return None;
+		};
+		if path.is_empty() || path.ends_with('/') {
+			return None;
+		}
+		Some(path.to_string())
+	}
+
+	/// Parse a line of stderr, all of them are errors or warnings.
+	pub fn parse_stderr(&mut self, line: &str) -> Option<RsyncError> {
+		let line = line.trim_end();
+		if line.is_empty() {
+			return None;
+		}
+		// e.g. rsync: [sender] link_stat "/pool/a.deb" (in debs) failed: ...
+		let path = line
+			.split_once('"')
+			.and_then(|(_, rest)| rest.split_once('"'))
+			.map(|(path, _)| path.trim_start_matches('/').to_string());
+		let error = RsyncError {
+			path,
+			message: line.to_string(),
+		};
+		self.stats.push_error(error.clone());
+		Some(error)
+	}
+}
+
+#[test]
+fn test_parse_output() {
+	let stdout = r#"receiving incremental file list
+file: pool/
+file: pool/main/f/foo/
+file: pool/main/f/foo/foo_1_amd64.deb
+skipping non-regular file "pool/main/f/foo/foo_latest.deb"
+cannot delete non-empty directory: pool/main/b/bar
+rsync: some message about pool/main/f/foo/foo_2_amd64.deb
+file: pool/main/f/foo/foo_2_amd64.deb
+
+Number of files: 5 (reg: 2, dir: 3)
+Number of created files: 2 (reg: 2)
+Number of deleted files: 0
+Number of regular files transferred: 2
+Total file size: 2,097,152 bytes
+Total transferred file size: 1,048,576 bytes
+Literal data: 1,048,576 bytes
+Matched data: 0 bytes
+File list size: 0
+File list generation time: 0.001 seconds
+File list transfer time: 0.000 seconds
+Total bytes sent: 62
+Total bytes received: 1,048,890
+
+sent 62 bytes  received 1,048,890 bytes  2,097,904.00 bytes/sec
+total size is 2,097,152  speedup is 2.00
+"#;
+	let mut output = RsyncOutput::default();
+	let files: Vec<_> = stdout
+		.lines()
+		.filter_map(|l| output.parse_stdout(l))
+		.collect();
+	assert_eq!(
+		files,
+		[
+			"pool/main/f/foo/foo_1_amd64.deb",
+			"pool/main/f/foo/foo_2_amd64.deb"
+		]
+	);
+	let e = output
+		.parse_stderr(
+			r#"rsync: [sender] link_stat "/pool/main/f/foo/foo_3_amd64.deb" (in debs) failed: No such file or directory (2)"#,
+		)
+		.unwrap();
+	assert_eq!(e.path.as_deref(), Some("pool/main/f/foo/foo_3_amd64.deb"));
+	let mut stats = TransferStats::default();
+	stats.add(output.stats);
+	assert_eq!(stats.files_transferred, 2);
+	assert_eq!(stats.transferred_size, 1_048_576);
+	assert_eq!(stats.total_size, 2_097_152);
+	assert_eq!(stats.bytes_sent, 62);
+	assert_eq!(stats.bytes_received, 1_048_890);
+	assert!((stats.speedup - 2.0).abs() < 0.01);
+	assert_eq!(stats.errors.len(), 1);
+}
+
+#[test]
+fn test_ssh_command() {
+	let config = crate::config::test_config(
+		r#"
+mirror_url = "ssh://mirror@repo.example.org:2222/srv/debs/"
+ssh_identity_file = "/etc/aosc-mirror/id_ed25519"
+ssh_known_hosts = "/etc/aosc-mirror/known_hosts"
+rsync_args = ["--timeout=600"]
+"#,
+	);
+	let rsync = RsyncCommand::new(&config).unwrap();
+	assert_eq!(rsync.source, "mirror@repo.example.org:/srv/debs/");
+	assert_eq!(
+		rsync.args,
+		[
+			"-e",
+			"ssh -o BatchMode=yes -p 2222 -i '/etc/aosc-mirror/id_ed25519' -o IdentitiesOnly=yes \
+			 -o UserKnownHostsFile='/etc/aosc-mirror/known_hosts' -o StrictHostKeyChecking=yes",
+			"--timeout=600",
+		]
+	);
+}
@@ -2,19 +2,37 @@ use std::sync::Arc;
 
 use axum::{
 	Router,
-	extract::State,
+	extract::{Query, State},
+	http::{HeaderMap, Response},
+	response::{
+		IntoResponse,
+		sse::{Event, KeepAlive, Sse},
+	},
 	routing::{get, post},
 };
+use chrono::Local;
+use futures_util::{StreamExt, stream};
+use log::info;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, broadcast::error::RecvError};
 
-use crate::{AppState, sync::do_sync};
+use crate::{
+	AppState,
+	bandwidth::{active_window, bandwidth_limit},
+	invoke::InvocationReport,
+	reload::reload,
+	rsync::TransferStats,
+	sync::{cancel, do_sync},
+	verify::verify_action_signature,
+};
 
 #[derive(Copy, Clone, Deserialize, PartialEq, Serialize, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum Status {
 	Success,
 	Failed,
+	Queued,
+	Cancelled,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -33,30 +51,141 @@ pub struct SyncRequestResponse {
 pub struct SyncStatusResponse {
 	pub syncing: bool,
 	pub last_sync_timestamp: i64,
+	/// Timestamp of the sync request the last sync job served, so that the
+	/// origin server can tell whether its request is done
+	#[serde(default)]
+	pub last_request_timestamp: Option<i64>,
 	pub last_sync_status: Status,
 	pub last_sync_message: String,
+	/// Timestamp of the sync request queued during the running sync
+	pub pending_timestamp: Option<i64>,
+	/// mirror_url of the upstream the last successful sync used
+	#[serde(default)]
+	pub last_upstream: Option<String>,
+	/// Results of the sync requests sent to the downstream mirrors after the
+	/// last successful sync
+	#[serde(default)]
+	pub last_push: Option<InvocationReport>,
+	/// Figures of the rsync transfer of the last sync
+	#[serde(default)]
+	pub last_transfer: Option<TransferStats>,
+	/// Bandwidth cap in KiB/s in effect now
+	#[serde(default)]
+	pub bandwidth_limit_kib: Option<u64>,
+	/// Bandwidth window in effect now, e.g. "08:00-20:00"
+	#[serde(default)]
+	pub bandwidth_window: Option<String>,
+	/// Whether the syncs started by polling are postponed now
+	#[serde(default)]
+	pub polling_deferred: bool,
 }
 
 pub async fn status(State(s): State<Arc<RwLock<AppState>>>) -> String {
 	let lock = s.read().await;
+	let now = Local::now().time();
+	let window = active_window(&lock.config, now);
 	serde_json::to_string_pretty(&SyncStatusResponse {
 		syncing: lock.syncing,
 		last_sync_timestamp: lock.last_sync_timestamp,
+		last_request_timestamp: lock.last_request_timestamp,
 		last_sync_status: lock.last_sync_status,
 		last_sync_message: lock.last_sync_message.clone(),
+		pending_timestamp: lock.pending_timestamp,
+		last_upstream: lock.last_upstream.clone(),
+		last_push: lock.last_push.clone(),
+		last_transfer: lock.last_transfer.clone(),
+		bandwidth_limit_kib: bandwidth_limit(&lock.config, now),
+		bandwidth_window: window.map(|w| w.to_string()),
+		polling_deferred: window.is_some_and(|w| w.defer_polling),
 	})
 	.unwrap()
 }
 
-async fn exit() {
-	std::process::exit(0);
+pub async fn metrics(State(s): State<Arc<RwLock<AppState>>>) -> String {
+	let lock = s.read().await;
+	let metrics = lock.metrics.clone();
+	let root = lock.config.mirror_root.clone();
+	drop(lock);
+	metrics.render(&root)
+}
+
+/// Build a JSON response for a rejected request.
+pub fn reject(code: u16, message: impl Into<String>) -> Response<String> {
+	let res = SyncRequestResponse {
+		status: Status::Failed,
+		message: message.into(),
+	};
+	Response::builder()
+		.status(code)
+		.body(serde_json::to_string_pretty(&res).unwrap())
+		.unwrap()
+}
+
+/// Stream the sync events as server-sent events. The request must be signed
+/// with the "events" action, passed as query parameters.
+pub async fn events(
+	State(s): State<Arc<RwLock<AppState>>>,
+	headers: HeaderMap,
+	Query(auth): Query<SyncRequestBody>,
+) -> impl IntoResponse {
+	let lock = s.read().await;
+	if !lock.config.skip_verification
+		&& let Err(e) = verify_action_signature(
+			"events",
+			auth.timestamp,
+			&auth.signature,
+			&lock.server_pubkeys,
+		) {
+		info!("Rejecting event stream request: {}", e);
+		return reject(403, format!("Invalid signature: {}", e)).into_response();
+	}
+	let events = lock.events.clone();
+	drop(lock);
+	// Resume from where the client left off after a reconnection.
+	let last_id = headers
+		.get("Last-Event-ID")
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.parse::<u64>().ok())
+		.unwrap_or_default();
+	let (backlog, rx) = events.subscribe(last_id);
+	let live = stream::unfold(rx, |mut rx| async move {
+		loop {
+			match rx.recv().await {
+				Ok(e) => return Some((e, rx)),
+				// Slow consumers miss some events, keep going.
+				Err(RecvError::Lagged(_)) => continue,
+				Err(RecvError::Closed) => return None,
+			}
+		}
+	});
+	let stream = stream::iter(backlog).chain(live).map(|e| {
+		Event::default()
+			.id(e.id.to_string())
+			.event("sync")
+			.json_data(&e)
+	});
+	Sse::new(stream)
+		.keep_alive(KeepAlive::default())
+		.into_response()
 }
 
 pub fn build_server(s: Arc<RwLock<AppState>>) -> Router {
 	// let service = do_sync.with_state(s.clone()).into_make_service_with_connect_info::<SocketAddr>();
 	Router::new()
 		.route("/do-sync", post(do_sync))
+		.route("/cancel", post(cancel))
+		.route("/reload", post(reload))
 		.route("/status", get(status))
-		.route("/exit", post(exit))
+		.route("/metrics", get(metrics))
+		.route("/events", get(events))
 		.with_state(s)
 }
+
+/// Serve each repository under /repos/NAME.
+pub fn build_repos_server(repos: Vec<(String, Arc<RwLock<AppState>>)>) -> Router {
+	let mut router = Router::new();
+	for (name, s) in repos {
+		router = router.nest(&format!("/repos/{}", name), build_server(s));
+	}
+	router
+}
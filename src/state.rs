@@ -0,0 +1,318 @@
+use std::{
+	collections::{BTreeSet, HashMap},
+	fs::{File, Metadata, create_dir_all, read_to_string, rename},
+	io::Write,
+	os::unix::fs::MetadataExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	AppState,
+	invoke::InvocationReport,
+	metadata::{AptMetadataHashAlgm, SuiteValidators},
+	rsync::TransferStats,
+	server::Status,
+};
+
+/// Directory under the mirror root to keep the states of this program.
+/// Nothing in this directory is touched while removing unused files.
+pub const STATE_DIR: &str = ".aosc-mirror";
+
+pub fn state_dir(mirror_root: &dyn AsRef<Path>) -> PathBuf {
+	mirror_root.as_ref().join(STATE_DIR)
+}
+
+/// Returns the timestamp of the published dists-TIMESTAMP snapshot.
+pub fn published_snapshot(mirror_root: &dyn AsRef<Path>) -> Option<i64> {
+	let target = std::fs::read_link(mirror_root.as_ref().join("dists")).ok()?;
+	target.file_name()?
+		.to_str()?
+		.strip_prefix("dists-")?
+		.parse()
+		.ok()
+}
+
+/// Part of the [`AppState`] that survives a restart.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PersistentState {
+	pub last_sync_timestamp: i64,
+	#[serde(default)]
+	pub last_request_timestamp: Option<i64>,
+	pub last_sync_status: Status,
+	pub last_sync_message: String,
+	#[serde(default)]
+	pub last_transfer: Option<TransferStats>,
+	#[serde(default)]
+	pub last_upstream: Option<String>,
+	#[serde(default)]
+	pub last_push: Option<InvocationReport>,
+}
+
+impl PersistentState {
+	pub fn from_app_state(s: &AppState) -> Self {
+		Self {
+			last_sync_timestamp: s.last_sync_timestamp,
+			last_request_timestamp: s.last_request_timestamp,
+			last_sync_status: s.last_sync_status,
+			last_sync_message: s.last_sync_message.clone(),
+			last_transfer: s.last_transfer.clone(),
+			last_upstream: s.last_upstream.clone(),
+			last_push: s.last_push.clone(),
+		}
+	}
+
+	/// Returns None if the state is never saved.
+	pub fn load(mirror_root: &dyn AsRef<Path>) -> Result<Option<Self>> {
+		let path = state_dir(mirror_root).join("state.json");
+		if !path.is_file() {
+			return Ok(None);
+		}
+		let content = read_to_string(&path)
+			.context(format!("Failed to read {}", path.display()))?;
+		let state = serde_json::from_str(&content)
+			.context(format!("Failed to parse {}", path.display()))?;
+		Ok(Some(state))
+	}
+
+	pub fn save(&self, mirror_root: &dyn AsRef<Path>) -> Result<()> {
+		write_state_file(
+			mirror_root,
+			"state.json",
+			&serde_json::to_string_pretty(self)?,
+		)
+	}
+}
+
+/// HTTP validators of the manifests in the published snapshot, sent with the
+/// conditional requests of the next sync.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ManifestCache {
+	/// Timestamp of the snapshot the manifests are saved to
+	pub timestamp: i64,
+	pub suites: HashMap<String, SuiteValidators>,
+}
+
+impl ManifestCache {
+	/// Returns None if there is no cache for the given snapshot.
+	pub fn load(mirror_root: &dyn AsRef<Path>, timestamp: i64) -> Result<Option<Self>> {
+		let path = state_dir(mirror_root).join("manifests.json");
+		if !path.is_file() {
+			return Ok(None);
+		}
+		let content = read_to_string(&path)
+			.context(format!("Failed to read {}", path.display()))?;
+		let cache: Self = serde_json::from_str(&content)
+			.context(format!("Failed to parse {}", path.display()))?;
+		Ok(Some(cache).filter(|c| c.timestamp == timestamp))
+	}
+
+	pub fn save(&self, mirror_root: &dyn AsRef<Path>) -> Result<()> {
+		write_state_file(
+			mirror_root,
+			"manifests.json",
+			&serde_json::to_string_pretty(self)?,
+		)
+	}
+}
+
+/// Files found broken by `sync-client verify --queue`. The next sync
+/// downloads them again, instead of reusing them.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RedownloadQueue {
+	/// Paths relative to the mirror root
+	pub paths: BTreeSet<String>,
+}
+
+impl RedownloadQueue {
+	/// Returns an empty queue if nothing is queued.
+	pub fn load(mirror_root: &dyn AsRef<Path>) -> Result<Self> {
+		let path = state_dir(mirror_root).join("redownload.json");
+		if !path.is_file() {
+			return Ok(Self::default());
+		}
+		let content = read_to_string(&path)
+			.context(format!("Failed to read {}", path.display()))?;
+		serde_json::from_str(&content)
+			.context(format!("Failed to parse {}", path.display()))
+	}
+
+	pub fn save(&self, mirror_root: &dyn AsRef<Path>) -> Result<()> {
+		write_state_file(
+			mirror_root,
+			"redownload.json",
+			&serde_json::to_string_pretty(self)?,
+		)
+	}
+
+	/// Forget the queued files, once they are downloaded again.
+	pub fn clear(mirror_root: &dyn AsRef<Path>) -> Result<()> {
+		let path = state_dir(mirror_root).join("redownload.json");
+		if path.is_file() {
+			std::fs::remove_file(&path)
+				.context(format!("Failed to remove {}", path.display()))?;
+		}
+		Ok(())
+	}
+}
+
+/// Inode number and modification time (in nanoseconds) of a file.
+type FileKey = (u64, i64);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ChecksumEntry {
+	inode: u64,
+	mtime: i64,
+	algorithm: AptMetadataHashAlgm,
+	hash: String,
+}
+
+/// Checksums of the metadata files verified before, keyed by the inode
+/// number and the modification time, so that unchanged files are not hashed
+/// again on every sync. Files hard linked into a new snapshot keep their
+/// inode, thus their entries.
+#[derive(Debug, Default)]
+pub struct ChecksumCache {
+	previous: HashMap<String, Vec<ChecksumEntry>>,
+	current: HashMap<String, Vec<ChecksumEntry>>,
+}
+
+/// Checksums of the metadata files of a suite.
+#[derive(Debug, Default)]
+pub struct SuiteChecksums {
+	entries: HashMap<FileKey, (AptMetadataHashAlgm, String)>,
+	/// Entries looked up or added in this sync, only these are kept.
+	used: HashMap<FileKey, (AptMetadataHashAlgm, String)>,
+}
+
+impl ChecksumCache {
+	/// Returns an empty cache if it is never saved.
+	pub fn load(mirror_root: &dyn AsRef<Path>) -> Result<Self> {
+		let path = state_dir(mirror_root).join("checksums.json");
+		if !path.is_file() {
+			return Ok(Self::default());
+		}
+		let content = read_to_string(&path)
+			.context(format!("Failed to read {}", path.display()))?;
+		let previous = serde_json::from_str(&content)
+			.context(format!("Failed to parse {}", path.display()))?;
+		Ok(Self {
+			previous,
+			current: HashMap::new(),
+		})
+	}
+
+	/// Save the checksums of the suites synced in this run. Suites no
+	/// longer synced are dropped.
+	pub fn save(&self, mirror_root: &dyn AsRef<Path>) -> Result<()> {
+		write_state_file(
+			mirror_root,
+			"checksums.json",
+			&serde_json::to_string(&self.current)?,
+		)
+	}
+
+	/// Take the checksums of the given suite, to verify its metadata files.
+	pub fn suite(&mut self, suite: &str) -> SuiteChecksums {
+		let entries = self
+			.previous
+			.remove(suite)
+			.unwrap_or_default()
+			.into_iter()
+			.map(|e| ((e.inode, e.mtime), (e.algorithm, e.hash)))
+			.collect();
+		SuiteChecksums {
+			entries,
+			used: HashMap::new(),
+		}
+	}
+
+	/// Put back the checksums of a suite after verifying its metadata files.
+	pub fn finish_suite(&mut self, suite: &str, checksums: SuiteChecksums) {
+		let entries = checksums
+			.used
+			.into_iter()
+			.map(|((inode, mtime), (algorithm, hash))| ChecksumEntry {
+				inode,
+				mtime,
+				algorithm,
+				hash,
+			})
+			.collect();
+		self.current.insert(suite.to_string(), entries);
+	}
+
+	/// Keep the checksums of a suite whose metadata files are reused as is.
+	pub fn keep_suite(&mut self, suite: &str) {
+		if let Some(entries) = self.previous.remove(suite) {
+			self.current.insert(suite.to_string(), entries);
+		}
+	}
+}
+
+impl SuiteChecksums {
+	fn key(metadata: &Metadata) -> FileKey {
+		(
+			metadata.ino(),
+			metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+		)
+	}
+
+	/// Returns true if the file is verified before with the same checksum.
+	pub fn is_verified(
+		&mut self,
+		metadata: &Metadata,
+		algm: AptMetadataHashAlgm,
+		hash: &str,
+	) -> bool {
+		if !self.is_known(metadata, algm, hash) {
+			return false;
+		}
+		self.used
+			.insert(Self::key(metadata), (algm, hash.to_string()));
+		true
+	}
+
+	/// Like [`Self::is_verified`], but the entry is not kept for the next
+	/// sync, e.g. to tell which files are going to be downloaded.
+	pub fn is_known(&self, metadata: &Metadata, algm: AptMetadataHashAlgm, hash: &str) -> bool {
+		matches!(
+			self.entries.get(&Self::key(metadata)),
+			Some((a, h)) if *a == algm && h.eq_ignore_ascii_case(hash)
+		)
+	}
+
+	pub fn insert(&mut self, metadata: &Metadata, algm: AptMetadataHashAlgm, hash: &str) {
+		let key = Self::key(metadata);
+		self.entries.insert(key, (algm, hash.to_string()));
+		self.used.insert(key, (algm, hash.to_string()));
+	}
+}
+
+/// Write to a temporary file first, so that the state is never left half
+/// written.
+fn write_state_file(mirror_root: &dyn AsRef<Path>, name: &str, content: &str) -> Result<()> {
+	let dir = state_dir(mirror_root);
+	create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+	let path = dir.join(name);
+	let tmp_path = dir.join(format!("{}.tmp", name));
+	let mut fd = File::options()
+		.create(true)
+		.truncate(true)
+		.write(true)
+		.open(&tmp_path)?;
+	fd.write_all(content.as_bytes())?;
+	fd.sync_all()?;
+	rename(&tmp_path, &path).context(format!("Failed to save {}", path.display()))?;
+	Ok(())
+}
+
+/// Save the persistent part of the state, errors are logged.
+pub fn save_state(s: &AppState) {
+	if let Err(e) = PersistentState::from_app_state(s).save(&s.config.mirror_root) {
+		warn!("Failed to save the sync state: {:#}", e);
+	}
+}
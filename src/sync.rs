@@ -1,45 +1,59 @@
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use axum::{
 	Json,
 	extract::{ConnectInfo, State},
 	http::Response,
 };
 use chrono::prelude::*;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use reqwest::Client;
 use std::{
-	collections::{HashMap, HashSet},
+	collections::{BTreeSet, HashMap, HashSet},
 	fs::{remove_dir_all, remove_file},
-	net::SocketAddr,
 	path::{Path, PathBuf},
-	sync::Arc,
+	process::Stdio,
+	sync::{Arc, Mutex, atomic::Ordering},
+	time::Instant,
 };
 use tokio::{
 	fs::{File, create_dir_all, symlink},
-	io::{AsyncWriteExt, BufWriter},
-	process::Command,
-	sync::RwLock,
-	task::JoinSet,
+	io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+	sync::{RwLock, Semaphore, mpsc::error::SendError},
+	task::{JoinHandle, JoinSet},
 };
 use url::Url;
 
 use crate::{
 	AppState,
 	aosc::fetch_topics,
-	config::OperationMode,
+	bandwidth::{SharedBandwidth, bandwidth_limit},
+	cancel::{CancelToken, SyncTask},
+	config::{AppConfig, OperationMode},
 	debian::collect_source_files,
+	events::{EventLog, SyncEventKind},
+	invoke::push_downstream,
+	listener::PeerAddr,
+	lock::MirrorLock,
 	metadata::{
-		AptRepoReleaseInfo, download_metadata_files, fetch_manifest, get_files,
-		split_inrelease,
+		AptRepoReleaseInfo, FileEntry, ManifestFetch, SuiteValidators,
+		download_metadata_files, fetch_manifest, fetch_manifest_conditional, get_files,
+		metadata_download_size, split_inrelease,
+	},
+	metrics::{Metrics, RejectReason, SyncPhase},
+	rsync::{RsyncCommand, RsyncError, RsyncOutput, TransferStats},
+	server::{Status, SyncRequestBody, SyncRequestResponse, reject},
+	state::{ChecksumCache, ManifestCache, RedownloadQueue, published_snapshot, save_state},
+	systemd,
+	upstream::healthy_upstreams,
+	utils::{disk_usage, format_size, hard_link_tree, remove_existing, scan_delta},
+	verify::{
+		PgpKeyringStore, verify_action_signature, verify_pgp_signature,
+		verify_request_signature,
 	},
-	server::{Status, SyncRequestBody, SyncRequestResponse},
-	utils::scan_delta,
-	verify::{PgpKeyringStore, verify_pgp_signature, verify_request_signature},
 };
 
 #[derive(Debug, Clone)]
 pub struct SyncJob<'a> {
-	pub rsync_url: &'a Url,
 	pub http_url: &'a Url,
 	pub mode: OperationMode,
 	pub mirror_sources: bool,
@@ -50,21 +64,81 @@ pub struct SyncJob<'a> {
 	pub timestamp: i64,
 	pub keyring_store: &'a PgpKeyringStore,
 	pub client: &'a Client,
+	pub metrics: &'a Metrics,
+	pub events: &'a Arc<EventLog>,
+	pub transfers: &'a Arc<Semaphore>,
+	pub bandwidth: &'a Arc<SharedBandwidth>,
+	/// Free space to leave on the file system, in bytes
+	pub disk_reserve: u64,
+	/// Maximum size of the mirror, in bytes
+	pub quota: Option<u64>,
+	/// Config of the repository, for the rsync command and the bandwidth
+	/// caps, which are only needed once the files are transferred
+	pub config: &'a AppConfig,
+	/// Directory to create the new snapshot in instead of the mirror root,
+	/// for dry runs
+	pub scratch: Option<&'a Path>,
+	/// Files queued by `sync-client verify --queue`, relative to the mirror
+	/// root. They are fetched again instead of being reused.
+	pub redownload: BTreeSet<String>,
+	pub cancel: CancelToken,
+}
+
+impl SyncJob<'_> {
+	/// Bandwidth cap in KiB/s at this moment. It is looked up again for each
+	/// suite and for each rsync instance, as a long sync might run into
+	/// another bandwidth window.
+	fn bandwidth_limit(&self) -> Option<u64> {
+		bandwidth_limit(self.config, Local::now().time())
+	}
+
+	/// Directory to create the dists-TIMESTAMP snapshot in.
+	fn snapshot_root(&self) -> &Path {
+		self.scratch.unwrap_or(self.dst)
+	}
+
+	/// Refuse to go on if the file system of the mirror root does not have
+	/// `needed` bytes available, besides the reserve.
+	fn check_disk_space(&self, needed: u64, what: &str) -> Result<()> {
+		let (_, free) = disk_usage(&self.dst)?;
+		let available = free.saturating_sub(self.disk_reserve);
+		if needed > available {
+			bail!(
+				"Not enough disk space for {}: {} needed, {} available ({} free, {} reserved)",
+				what,
+				format_size(needed),
+				format_size(available),
+				format_size(free),
+				format_size(self.disk_reserve)
+			);
+		}
+		Ok(())
+	}
+
+	fn enter_phase(&self, phase: SyncPhase) {
+		self.events.push(SyncEventKind::Phase { phase });
+		systemd::notify_status(&format!(
+			"Syncing (timestamp {}): {}",
+			self.timestamp,
+			phase.as_str()
+		));
+	}
 }
 
 #[axum::debug_handler]
 pub async fn do_sync(
-	ConnectInfo(addr): ConnectInfo<SocketAddr>,
+	ConnectInfo(addr): ConnectInfo<PeerAddr>,
 	State(s): State<Arc<RwLock<AppState>>>,
 	Json(payload): Json<SyncRequestBody>,
 ) -> Response<String> {
 	info!("Got request from {}", addr);
 	let s2 = s.clone();
-	let lock = s2.read().await;
+	let mut lock = s2.write().await;
 	if !lock.config.skip_verification {
 		// Verify signatures
 		if payload.signature.is_empty() {
 			info!("Got empty signature, rejecting.");
+			lock.metrics.reject(RejectReason::InvalidSignature);
 			let res = SyncRequestResponse {
 				status: Status::Failed,
 				message: "Invalid signature".into(),
@@ -83,6 +157,7 @@ pub async fn do_sync(
 		.is_err()
 		{
 			info!("Got invalid signature, rejecting.");
+			lock.metrics.reject(RejectReason::InvalidSignature);
 			let res = SyncRequestResponse {
 				status: Status::Failed,
 				message: "Invalid signature".into(),
@@ -96,23 +171,39 @@ pub async fn do_sync(
 	} else {
 		warn!("Testing mode is enabled! Skipping signature verification.");
 	}
+	if lock.shutting_down {
+		info!("The server is shutting down, rejecting.");
+		return reject(503, "The server is shutting down");
+	}
 	if lock.syncing {
-		info!("Sync is already started, rejecting.");
+		// Several requests during one sync collapse into one follow-up sync.
+		let pending = lock
+			.pending_timestamp
+			.map_or(payload.timestamp, |t| t.max(payload.timestamp));
+		info!(
+			"Sync is already started, queueing the request (timestamp {}).",
+			pending
+		);
+		lock.pending_timestamp = Some(pending);
+		lock.metrics.requests_queued.fetch_add(1, Ordering::Relaxed);
 		let res = SyncRequestResponse {
-			status: Status::Failed,
-			message: "Sync job is already started".into(),
+			status: Status::Queued,
+			message: "Sync job is already started, the request is queued".into(),
 		};
 		return Response::builder()
-			.status(400)
+			.status(202)
 			.body(serde_json::to_string_pretty(&res).unwrap())
 			.unwrap();
 	}
-	let h = tokio::spawn(async move { do_sync_inner(s, payload.timestamp).await });
-	if let Err(e) = lock.sender.send(h).await {
+	if let Err(e) = start_sync(s, &mut lock, payload.timestamp).await {
 		error!("Can not send the handle to the consumer: {}", e);
+		lock.metrics.reject(RejectReason::InternalError);
 		let res = SyncRequestResponse {
 			status: Status::Failed,
-			message: format!("Internal error: Unable to consume the spawned task: {}", e)
+			message: format!(
+				"Internal error: Unable to consume the spawned task: {}",
+				e
+			),
 		};
 		return Response::builder()
 			.status(400)
@@ -128,51 +219,236 @@ pub async fn do_sync(
 	Response::new(serde_json::to_string_pretty(&res).unwrap())
 }
 
-async fn fireup_rsync(rsync_url: Url, dst_root: PathBuf, file_list: PathBuf) -> Result<()> {
-	let mut cmd = Command::new("rsync");
-	cmd.args(["-R", "-r", "-v", "--no-motd"]);
-	cmd.arg(format!("--files-from={}", file_list.display()));
-	cmd.arg(rsync_url.to_string());
-	cmd.arg(dst_root);
-	let mut handle = cmd.spawn()?;
-	handle.wait().await?;
-	Ok(())
+/// Spawn a sync job. The caller holds the write lock of the state and has
+/// checked that no sync job is running.
+pub async fn start_sync(
+	s: Arc<RwLock<AppState>>,
+	lock: &mut AppState,
+	timestamp: i64,
+) -> Result<(), SendError<JoinHandle<()>>> {
+	// Mark it now, so that requests coming before the task starts are queued.
+	lock.syncing = true;
+	let (task, guard) = SyncTask::new();
+	let cancel = task.cancel.clone();
+	let h = tokio::spawn(async move {
+		let _guard = guard;
+		do_sync_inner(s, timestamp, &cancel).await
+	});
+	lock.sync_task = Some(task);
+	lock.sender.send(h).await
 }
 
-pub async fn do_sync_inner(s: Arc<RwLock<AppState>>, timestamp: i64) {
-	let local: DateTime<Local> = Local::now();
-	info!("Starting sync at {}", local);
+pub async fn cancel(
+	ConnectInfo(addr): ConnectInfo<PeerAddr>,
+	State(s): State<Arc<RwLock<AppState>>>,
+	Json(payload): Json<SyncRequestBody>,
+) -> Response<String> {
+	info!("Got cancel request from {}", addr);
+	let lock = s.read().await;
+	if !lock.config.skip_verification
+		&& let Err(e) = verify_action_signature(
+			"cancel",
+			payload.timestamp,
+			&payload.signature,
+			&lock.server_pubkeys,
+		) {
+		info!("Got invalid signature, rejecting: {}", e);
+		lock.metrics.reject(RejectReason::InvalidSignature);
+		return reject(400, "Invalid signature");
+	}
+	drop(lock);
+	if !cancel_sync(s, "Cancelled by request").await {
+		return reject(400, "No sync job is running");
+	}
+	let res = SyncRequestResponse {
+		status: Status::Cancelled,
+		message: "Sync job cancelled".into(),
+	};
+	Response::new(serde_json::to_string_pretty(&res).unwrap())
+}
+
+/// Cancel the running sync job, and wait until it removes the partially
+/// downloaded metadata and file lists. The run is recorded as cancelled.
+/// Returns false if no sync job is running.
+pub async fn cancel_sync(s: Arc<RwLock<AppState>>, reason: &str) -> bool {
 	let mut lock = s.write().await;
-	lock.syncing = true;
-	let k = lock.keyring_store.clone();
-	let c = lock.config.clone();
-	let client = lock.client.clone();
+	let task = match lock.sync_task.take() {
+		Some(t) => t,
+		None => return false,
+	};
+	// Queued requests are dropped as well.
+	lock.pending_timestamp = None;
 	drop(lock);
-	let suites = match c.mode {
-		OperationMode::AOSC => {
-			if !c.mirror_topics {
-				vec!["stable".into()]
-			} else {
-				let mut topics = fetch_topics(
-					&c.http_url,
-					c.mirror_root.clone(),
-					client.clone(),
-				)
-				.await
-				.context("Unable to fetch the topic manifest")
-				.unwrap()
-				.into_iter()
-				.map(|x| x.name)
-				.collect::<Vec<_>>();
-				info!("Manifest has {} topics.", topics.len());
-				topics.push("stable".into());
-				topics
+	warn!("Cancelling the running sync job: {}", reason);
+	task.cancel(reason).await;
+	// The job might have finished before noticing.
+	if s.read().await.last_sync_status != Status::Cancelled {
+		return false;
+	}
+	info!("Sync job cancelled.");
+	true
+}
+
+/// Remove the unpublished dists-TIMESTAMP directory and the file lists of
+/// the given sync job.
+fn remove_partial_files(root: PathBuf, timestamp: i64) -> Result<()> {
+	let name = format!("dists-{}", timestamp);
+	let dists = root.join(&name);
+	// The snapshot might be published right before the cancellation.
+	let published = std::fs::read_link(root.join("dists"))
+		.is_ok_and(|p| p.file_name().is_some_and(|n| n == name.as_str()));
+	if dists.is_dir() && !published {
+		info!("Removing {} ...", dists.display());
+		remove_dir_all(&dists)
+			.context(format!("Unable to remove directory {}", dists.display()))?;
+	}
+	let tmp_dir = root.join(".tmp");
+	if !tmp_dir.is_dir() {
+		return Ok(());
+	}
+	let prefix = format!("files-{}-", timestamp);
+	for entry in std::fs::read_dir(&tmp_dir)? {
+		let entry = entry?;
+		if entry.file_name().to_string_lossy().starts_with(&prefix) {
+			remove_file(entry.path()).context(format!(
+				"Unable to remove file list {}",
+				entry.path().display()
+			))?;
+		}
+	}
+	Ok(())
+}
+
+async fn fireup_rsync(
+	rsync: RsyncCommand,
+	dst_root: PathBuf,
+	file_list: PathBuf,
+	bwlimit: Option<u64>,
+	checksum: bool,
+	events: Arc<EventLog>,
+) -> Result<TransferStats> {
+	let mut cmd = rsync.command(&dst_root, &file_list, bwlimit, checksum);
+	// Make sure rsync gets killed if the sync job is cancelled.
+	cmd.kill_on_drop(true);
+	cmd.stdout(Stdio::piped());
+	cmd.stderr(Stdio::piped());
+	let mut handle = cmd.spawn().context("Failed to start rsync")?;
+	let mut stdout = BufReader::new(handle.stdout.take().context("No stdout")?).lines();
+	let mut stderr = BufReader::new(handle.stderr.take().context("No stderr")?).lines();
+	let mut output = RsyncOutput::default();
+	let (mut stdout_open, mut stderr_open) = (true, true);
+	while stdout_open || stderr_open {
+		tokio::select! {
+			line = stdout.next_line(), if stdout_open => match line? {
+				Some(line) => {
+					if let Some(path) = output.parse_stdout(&line) {
+						debug!("rsync: {}", path);
+						events.push(SyncEventKind::Download { path });
+					}
+				}
+				None => stdout_open = false,
+			},
+			line = stderr.next_line(), if stderr_open => match line? {
+				Some(line) => {
+					if let Some(e) = output.parse_stderr(&line) {
+						warn!("{}", e.message);
+						events.warn(e.message);
+					}
+				}
+				None => stderr_open = false,
+			},
+		}
+	}
+	let status = handle.wait().await?;
+	let mut stats = output.stats;
+	match status.code() {
+		Some(0) => {}
+		// Files removed from the upstream during the transfer
+		Some(24) => warn!("Some files vanished on the upstream during the transfer."),
+		// Like the errors of the files, this does not fail the sync, the
+		// files missing are fetched by the next one.
+		_ => {
+			let message = format!("rsync exited with {}", status);
+			warn!("{}", message);
+			events.warn(message.clone());
+			stats.push_error(RsyncError {
+				path: None,
+				message,
+			});
+		}
+	}
+	Ok(stats)
+}
+
+/// Run a sync job, then the follow-up sync jobs for the requests queued in
+/// the meantime.
+pub async fn do_sync_inner(s: Arc<RwLock<AppState>>, timestamp: i64, cancel: &CancelToken) {
+	let mut timestamp = timestamp;
+	loop {
+		do_sync_once(s.clone(), timestamp, cancel).await;
+		let mut lock = s.write().await;
+		match lock.pending_timestamp.take() {
+			Some(_) if cancel.is_cancelled() => {
+				warn!("Dropping the sync request queued during the cancellation.");
+				lock.syncing = false;
+				lock.sync_task = None;
+				break;
+			}
+			Some(_) if lock.shutting_down => {
+				info!("Shutting down, dropping the queued sync request.");
+				lock.syncing = false;
+				lock.sync_task = None;
+				break;
+			}
+			Some(t) => {
+				timestamp = follow_up_timestamp(t, timestamp);
+				info!("Starting the queued sync job (timestamp {}) ...", timestamp);
+			}
+			None => {
+				lock.syncing = false;
+				lock.sync_task = None;
+				break;
 			}
 		}
-		OperationMode::Debian => c.suites.clone(),
-	};
+	}
+}
+
+/// Timestamp of the sync job serving the request `queued`, after the one
+/// with `timestamp`. dists-TIMESTAMP must not collide with the one just
+/// published.
+fn follow_up_timestamp(queued: i64, timestamp: i64) -> i64 {
+	queued.max(timestamp + 1)
+}
+
+/// Context of the errors fetching the metadata or transferring the files,
+/// before the snapshot is published. Another upstream might not have them.
+#[derive(Debug)]
+struct UpstreamError;
+
+impl std::fmt::Display for UpstreamError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Unable to fetch from the upstream")
+	}
+}
+
+/// Run a sync job against the upstream in the config.
+#[allow(clippy::too_many_arguments)]
+async fn sync_from(
+	c: &AppConfig,
+	timestamp: i64,
+	k: &PgpKeyringStore,
+	client: &Client,
+	metrics: &Metrics,
+	events: &Arc<EventLog>,
+	transfers: &Arc<Semaphore>,
+	bandwidth: &Arc<SharedBandwidth>,
+	cancel: &CancelToken,
+) -> Result<Option<TransferStats>> {
+	let suites = cancel
+		.run(suites_to_sync(c, client, c.mirror_root.clone()))
+		.await
+		.context(UpstreamError)?;
 	let j = SyncJob {
-		rsync_url: &c.mirror_url,
 		http_url: &c.http_url,
 		mode: c.mode,
 		mirror_sources: c.mirror_sources,
@@ -181,37 +457,246 @@ pub async fn do_sync_inner(s: Arc<RwLock<AppState>>, timestamp: i64) {
 		dst: &c.mirror_root,
 		threads: c.parallel_jobs,
 		timestamp,
-		keyring_store: &k,
-		client: &client,
+		keyring_store: k,
+		client,
+		metrics,
+		events,
+		transfers,
+		bandwidth,
+		disk_reserve: c.disk_reserve_mib * 1024 * 1024,
+		quota: c.quota_mib.map(|q| q * 1024 * 1024),
+		config: c,
+		scratch: None,
+		redownload: RedownloadQueue::load(&c.mirror_root)?.paths,
+		cancel: cancel.clone(),
 	};
+	do_sync_inner2(j).await
+}
+
+async fn do_sync_once(s: Arc<RwLock<AppState>>, timestamp: i64, cancel: &CancelToken) {
+	// The timestamp is changed for another upstream, not the request.
+	let requested = timestamp;
+	let mut timestamp = timestamp;
+	let local: DateTime<Local> = Local::now();
+	info!("Starting sync at {}", local);
+	let mut lock = s.write().await;
+	lock.syncing = true;
+	lock.current_timestamp = Some(timestamp);
+	let k = lock.keyring_store.clone();
+	let c = lock.config.clone();
+	let client = lock.client.clone();
+	let metrics = lock.metrics.clone();
+	let events = lock.events.clone();
+	let transfers = lock.transfers.clone();
+	let bandwidth = lock.bandwidth.clone();
+	drop(lock);
+	metrics.syncs_started.fetch_add(1, Ordering::Relaxed);
+	events.push(SyncEventKind::Started { timestamp });
+	let result = async {
+		let _lock = MirrorLock::acquire(&c.mirror_root, "sync", timestamp)?;
+		let upstreams = cancel.run(healthy_upstreams(&c, &client, &k)).await?;
+		let mut result = Err(anyhow!("No upstream to sync from"));
+		for (idx, upstream) in upstreams.iter().enumerate() {
+			if idx > 0 {
+				// Start over with the metadata of the next upstream, in a
+				// snapshot of its own.
+				let root = c.mirror_root.clone();
+				let t = timestamp;
+				tokio::task::spawn_blocking(move || remove_partial_files(root, t))
+					.await??;
+				timestamp = Utc::now().timestamp().max(timestamp + 1);
+				s.write().await.current_timestamp = Some(timestamp);
+				info!("Retrying with upstream {} ...", upstream);
+				events.warn(format!("Retrying with upstream {}", upstream));
+			}
+			let c = c.with_upstream(upstream);
+			result = sync_from(
+				&c, timestamp, &k, &client, &metrics, &events, &transfers,
+				&bandwidth, cancel,
+			)
+			.await
+			.map(|stats| (stats, upstream.to_string()));
+			match &result {
+				Ok(_) => break,
+				Err(_) if cancel.is_cancelled() => break,
+				// Only the upstream failing before the snapshot is published
+				// is worth another try, e.g. not a full disk.
+				Err(e) if idx + 1 < upstreams.len() && e.is::<UpstreamError>() => {
+					warn!("Sync from upstream {} failed: {:#}", upstream, e);
+				}
+				Err(_) => break,
+			}
+		}
+		if cancel.is_cancelled() {
+			// Still holding the lock, so nothing else sees the partial files.
+			let root = c.mirror_root.clone();
+			let t = timestamp;
+			if let Err(e) =
+				tokio::task::spawn_blocking(move || remove_partial_files(root, t))
+					.await?
+			{
+				error!("{:#}", e);
+				events.warn(format!("{:#}", e));
+			}
+		}
+		result
+	}
+	.await;
 	let mut status = Status::Success;
 	let mut message = String::new();
-	if let Err(e) = do_sync_inner2(j).await {
-		status = Status::Failed;
-		info!("Sync failed:");
-		error!("{}", e);
-		e.chain().skip(1).for_each(|e| error!("{}", e));
-		e.chain().for_each(|e| {
-			message.push_str(": ");
-			message.push_str(&e.to_string());
-		});
+	let mut transfer = None;
+	let mut upstream = None;
+	match result {
+		Ok((stats, url)) => {
+			transfer = stats;
+			upstream = Some(url);
+		}
+		Err(_) if cancel.is_cancelled() => {
+			status = Status::Cancelled;
+			message = cancel.reason().unwrap_or_default();
+		}
+		Err(e) => {
+			status = Status::Failed;
+			info!("Sync failed:");
+			error!("{}", e);
+			e.chain().skip(1).for_each(|e| error!("{}", e));
+			e.chain().for_each(|e| {
+				message.push_str(": ");
+				message.push_str(&e.to_string());
+			});
+		}
 	}
 	let mut lock = s.write().await;
-	lock.syncing = false;
 	let now: DateTime<Utc> = Utc::now();
 	let now = now.timestamp();
+	match status {
+		Status::Success => {
+			metrics.syncs_succeeded.fetch_add(1, Ordering::Relaxed);
+			metrics.last_success_timestamp.store(now, Ordering::Relaxed);
+		}
+		Status::Cancelled => {
+			metrics.syncs_cancelled.fetch_add(1, Ordering::Relaxed);
+		}
+		_ => {
+			metrics.syncs_failed.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+	events.push(SyncEventKind::Finished {
+		status,
+		message: message.clone(),
+	});
+	systemd::notify_status(&format!(
+		"Idle, last sync (timestamp {}): {:?}",
+		timestamp, status
+	));
+	lock.current_timestamp = None;
 	lock.last_sync_timestamp = now;
+	lock.last_request_timestamp = Some(requested);
 	lock.last_sync_status = status;
 	lock.last_sync_message = message;
+	lock.last_transfer = transfer;
+	if upstream.is_some() {
+		lock.last_upstream = upstream;
+	}
+	save_state(&lock);
+	drop(lock);
+	if status != Status::Success {
+		return;
+	}
+	// Pass the request on, the downstream mirrors sync from this one. That
+	// might take a while with the retries, so it does not hold up the next
+	// sync, nor the cancellation or the shutdown.
+	tokio::spawn(push_after_sync(s, c, timestamp, events));
+}
+
+/// Send the sync request to the downstream mirrors, and record the results.
+async fn push_after_sync(
+	s: Arc<RwLock<AppState>>,
+	c: Arc<AppConfig>,
+	timestamp: i64,
+	events: Arc<EventLog>,
+) {
+	match push_downstream(&c, timestamp).await {
+		Ok(Some(report)) => {
+			if report.num_failed > 0 {
+				events.warn(format!(
+					"{} of {} downstream mirrors failed to accept the sync request",
+					report.num_failed, report.num_clients
+				));
+			}
+			let mut lock = s.write().await;
+			// The push after a later sync might finish first.
+			if lock.last_push
+				.as_ref()
+				.is_none_or(|p| p.req_timestamp <= timestamp)
+			{
+				lock.last_push = Some(report);
+				save_state(&lock);
+			}
+		}
+		Ok(None) => {}
+		Err(e) => {
+			error!("Failed to invoke the downstream mirrors: {:#}", e);
+			events.warn(format!("Failed to invoke the downstream mirrors: {:#}", e));
+		}
+	}
 }
 
-async fn do_sync_inner2(j: SyncJob<'_>) -> Result<()> {
+/// Returns the suites to sync. In AOSC mode, the topics manifest is saved
+/// to `manifest_root` if topics are mirrored.
+pub async fn suites_to_sync(
+	c: &AppConfig,
+	client: &Client,
+	manifest_root: PathBuf,
+) -> Result<Vec<String>> {
+	Ok(match c.mode {
+		OperationMode::AOSC => {
+			if !c.mirror_topics {
+				vec!["stable".into()]
+			} else {
+				let mut topics =
+					fetch_topics(&c.http_url, manifest_root, client.clone())
+						.await
+						.context("Unable to fetch the topic manifest")?
+						.into_iter()
+						.map(|x| x.name)
+						.collect::<Vec<_>>();
+				info!("Manifest has {} topics.", topics.len());
+				topics.push("stable".into());
+				topics
+			}
+		}
+		OperationMode::Debian => c.suites.clone(),
+	})
+}
+
+/// Result of the phases of a sync job before transferring the package files.
+/// Nothing in the published tree is changed by these.
+pub struct PreparedSync {
+	pub manifests: Vec<AptRepoReleaseInfo>,
+	validators: HashMap<String, SuiteValidators>,
+	checksums: ChecksumCache,
+	/// Paths of all package files in the new snapshot
+	pub known_files: HashSet<String>,
+	/// Total size of the package files in the new snapshot
+	pub total_bytes: u64,
+	/// Package files to download
+	pub delta: Vec<FileEntry>,
+}
+
+/// Download the metadata into a new snapshot, collect the package files
+/// listed in it, then scan the mirror for the ones to download.
+pub async fn prepare_sync(j: &SyncJob<'_>) -> Result<PreparedSync> {
 	// Download manifests and metadata to dists-TIMESTAMP/SUITE.
-	let manifests = download_metadata(&j).await?;
+	let phase_start = Instant::now();
+	j.enter_phase(SyncPhase::Metadata);
+	let (manifests, validators, checksums) =
+		download_metadata(j).await.context(UpstreamError)?;
+	j.metrics
+		.observe_phase(SyncPhase::Metadata, phase_start.elapsed());
 
-	let rsync_url = j.rsync_url.clone();
-	let dst = j.dst.to_path_buf().clone();
-	let cur_dists_dir = dst.join(format!("dists-{}", j.timestamp));
+	let snapshot_root = j.snapshot_root().to_path_buf();
+	let cur_dists_dir = snapshot_root.join(format!("dists-{}", j.timestamp));
 	let mut suites = HashMap::new();
 	for manifest in &manifests {
 		let components = manifest.components.clone();
@@ -219,14 +704,21 @@ async fn do_sync_inner2(j: SyncJob<'_>) -> Result<()> {
 	}
 	let archs = j.archs.clone();
 	let suites2 = suites.clone();
-	let mut files_collected =
-		tokio::task::spawn_blocking(move || get_files(dst, suites2, archs, j.timestamp))
-			.await??;
+	let timestamp = j.timestamp;
+	let cancel = j.cancel.clone();
+	let phase_start = Instant::now();
+	j.enter_phase(SyncPhase::Collect);
+	let mut files_collected = tokio::task::spawn_blocking(move || {
+		get_files(snapshot_root, suites2, archs, timestamp, &cancel)
+	})
+	.await??;
 	if j.mode == OperationMode::Debian && j.mirror_sources {
 		let mut source_files =
 			collect_source_files(cur_dists_dir, suites, j.threads).await?;
 		files_collected.append(&mut source_files);
 	}
+	j.metrics
+		.observe_phase(SyncPhase::Collect, phase_start.elapsed());
 
 	let mut hashset: HashSet<String> = HashSet::with_capacity(files_collected.capacity());
 	files_collected
@@ -238,6 +730,8 @@ async fn do_sync_inner2(j: SyncJob<'_>) -> Result<()> {
 	files_collected.sort_by_key(|e| e.path.clone());
 	info!("Collected {} files in total.", files_collected.len());
 	info!("Scanning for incremental deltas ...");
+	let phase_start = Instant::now();
+	j.enter_phase(SyncPhase::Scan);
 	// Scan the files for incremental deltas, concurrently.
 	let mut scan_queues = Vec::new();
 	let actual_threads = files_collected.len().clamp(1, j.threads.into());
@@ -249,28 +743,98 @@ async fn do_sync_inner2(j: SyncJob<'_>) -> Result<()> {
 	let mut tasks = JoinSet::new();
 	for queue in scan_queues {
 		let root = j.dst.to_owned();
-		tasks.spawn_blocking(move || scan_delta(&root, &queue));
+		let cancel = j.cancel.clone();
+		tasks.spawn_blocking(move || scan_delta(&root, &queue, &cancel));
 	}
 	while let Some(task) = tasks.join_next().await {
-		delta.extend(task?);
+		delta.extend(task??);
 	}
-	// We don't need the sizes any more.
-	drop(files_collected);
+	// The queued files might look fine by their sizes.
+	let scanned: HashSet<String> = delta.iter().map(|f| f.path.clone()).collect();
+	for f in &files_collected {
+		if j.redownload.contains(&f.path) && !scanned.contains(&f.path) {
+			delta.push(f.clone());
+		}
+	}
+	j.metrics
+		.observe_phase(SyncPhase::Scan, phase_start.elapsed());
+	Ok(PreparedSync {
+		manifests,
+		validators,
+		checksums,
+		known_files: hashset,
+		total_bytes: files_collected.iter().map(|f| f.size).sum(),
+		delta,
+	})
+}
+
+/// Returns the figures of the transfer, None if there is nothing to
+/// download.
+async fn do_sync_inner2(j: SyncJob<'_>) -> Result<Option<TransferStats>> {
+	if !j.redownload.is_empty() {
+		info!("{} files are queued for re-download.", j.redownload.len());
+	}
+	let PreparedSync {
+		manifests,
+		validators,
+		checksums,
+		known_files: hashset,
+		total_bytes,
+		delta,
+	} = prepare_sync(&j).await?;
+	if let Err(e) = checksums.save(&j.dst) {
+		warn!(
+			"Failed to save the checksums of the metadata files: {:#}",
+			e
+		);
+	}
+	let delta_bytes: u64 = delta.iter().map(|f| f.size).sum();
+	if let Some(quota) = j.quota {
+		let mut total = total_bytes;
+		for manifest in &manifests {
+			total += manifest.metadata_size(j.mode)?;
+		}
+		if total > quota {
+			bail!(
+				"The mirror would exceed its quota: {} needed, the quota is {}",
+				format_size(total),
+				format_size(quota)
+			);
+		}
+	}
+	j.check_disk_space(delta_bytes, "the package files")?;
 
+	let mut transfer = None;
 	if !delta.is_empty() {
 		info!("Scan complete. {} files to download.", delta.len());
+		let phase_start = Instant::now();
+		j.enter_phase(SyncPhase::Transfer);
+		let rsync = RsyncCommand::new(j.config)?;
+		// Files to compare by their checksums get a list of their own, so do
+		// the queued ones, which rsync would otherwise skip as unchanged.
+		let (checksum_files, delta_files): (Vec<_>, Vec<_>) =
+			delta.iter().cloned().partition(|f| {
+				rsync.needs_checksum(&f.path) || j.redownload.contains(&f.path)
+			});
 		// Distribute files into N lists
 		let mut queues = Vec::new();
-		let each_size = delta.len().div_ceil(actual_threads as usize);
-		delta.chunks(each_size)
-			.for_each(|x| queues.push(x.to_vec()));
+		if !delta_files.is_empty() {
+			let actual_threads = delta_files.len().clamp(1, j.threads.into());
+			let each_size = delta_files.len().div_ceil(actual_threads);
+			delta_files
+				.chunks(each_size)
+				.for_each(|x| queues.push((x.to_vec(), false)));
+		}
+		if !checksum_files.is_empty() {
+			queues.push((checksum_files, true));
+		}
 
 		// Generate file lists for rsync
 		let tmp_dir = j.dst.join(".tmp");
 		create_dir_all(&tmp_dir).await?;
 		info!("Writing file lists to {} ...", tmp_dir.display());
 		let mut filelists = Vec::new();
-		for (idx, queue) in queues.into_iter().enumerate() {
+		for (idx, (queue, checksum)) in queues.into_iter().enumerate() {
 			let path = tmp_dir.join(format!("files-{}-{}.txt", j.timestamp, idx + 1));
 			let fd = File::options()
 				.create(true)
@@ -281,29 +845,75 @@ async fn do_sync_inner2(j: SyncJob<'_>) -> Result<()> {
 				.await?;
 			let mut writer = BufWriter::with_capacity(128 * 1024, fd);
 			for f in queue {
-				writer.write_all(f.as_bytes())
+				writer.write_all(f.path.as_bytes())
 					.await
 					.context("Failed to write file lists")?;
 				writer.write_all(b"\n").await?;
 			}
 			writer.flush().await?;
-			filelists.push(path);
+			filelists.push((path, checksum));
 		}
 
 		// Fire up N instances of rsync
-		let mut handles = Vec::new();
-		info!("Starting up {} rsync instances ...", j.threads);
-		for list in filelists {
-			let rsync_url = rsync_url.clone();
+		// Tasks in a JoinSet are aborted once it is dropped.
+		let mut handles = JoinSet::new();
+		info!("Starting up {} rsync instances ...", filelists.len());
+		// Each instance gets its share of the cap, among those running at
+		// the same time in all repositories.
+		let concurrency = filelists
+			.len()
+			.min(j.config.max_transfers.unwrap_or(usize::MAX)) as u64;
+		let _share = j.bandwidth.join(concurrency);
+		for (list, checksum) in filelists {
+			let rsync = rsync.clone();
 			let dst = j.dst.to_path_buf();
-			handles.push(tokio::task::spawn(async move {
-				fireup_rsync(rsync_url, dst, list).await
-			}));
+			let transfers = j.transfers.clone();
+			let bandwidth = j.bandwidth.clone();
+			let events = j.events.clone();
+			let config = j.config.clone();
+			handles.spawn(async move {
+				// Other repositories might be transferring as well.
+				let _permit = transfers.acquire_owned().await?;
+				let bwlimit = bandwidth.rsync_limit(
+					bandwidth_limit(&config, Local::now().time()),
+					config.max_transfers,
+				);
+				fireup_rsync(rsync, dst, list, bwlimit, checksum, events).await
+			});
 		}
 
-		for r in handles.into_iter() {
-			r.await??;
+		let mut stats = TransferStats::default();
+		let all_done = async {
+			while let Some(r) = handles.join_next().await {
+				stats.add(r?.context(UpstreamError)?);
+			}
+			Ok(())
+		};
+		let joined = j.cancel.run(all_done).await;
+		if joined.is_err() {
+			// Wait for the rsync instances to be killed.
+			handles.shutdown().await;
 		}
+		joined?;
+		j.metrics
+			.observe_phase(SyncPhase::Transfer, phase_start.elapsed());
+		j.metrics
+			.files_transferred
+			.fetch_add(stats.files_transferred, Ordering::Relaxed);
+		j.metrics
+			.bytes_transferred
+			.fetch_add(stats.bytes_received, Ordering::Relaxed);
+		j.metrics
+			.rsync_errors
+			.fetch_add(stats.errors.len() as u64, Ordering::Relaxed);
+		info!(
+			"rsync transferred {} files ({}), received {}, speedup {:.2}.",
+			stats.files_transferred,
+			format_size(stats.transferred_size),
+			format_size(stats.bytes_received),
+			stats.speedup
+		);
+		transfer = Some(stats);
 	} else {
 		info!("The mirror is up to date - nothing to download.");
 	}
@@ -317,27 +927,50 @@ async fn do_sync_inner2(j: SyncJob<'_>) -> Result<()> {
 	}
 	info!("Linking dists to dists-{} ...", j.timestamp);
 	symlink(tmp_dists, symlink_dists).await?;
+	let cache = ManifestCache {
+		timestamp: j.timestamp,
+		suites: validators,
+	};
+	if let Err(e) = cache.save(&j.dst) {
+		warn!("Failed to save the manifest validators: {:#}", e);
+	}
+	if let Some(date) = manifests.iter().filter_map(|m| m.date).max() {
+		j.metrics
+			.release_date
+			.store(date.timestamp(), Ordering::Relaxed);
+	}
 
 	// Remove unused files
+	let phase_start = Instant::now();
+	j.enter_phase(SyncPhase::Cleanup);
 	let root = j.dst.to_path_buf();
-	tokio::task::spawn_blocking(move || {
-		remove_unused_files(root, j.timestamp, hashset)
+	let events = j.events.clone();
+	let cancel = j.cancel.clone();
+	let removed = tokio::task::spawn_blocking(move || {
+		remove_unused_files(root, j.timestamp, hashset, events, &cancel)
 	})
 	.await??;
+	j.metrics
+		.observe_phase(SyncPhase::Cleanup, phase_start.elapsed());
+	j.metrics
+		.files_deleted
+		.fetch_add(removed as u64, Ordering::Relaxed);
+
+	if !j.redownload.is_empty()
+		&& let Err(e) = RedownloadQueue::clear(&j.dst)
+	{
+		warn!("{:#}", e);
+	}
 
 	let local: DateTime<Local> = Local::now();
 	info!("Sync finished successfully at {}", local);
-	Ok(())
+	Ok(transfer)
 }
 
-fn remove_unused_files(
-	root: PathBuf,
-	cur_timestamp: i64,
-	known_files: HashSet<String>,
-) -> Result<()> {
-	info!("Removing unused files ...");
-	// Remove old dists
-	for entry in walkdir::WalkDir::new(&root)
+/// Returns the dists-TIMESTAMP directories other than the given one.
+pub fn old_dists_dirs(root: &Path, cur_timestamp: i64) -> Result<Vec<PathBuf>> {
+	let mut dirs = Vec::new();
+	for entry in walkdir::WalkDir::new(root)
 		.min_depth(1)
 		.max_depth(1)
 		.into_iter()
@@ -354,25 +987,25 @@ fn remove_unused_files(
 			.file_name()
 			.to_str()
 			.context(format!("Invalid name: {}", entry.path().display()))?;
-		if !name.starts_with("dists-") {
-			continue;
-		}
-		if let Some(n) = name.strip_prefix("dists-") {
-			let t = n.parse::<i64>();
-			if let std::result::Result::Ok(t) = t {
-				if t != cur_timestamp {
-					info!("Removing old dists directory {} ...", name);
-					remove_dir_all(entry.path()).context(format!(
-						"Unable to remove directory {}",
-						entry.path().display()
-					))?;
-				}
-			}
+		let stale = name
+			.strip_prefix("dists-")
+			.and_then(|n| n.parse::<i64>().ok())
+			.is_some_and(|t| t != cur_timestamp);
+		if stale {
+			dirs.push(entry.into_path());
 		}
 	}
-	// Remove package files that is not known to us
-	info!("Removing unused package files ...");
-	let mut cnt: usize = 0;
+	Ok(dirs)
+}
+
+/// Returns the paths (relative to the mirror root) of the package files
+/// that is not known to us.
+pub fn unused_package_files(
+	root: &Path,
+	known_files: &HashSet<String>,
+	events: &EventLog,
+) -> Vec<PathBuf> {
+	let mut files = Vec::new();
 	for entry in walkdir::WalkDir::new(root.join("pool"))
 		.min_depth(1)
 		.follow_links(false)
@@ -386,7 +1019,7 @@ fn remove_unused_files(
 		if !entry.file_type().is_file() {
 			continue;
 		}
-		let rel = if let Ok(rel) = entry.path().strip_prefix(&root) {
+		let rel = if let Ok(rel) = entry.path().strip_prefix(root) {
 			rel
 		} else {
 			continue;
@@ -395,16 +1028,47 @@ fn remove_unused_files(
 			s
 		} else {
 			warn!("Invalid file path {}", rel.display());
+			events.warn(format!("Invalid file path {}", rel.display()));
 			continue;
 		};
 		if !known_files.contains(rel_str) {
-			if let Err(e) = remove_file(entry.path()) {
-				warn!("Unable to remove {}: {}", entry.path().display(), e);
-				continue;
-			}
-			info!("Removed {}", rel_str);
-			cnt += 1;
+			files.push(rel.to_path_buf());
+		}
+	}
+	files
+}
+
+fn remove_unused_files(
+	root: PathBuf,
+	cur_timestamp: i64,
+	known_files: HashSet<String>,
+	events: Arc<EventLog>,
+	cancel: &CancelToken,
+) -> Result<usize> {
+	info!("Removing unused files ...");
+	// Remove old dists
+	for dir in old_dists_dirs(&root, cur_timestamp)? {
+		cancel.check()?;
+		info!(
+			"Removing old dists directory {} ...",
+			dir.file_name().unwrap_or_default().to_string_lossy()
+		);
+		remove_dir_all(&dir)
+			.context(format!("Unable to remove directory {}", dir.display()))?;
+	}
+	// Remove package files that is not known to us
+	info!("Removing unused package files ...");
+	let mut cnt: usize = 0;
+	for rel in unused_package_files(&root, &known_files, &events) {
+		cancel.check()?;
+		let path = root.join(&rel);
+		if let Err(e) = remove_file(&path) {
+			warn!("Unable to remove {}: {}", path.display(), e);
+			events.warn(format!("Unable to remove {}: {}", path.display(), e));
+			continue;
 		}
+		info!("Removed {}", rel.display());
+		cnt += 1;
 	}
 	info!("Removed {} files.", cnt);
 
@@ -418,51 +1082,213 @@ fn remove_unused_files(
 	}
 	info!("Finished removing unused files.");
 	drop(known_files);
-	Ok(())
+	Ok(cnt)
+}
+
+/// Verify the manifests of a suite and parse the release information.
+pub(crate) fn verify_manifest(
+	inrelease: &Option<String>,
+	release: &Option<(String, String)>,
+	keyring_store: &PgpKeyringStore,
+) -> Result<AptRepoReleaseInfo> {
+	if let Some(s) = inrelease {
+		let (body, sig) = split_inrelease(s);
+		verify_pgp_signature(&body, &sig, keyring_store)?;
+		// Also verify the signature of Release.
+		if let Some((release, sig)) = release {
+			verify_pgp_signature(&release, &sig, keyring_store)
+				.context("Failed to verify the authenticity of the Release file")?;
+		}
+		AptRepoReleaseInfo::parse_from(&body)
+	} else if let Some((release, sig)) = release {
+		verify_pgp_signature(&release, &sig, keyring_store)?;
+		AptRepoReleaseInfo::parse_from(release)
+	} else {
+		bail!("No InRelease or Release file provided");
+	}
+}
+
+/// Reuse the metadata of an unchanged suite in the published snapshot, by
+/// hard linking them into the new snapshot. The manifests are verified again,
+/// as the keyring might have changed since then.
+async fn reuse_suite_metadata(
+	j: &SyncJob<'_>,
+	published: i64,
+	suite: &str,
+) -> Result<AptRepoReleaseInfo> {
+	let src = j.dst.join(format!("dists-{}", published));
+	let dst = j.snapshot_root().join(format!("dists-{}", j.timestamp));
+	let cancel = j.cancel.clone();
+	let read = |name: &str| {
+		let path = src.join(suite).join(name);
+		std::fs::read_to_string(&path).ok()
+	};
+	let inrelease = read("InRelease");
+	let release = read("Release").zip(read("Release.gpg"));
+	let manifest = verify_manifest(&inrelease, &release, j.keyring_store).context(format!(
+		"Unable to reuse the published manifests of suite {}",
+		suite
+	))?;
+	// dists/<suite> might be a symbolic link to dists/<codename>.
+	let mut dirs = vec![suite.to_string()];
+	if manifest.codename != suite && src.join(&manifest.codename).is_dir() {
+		dirs.push(manifest.codename.clone());
+	}
+	tokio::task::spawn_blocking(move || {
+		for dir in dirs {
+			if dst.join(&dir).symlink_metadata().is_ok() {
+				continue;
+			}
+			if src.join(&dir).is_symlink() {
+				std::os::unix::fs::symlink(
+					std::fs::read_link(src.join(&dir))?,
+					dst.join(&dir),
+				)?;
+			} else {
+				hard_link_tree(&src.join(&dir), &dst.join(&dir), &cancel)?;
+			}
+		}
+		anyhow::Ok(())
+	})
+	.await??;
+	Ok(manifest)
 }
 
-async fn download_metadata(j: &SyncJob<'_>) -> Result<Vec<AptRepoReleaseInfo>> {
+async fn download_metadata(
+	j: &SyncJob<'_>,
+) -> Result<(
+	Vec<AptRepoReleaseInfo>,
+	HashMap<String, SuiteValidators>,
+	ChecksumCache,
+)> {
 	let mut manifests = Vec::new();
+	let mut validators = HashMap::new();
+	let mut checksums = ChecksumCache::load(&j.dst).unwrap_or_else(|e| {
+		warn!("{:#}", e);
+		ChecksumCache::default()
+	});
+	let snapshot_root = j.snapshot_root();
+	create_dir_all(snapshot_root.join(format!("dists-{}", j.timestamp))).await?;
+	let mut limit = None;
+	let mut limiter = None;
+	// Validators are only useful if the metadata they belong to are still
+	// there to be reused.
+	let published = published_snapshot(&j.dst);
+	let cache = match published.map(|ts| ManifestCache::load(&j.dst, ts)) {
+		Some(Ok(cache)) => cache.unwrap_or_default(),
+		Some(Err(e)) => {
+			warn!("{:#}", e);
+			ManifestCache::default()
+		}
+		None => ManifestCache::default(),
+	};
 	for suite in &j.suites {
-		let (inrelease_content, release) =
-			fetch_manifest(j.http_url.clone(), suite.clone(), j.client).await?;
-		let manifest = if let Some(s) = &inrelease_content {
-			let (body, sig) = split_inrelease(&s);
-			verify_pgp_signature(&body, &sig, j.keyring_store)?;
-			// Also verify the signature of Release.
-			if let Some((release, sig)) = &release {
-				verify_pgp_signature(&release, &sig, j.keyring_store).context(
-					"Failed to verify the authenticity of the Release file",
-				)?;
+		j.cancel.check()?;
+		// Suites with queued metadata files are fetched in full.
+		let queued = j
+			.redownload
+			.iter()
+			.any(|p| p.starts_with(&format!("dists/{}/", suite)));
+		let previous = cache.suites.get(suite).filter(|_| !queued);
+		let published_dir = j.dst.join("dists").join(suite);
+		let fetched = fetch_manifest_conditional(
+			j.http_url,
+			suite,
+			j.client,
+			previous,
+			Some(&published_dir),
+		)
+		.await?;
+		let (inrelease_content, release, suite_validators) = match fetched {
+			ManifestFetch::Unchanged => {
+				// Unchanged implies that the validators are cached, which
+				// in turn implies a published snapshot.
+				let published = published.context("No published snapshot")?;
+				match reuse_suite_metadata(j, published, suite).await {
+					Ok(manifest) => {
+						info!(
+							"Suite {} is unchanged, reusing its metadata.",
+							suite
+						);
+						manifests.push(manifest);
+						checksums.keep_suite(suite);
+						validators.insert(
+							suite.clone(),
+							previous.cloned().unwrap_or_default(),
+						);
+						continue;
+					}
+					Err(e) => {
+						warn!("{:#}", e);
+						let (inrelease, release) = fetch_manifest(
+							j.http_url.clone(),
+							suite.clone(),
+							j.client,
+						)
+						.await?;
+						(inrelease, release, SuiteValidators::default())
+					}
+				}
 			}
-			AptRepoReleaseInfo::parse_from(&body)?
-		} else if let Some(pair) = &release {
-			let (release, sig) = pair;
-			verify_pgp_signature(&release, &sig, j.keyring_store)?;
-			AptRepoReleaseInfo::parse_from(&release)?
-		} else {
-			bail!("No InRelease or Release file provided");
+			ManifestFetch::Fetched {
+				inrelease,
+				release,
+				validators,
+			} => (inrelease, release, validators),
 		};
+		let manifest = verify_manifest(&inrelease_content, &release, j.keyring_store)?;
+		let suite_checksums = checksums.suite(suite);
+		if j.scratch.is_none() {
+			// Only the changed files take space, the rest are hard links.
+			let needed = metadata_download_size(
+				&manifest,
+				j.mode,
+				j.dst,
+				&suite_checksums,
+				&j.redownload,
+			)?;
+			j.check_disk_space(needed, &format!("the metadata of suite {}", suite))?;
+		}
+		let suite_checksums = Arc::new(Mutex::new(suite_checksums));
+		let current = j.bandwidth_limit();
+		if current != limit {
+			limit = current;
+			match limit {
+				Some(l) => info!("Limiting the bandwidth to {} KiB/s.", l),
+				None => info!("Not limiting the bandwidth."),
+			}
+			limiter = j.bandwidth.bucket(limit);
+		}
 		// Save InRelease to the disk.
 		download_metadata_files(
 			j.http_url,
 			&manifest,
 			j.dst.to_path_buf(),
+			snapshot_root.to_path_buf(),
 			j.timestamp,
 			j.mode,
 			j.threads.into(),
 			j.client,
+			j.events,
+			&suite_checksums,
+			limiter.as_ref(),
+			&j.redownload,
+			&j.cancel,
 		)
 		.await?;
+		if let Ok(suite_checksums) = Arc::try_unwrap(suite_checksums) {
+			checksums.finish_suite(suite, suite_checksums.into_inner().unwrap());
+		}
 		manifests.push(manifest);
+		validators.insert(suite.clone(), suite_validators);
 		info!("Saving InRelease/Release ...");
 		// Integrity of the metadata files are verified, let's save the InRelease and Release/Release.gpg.
 		if let Some(s) = &inrelease_content {
-			let path =
-				j.dst.join(format!("dists-{}/{}/InRelease", j.timestamp, &suite));
+			let path = snapshot_root
+				.join(format!("dists-{}/{}/InRelease", j.timestamp, &suite));
+			remove_existing(&path)?;
 			let mut fd = File::options()
-				.create(true)
-				.truncate(true)
+				.create_new(true)
 				.write(true)
 				.open(&path)
 				.await?;
@@ -470,20 +1296,20 @@ async fn download_metadata(j: &SyncJob<'_>) -> Result<Vec<AptRepoReleaseInfo>> {
 			fd.flush().await?;
 		}
 		if let Some((content, sig)) = &release {
-			let path =
-				j.dst.join(format!("dists-{}/{}/Release", j.timestamp, &suite));
+			let path = snapshot_root
+				.join(format!("dists-{}/{}/Release", j.timestamp, &suite));
+			remove_existing(&path)?;
 			let mut fd = File::options()
-				.create(true)
-				.truncate(true)
+				.create_new(true)
 				.write(true)
 				.open(&path)
 				.await?;
 			fd.write_all(content.as_bytes()).await?;
-			let path =
-				j.dst.join(format!("dists-{}/{}/Release.gpg", j.timestamp, &suite));
+			let path = snapshot_root
+				.join(format!("dists-{}/{}/Release.gpg", j.timestamp, &suite));
+			remove_existing(&path)?;
 			let mut fd = File::options()
-				.create(true)
-				.truncate(true)
+				.create_new(true)
 				.write(true)
 				.open(&path)
 				.await?;
@@ -491,5 +1317,30 @@ async fn download_metadata(j: &SyncJob<'_>) -> Result<Vec<AptRepoReleaseInfo>> {
 			fd.flush().await?;
 		}
 	}
-	Ok(manifests)
+	Ok((manifests, validators, checksums))
+}
+
+#[tokio::test]
+async fn test_queue_requests() {
+	let s = crate::test_state(crate::config::test_config(""));
+	s.write().await.syncing = true;
+	for timestamp in [100, 50] {
+		let body = SyncRequestBody {
+			timestamp,
+			signature: String::new(),
+		};
+		let res = do_sync(
+			ConnectInfo(PeerAddr::Unix(None)),
+			State(s.clone()),
+			Json(body),
+		)
+		.await;
+		assert_eq!(res.status(), 202);
+		let res: SyncRequestResponse = serde_json::from_str(res.body()).unwrap();
+		assert_eq!(res.status, Status::Queued);
+	}
+	// The requests collapse into one follow-up sync, for the newest one.
+	assert_eq!(s.read().await.pending_timestamp, Some(100));
+	assert_eq!(follow_up_timestamp(100, 90), 100);
+	assert_eq!(follow_up_timestamp(100, 100), 101);
 }
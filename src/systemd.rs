@@ -0,0 +1,160 @@
+//! Minimal systemd integration: sd_notify(3), the watchdog and socket
+//! activation, without linking to libsystemd.
+
+use std::{
+	env,
+	os::{
+		fd::{FromRawFd, RawFd},
+		linux::net::SocketAddrExt,
+		unix::net::{SocketAddr, UnixDatagram},
+	},
+	sync::Arc,
+	time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use log::{debug, warn};
+use tokio::{sync::RwLock, time::timeout};
+
+use crate::AppState;
+
+/// The first file descriptor passed by socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Send a notification to the service manager. Does nothing if the program
+/// is not started by systemd with `Type=notify`.
+pub fn notify(state: &str) -> Result<()> {
+	let path = match env::var_os("NOTIFY_SOCKET") {
+		Some(p) => p,
+		None => return Ok(()),
+	};
+	let path = path.to_string_lossy();
+	let addr = if let Some(name) = path.strip_prefix('@') {
+		SocketAddr::from_abstract_name(name)?
+	} else {
+		SocketAddr::from_pathname(path.as_ref())?
+	};
+	let socket = UnixDatagram::unbound()?;
+	socket.send_to_addr(state.as_bytes(), &addr)
+		.context(format!("Failed to notify systemd via {}", path))?;
+	debug!("Sent {:?} to systemd", state);
+	Ok(())
+}
+
+/// Tell the service manager that the configuration is being reloaded.
+/// Send READY=1 once it is done.
+pub fn notify_reloading() -> Result<()> {
+	// SAFETY: timespec is plain data, clock_gettime(2) only writes to it.
+	let ts = unsafe {
+		let mut ts: libc::timespec = std::mem::zeroed();
+		libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+		ts
+	};
+	let usec = ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000;
+	notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec))
+}
+
+/// Update the status line shown by `systemctl status`. Errors are logged.
+pub fn notify_status(status: &str) {
+	if let Err(e) = notify(&format!("STATUS={}", status)) {
+		warn!("{:#}", e);
+	}
+}
+
+/// Returns the interval to send WATCHDOG=1, if the watchdog is enabled for
+/// this process. Pings are sent twice as often as systemd expects.
+pub fn watchdog_interval() -> Option<Duration> {
+	if let Some(pid) = env::var_os("WATCHDOG_PID")
+		&& pid.to_string_lossy() != std::process::id().to_string()
+	{
+		return None;
+	}
+	let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+	if usec == 0 {
+		return None;
+	}
+	Some(Duration::from_micros(usec / 2))
+}
+
+/// Ping the watchdog as long as the daemon responds, i.e. the state of
+/// every repository can be read within half of the interval. A state locked
+/// up for longer (e.g. by a deadlock) lets the watchdog fire.
+pub async fn run_watchdog(interval: Duration, states: Vec<Arc<RwLock<AppState>>>) {
+	let mut timer = tokio::time::interval(interval);
+	loop {
+		timer.tick().await;
+		let responsive = timeout(interval / 2, async {
+			for state in &states {
+				drop(state.read().await);
+			}
+		})
+		.await;
+		if responsive.is_err() {
+			warn!("The daemon is not responding, not pinging the watchdog.");
+			continue;
+		}
+		if let Err(e) = notify("WATCHDOG=1") {
+			warn!("{:#}", e);
+		}
+	}
+}
+
+/// A listening socket passed by socket activation.
+pub enum ActivatedSocket {
+	Tcp(std::net::TcpListener),
+	Unix(std::os::unix::net::UnixListener),
+}
+
+/// Take the listening sockets passed by socket activation (LISTEN_FDS).
+/// Returns an empty list if there is none.
+pub fn listen_fds() -> Result<Vec<ActivatedSocket>> {
+	match env::var("LISTEN_PID") {
+		Ok(pid) if pid == std::process::id().to_string() => {}
+		_ => return Ok(Vec::new()),
+	}
+	let num: RawFd = env::var("LISTEN_FDS")
+		.context("LISTEN_PID is set, but LISTEN_FDS is not")?
+		.parse()
+		.context("Invalid LISTEN_FDS value")?;
+	let mut listeners = Vec::new();
+	for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + num {
+		// SAFETY: fcntl(2) and getsockname(2) only inspect the file
+		// descriptor, and systemd hands the ownership of these to us.
+		unsafe {
+			// Do not leak the sockets to rsync.
+			if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+				bail!(
+					"Invalid file descriptor {} passed by systemd: {}",
+					fd,
+					std::io::Error::last_os_error()
+				);
+			}
+			let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+			let mut len =
+				std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+			if libc::getsockname(
+				fd,
+				&mut addr as *mut _ as *mut libc::sockaddr,
+				&mut len,
+			) != 0
+			{
+				bail!("File descriptor {} passed by systemd is not a socket", fd);
+			}
+			match addr.ss_family as libc::c_int {
+				libc::AF_INET | libc::AF_INET6 => {
+					listeners.push(ActivatedSocket::Tcp(
+						std::net::TcpListener::from_raw_fd(fd),
+					))
+				}
+				libc::AF_UNIX => listeners.push(ActivatedSocket::Unix(
+					std::os::unix::net::UnixListener::from_raw_fd(fd),
+				)),
+				_ => warn!(
+					"Ignoring the unsupported socket (fd {}) passed by systemd",
+					fd
+				),
+			}
+		}
+	}
+	Ok(listeners)
+}
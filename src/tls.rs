@@ -0,0 +1,92 @@
+use std::{fs::read, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use reqwest::{Certificate, ClientBuilder, Identity};
+use tokio_rustls::rustls::{
+	RootCertStore, ServerConfig,
+	crypto::ring::default_provider,
+	pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+	server::WebPkiClientVerifier,
+};
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+	let certs = CertificateDer::pem_file_iter(path)
+		.context(format!("Failed to open {}", path.display()))?
+		.collect::<Result<Vec<_>, _>>()
+		.context(format!(
+			"Failed to parse certificates in {}",
+			path.display()
+		))?;
+	if certs.is_empty() {
+		anyhow::bail!("No certificates found in {}", path.display());
+	}
+	Ok(certs)
+}
+
+/// Build the rustls server config for the sync listener.
+/// If `client_ca` is given, clients must present a certificate signed by
+/// one of the CAs in that file (mutual TLS).
+pub fn load_server_config(
+	cert: &Path,
+	key: &Path,
+	client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>> {
+	let certs = load_certs(cert)?;
+	let key = PrivateKeyDer::from_pem_file(key).context(format!(
+		"Failed to read the private key from {}",
+		key.display()
+	))?;
+	let provider = Arc::new(default_provider());
+	let builder = ServerConfig::builder_with_provider(provider.clone())
+		.with_safe_default_protocol_versions()?;
+	let builder = if let Some(ca) = client_ca {
+		let mut roots = RootCertStore::empty();
+		for cert in load_certs(ca)? {
+			roots.add(cert)
+				.context(format!("Invalid CA certificate in {}", ca.display()))?;
+		}
+		let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+			.build()
+			.context("Failed to set up client certificate verification")?;
+		builder.with_client_cert_verifier(verifier)
+	} else {
+		builder.with_no_client_auth()
+	};
+	let mut config = builder
+		.with_single_cert(certs, key)
+		.context("Invalid certificate or private key")?;
+	config.alpn_protocols = vec![b"http/1.1".to_vec()];
+	Ok(Arc::new(config))
+}
+
+/// Configure the client certificate (for mutual TLS) and the additional
+/// trusted CA of a HTTP client. Files are in PEM format, and the private key
+/// must be in PKCS#8.
+pub fn configure_client_tls(
+	mut builder: ClientBuilder,
+	client_cert: Option<&Path>,
+	client_key: Option<&Path>,
+	ca_cert: Option<&Path>,
+) -> Result<ClientBuilder> {
+	match (client_cert, client_key) {
+		(Some(cert), Some(key)) => {
+			let cert =
+				read(cert).context(format!("Failed to read {}", cert.display()))?;
+			let key = read(key).context(format!("Failed to read {}", key.display()))?;
+			let identity = Identity::from_pkcs8_pem(&cert, &key)
+				.context("Invalid client certificate or private key")?;
+			builder = builder.identity(identity);
+		}
+		(None, None) => {}
+		_ => anyhow::bail!("Client certificate and private key must be given together"),
+	}
+	if let Some(ca) = ca_cert {
+		let content = read(ca).context(format!("Failed to read {}", ca.display()))?;
+		for cert in Certificate::from_pem_bundle(&content)
+			.context(format!("Invalid CA certificate in {}", ca.display()))?
+		{
+			builder = builder.add_root_certificate(cert);
+		}
+	}
+	Ok(builder)
+}
@@ -0,0 +1,163 @@
+//! Choosing the upstream to sync from, among mirror_url and the fallback
+//! [[upstream]] tables.
+
+use anyhow::{Result, bail};
+use log::{info, warn};
+use reqwest::Client;
+
+use crate::{
+	config::{AppConfig, OperationMode, Upstream},
+	metadata::{AptRepoReleaseInfo, fetch_manifest},
+	sync::verify_manifest,
+	verify::PgpKeyringStore,
+};
+
+/// The verified manifest of a suite on an upstream.
+struct SuiteManifest {
+	info: AptRepoReleaseInfo,
+	/// InRelease, or Release if there is no InRelease
+	content: String,
+}
+
+/// Fetch and verify the manifests of the suites on an upstream.
+async fn check_upstream(
+	upstream: &Upstream,
+	suites: &[String],
+	client: &Client,
+	keyring_store: &PgpKeyringStore,
+) -> Result<Vec<SuiteManifest>> {
+	let mut manifests = Vec::new();
+	for suite in suites {
+		let (inrelease, release) =
+			fetch_manifest(upstream.http_url.clone(), suite.clone(), client).await?;
+		let info = verify_manifest(&inrelease, &release, keyring_store)?;
+		let content = match (inrelease, release) {
+			(Some(inrelease), _) => inrelease,
+			(None, Some((release, _))) => release,
+			(None, None) => unreachable!(),
+		};
+		manifests.push(SuiteManifest { info, content });
+	}
+	Ok(manifests)
+}
+
+/// Returns the upstreams to try in order. With fallbacks configured, only
+/// the healthy ones: their manifests can be fetched and verified, and are
+/// the same as the newest ones found on any upstream.
+pub async fn healthy_upstreams(
+	config: &AppConfig,
+	client: &Client,
+	keyring_store: &PgpKeyringStore,
+) -> Result<Vec<Upstream>> {
+	let upstreams = config.upstreams();
+	if upstreams.len() == 1 {
+		return Ok(upstreams);
+	}
+	// Topics come and go, stable is enough to compare the upstreams.
+	let suites = match config.mode {
+		OperationMode::AOSC => vec!["stable".to_string()],
+		OperationMode::Debian => config.suites.clone(),
+	};
+	info!("Checking {} upstreams ...", upstreams.len());
+	let mut checked = Vec::new();
+	for upstream in upstreams {
+		match check_upstream(&upstream, &suites, client, keyring_store).await {
+			Ok(manifests) => checked.push((upstream, manifests)),
+			Err(e) => warn!("Upstream {} is unhealthy: {:#}", upstream, e),
+		}
+	}
+	let healthy = up_to_date(&suites, &checked);
+	if healthy.is_empty() {
+		bail!("No healthy upstream found, refer to the log above for details");
+	}
+	Ok(healthy)
+}
+
+/// The upstreams whose manifests are the same as the newest ones, in order.
+fn up_to_date(suites: &[String], checked: &[(Upstream, Vec<SuiteManifest>)]) -> Vec<Upstream> {
+	// The newest manifest of each suite is the reference.
+	let mut newest: Vec<&SuiteManifest> = Vec::new();
+	for (_, manifests) in checked {
+		for (idx, m) in manifests.iter().enumerate() {
+			match newest.get_mut(idx) {
+				Some(n) if n.info.date < m.info.date => *n = m,
+				Some(_) => {}
+				None => newest.push(m),
+			}
+		}
+	}
+	let mut healthy = Vec::new();
+	'upstreams: for (upstream, manifests) in checked {
+		for ((suite, m), n) in suites.iter().zip(manifests).zip(&newest) {
+			// Undated manifests are only compared by their content.
+			if let (Some(date), Some(newest)) = (m.info.date, n.info.date)
+				&& date < newest
+			{
+				warn!(
+					"Upstream {} is outdated: suite {} is dated {}, the newest is {}.",
+					upstream, suite, date, newest
+				);
+				continue 'upstreams;
+			}
+			if m.content != n.content {
+				warn!(
+					"Upstream {} presents different metadata of suite {}, ignoring.",
+					upstream, suite
+				);
+				continue 'upstreams;
+			}
+		}
+		healthy.push(upstream.clone());
+	}
+	healthy
+}
+
+#[tokio::test]
+async fn test_healthy_upstreams() -> Result<()> {
+	let manifest = |date: &str, extra: &str| SuiteManifest {
+		info: AptRepoReleaseInfo::parse_from(&format!(
+			"Suite: stable\nCodename: stable\nDate: {}\nArchitectures: amd64\nComponents: main\n",
+			date
+		))
+		.unwrap(),
+		content: format!("{}{}", date, extra),
+	};
+	let upstream = |n: u32| Upstream {
+		mirror_url: format!("rsync://{}.example.org/debs/", n).parse().unwrap(),
+		http_url: format!("https://{}.example.org/debs/", n).parse().unwrap(),
+	};
+	let newer = "Tue, 20 Oct 2026 00:00:00 UTC";
+	let older = "Mon, 19 Oct 2026 00:00:00 UTC";
+	assert!(manifest(older, "").info.date.is_some());
+	let suites = ["stable".to_string()];
+	let checked = vec![
+		(upstream(1), vec![manifest(older, "")]),
+		(upstream(2), vec![manifest(newer, "")]),
+		// Same date, but different metadata
+		(upstream(3), vec![manifest(newer, "tampered")]),
+		(upstream(4), vec![manifest(newer, "")]),
+	];
+	assert_eq!(up_to_date(&suites, &checked), [upstream(2), upstream(4)]);
+
+	// A single upstream is not checked at all.
+	let mut config = crate::config::test_config(
+		r#"
+http_url = "http://127.0.0.1:9/"
+"#,
+	);
+	let client = Client::new();
+	let keyring_store = PgpKeyringStore::new();
+	assert_eq!(
+		healthy_upstreams(&config, &client, &keyring_store).await?,
+		config.upstreams()
+	);
+	// Unreachable upstreams are unhealthy.
+	config.fallback_upstreams.push(Upstream {
+		mirror_url: "rsync://127.0.0.1:9/debs/".parse()?,
+		http_url: "http://127.0.0.1:9/debs/".parse()?,
+	});
+	assert!(healthy_upstreams(&config, &client, &keyring_store)
+		.await
+		.is_err());
+	Ok(())
+}
@@ -1,14 +1,25 @@
 use std::{
-	fs::File,
-	io::{BufRead, BufReader, Read},
+	ffi::CString,
+	fs::{File, create_dir_all, hard_link, read_link, remove_file},
+	io::{BufRead, BufReader, ErrorKind, Read},
+	mem::MaybeUninit,
+	os::{
+		fd::AsRawFd,
+		unix::{ffi::OsStrExt, fs::symlink},
+	},
 	path::{Path, PathBuf},
-	sync::Arc,
+	sync::{Arc, Mutex},
 };
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use log::debug;
 use sequoia_openpgp::{fmt::hex, types::HashAlgorithm};
 
-use crate::metadata::{AptMetadataHashAlgm, FileEntry};
+use crate::{
+	cancel::CancelToken,
+	metadata::{AptMetadataHashAlgm, FileEntry},
+	state::SuiteChecksums,
+};
 
 pub fn get_reader(path: &dyn AsRef<Path>) -> Result<Box<BufReader<dyn Read>>> {
 	let path = path.as_ref();
@@ -41,22 +52,27 @@ pub fn get_reader(path: &dyn AsRef<Path>) -> Result<Box<BufReader<dyn Read>>> {
 /// VERY expensive. We only add it to the delta if either the file does not
 /// exist, or the size of the file is not correct (like what rsync normally
 /// does - checksums are performed if only it is instructed to do so).
-pub fn scan_delta(root: &dyn AsRef<Path>, list: &Vec<FileEntry>) -> Vec<String> {
+pub fn scan_delta(
+	root: &dyn AsRef<Path>,
+	list: &Vec<FileEntry>,
+	cancel: &CancelToken,
+) -> Result<Vec<FileEntry>> {
 	let root = root.as_ref();
 	let mut files = Vec::new();
 	for f in list {
+		cancel.check()?;
 		let full_path = root.join(&f.path);
 		if !full_path.exists() {
-			files.push(f.path.clone());
+			files.push(f.clone());
 		} else if let Ok(m) = full_path.metadata() {
 			if m.len() != f.size {
-				files.push(f.path.clone());
+				files.push(f.clone());
 			}
 		} else {
-			files.push(f.path.clone());
+			files.push(f.clone());
 		}
 	}
-	files
+	Ok(files)
 }
 
 pub fn checksum_file(
@@ -93,3 +109,215 @@ pub fn checksum_file(
 	}
 	Ok(())
 }
+
+/// Like [`checksum_file`], but files verified before with the same checksum
+/// are not hashed again.
+pub fn checksum_file_cached(
+	algm: AptMetadataHashAlgm,
+	path: Arc<PathBuf>,
+	expected: Arc<String>,
+	checksums: &Mutex<SuiteChecksums>,
+) -> Result<()> {
+	let metadata = std::fs::metadata(path.as_path())?;
+	if checksums
+		.lock()
+		.unwrap()
+		.is_verified(&metadata, algm, &expected)
+	{
+		return Ok(());
+	}
+	checksum_file(algm, path, expected.clone())?;
+	checksums.lock().unwrap().insert(&metadata, algm, &expected);
+	Ok(())
+}
+
+/// Remove the file at `path` if there is one. Files in a new snapshot might
+/// be hard links to the published ones, so they must be replaced, never
+/// written through.
+pub fn remove_existing(path: &dyn AsRef<Path>) -> Result<()> {
+	let path = path.as_ref();
+	match remove_file(path) {
+		Err(e) if e.kind() != ErrorKind::NotFound => {
+			Err(e).context(format!("Failed to remove {}", path.display()))
+		}
+		_ => Ok(()),
+	}
+}
+
+/// Make `dst` a hard link to `src`. If that is not possible (e.g. they are on
+/// different file systems), try to reflink it, then fall back to a copy. An
+/// existing `dst` is replaced.
+pub fn link_or_copy(src: &dyn AsRef<Path>, dst: &dyn AsRef<Path>) -> Result<()> {
+	let src = src.as_ref();
+	let dst = dst.as_ref();
+	remove_existing(&dst)?;
+	match hard_link(src, dst) {
+		Ok(()) => return Ok(()),
+		Err(e) => debug!(
+			"Unable to hard link {} to {}: {}",
+			src.display(),
+			dst.display(),
+			e
+		),
+	}
+	let src_fd = File::open(src).context(format!("Failed to open {}", src.display()))?;
+	let dst_fd = File::options()
+		.create_new(true)
+		.write(true)
+		.open(dst)
+		.context(format!("Failed to create {}", dst.display()))?;
+	// SAFETY: Both file descriptors are valid for the duration of the call.
+	if unsafe { libc::ioctl(dst_fd.as_raw_fd(), libc::FICLONE, src_fd.as_raw_fd()) } == 0 {
+		return Ok(());
+	}
+	drop(dst_fd);
+	std::fs::copy(src, dst).context(format!(
+		"Failed to copy '{}' to '{}'",
+		src.display(),
+		dst.display()
+	))?;
+	Ok(())
+}
+
+/// Format a size in bytes for humans, e.g. "1.5 GiB".
+pub fn format_size(bytes: u64) -> String {
+	const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+	let mut size = bytes as f64;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+	if unit == 0 {
+		format!("{} B", bytes)
+	} else {
+		format!("{:.1} {}", size, UNITS[unit])
+	}
+}
+
+/// Returns the SHA256 digest of the given data in hex.
+pub fn sha256_hex(data: &[u8]) -> Result<String> {
+	let mut hasher = HashAlgorithm::SHA256.context()?.for_digest();
+	hasher.update(data);
+	let mut digest = vec![0; hasher.digest_size()];
+	hasher.digest(&mut digest)?;
+	Ok(hex::encode(digest).to_ascii_lowercase())
+}
+
+/// Recreate the directory tree at `src` under `dst`, with hard links to the
+/// files (see [`link_or_copy`]) and copies of the symbolic links.
+pub fn hard_link_tree(
+	src: &dyn AsRef<Path>,
+	dst: &dyn AsRef<Path>,
+	cancel: &CancelToken,
+) -> Result<()> {
+	let src = src.as_ref();
+	let dst = dst.as_ref();
+	for entry in walkdir::WalkDir::new(src).follow_links(false) {
+		cancel.check()?;
+		let entry = entry?;
+		let target = dst.join(entry.path().strip_prefix(src)?);
+		let file_type = entry.file_type();
+		if file_type.is_dir() {
+			create_dir_all(&target)
+				.context(format!("Failed to create {}", target.display()))?;
+		} else if file_type.is_symlink() {
+			symlink(read_link(entry.path())?, &target)
+				.context(format!("Failed to create {}", target.display()))?;
+		} else {
+			link_or_copy(&entry.path(), &target)?;
+		}
+	}
+	Ok(())
+}
+
+/// Returns the total size and the available space (for unprivileged users)
+/// of the file system containing the given path, in bytes.
+pub fn disk_usage(path: &dyn AsRef<Path>) -> Result<(u64, u64)> {
+	let path = path.as_ref();
+	let c_path = CString::new(path.as_os_str().as_bytes())
+		.context(format!("Invalid path {}", path.display()))?;
+	let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+	// SAFETY: c_path is a valid NUL-terminated string, and stat is only
+	// read after statvfs(3) reports success.
+	let stat = unsafe {
+		if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+			return Err(std::io::Error::last_os_error())
+				.context(format!("statvfs() failed on {}", path.display()));
+		}
+		stat.assume_init()
+	};
+	let frsize = stat.f_frsize as u64;
+	Ok((stat.f_blocks as u64 * frsize, stat.f_bavail as u64 * frsize))
+}
+
+/// An empty directory for the test `name`, unique to the process.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> Result<PathBuf> {
+	let dir = std::env::temp_dir().join(format!("aosc-mirror-{}-{}", name, std::process::id()));
+	// Left over by a failed run of the test
+	let _ = std::fs::remove_dir_all(&dir);
+	create_dir_all(&dir)?;
+	Ok(dir)
+}
+
+#[test]
+fn test_checksum_cache() -> Result<()> {
+	use crate::state::ChecksumCache;
+
+	let dir = test_dir("checksums")?;
+	let path = dir.join("Packages");
+	std::fs::write(&path, "")?;
+	let empty = Arc::new(
+		"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+	);
+	let mut cache = ChecksumCache::default();
+	let checksums = Mutex::new(cache.suite("stable"));
+	checksum_file_cached(
+		AptMetadataHashAlgm::SHA256,
+		Arc::new(path.clone()),
+		empty.clone(),
+		&checksums,
+	)?;
+	let linked = dir.join("Packages.linked");
+	link_or_copy(&path, &linked)?;
+	// A hard link shares the inode, thus the cache entry.
+	let metadata = std::fs::metadata(&linked)?;
+	assert!(checksums.lock().unwrap().is_verified(
+		&metadata,
+		AptMetadataHashAlgm::SHA256,
+		&empty
+	));
+	assert!(!checksums.lock().unwrap().is_verified(
+		&metadata,
+		AptMetadataHashAlgm::SHA512,
+		&empty
+	));
+	cache.finish_suite("stable", checksums.into_inner().unwrap());
+	cache.save(&dir)?;
+	let mut cache = ChecksumCache::load(&dir)?;
+	assert!(cache
+		.suite("stable")
+		.is_verified(&metadata, AptMetadataHashAlgm::SHA256, &empty));
+	std::fs::remove_dir_all(&dir)?;
+	Ok(())
+}
+
+#[test]
+fn test_link_or_copy_replaces() -> Result<()> {
+	let dir = test_dir("link")?;
+	// The new snapshot starts as hard links to the published one.
+	let published = dir.join("published");
+	let snapshot = dir.join("snapshot");
+	std::fs::write(&published, "old")?;
+	hard_link(&published, &snapshot)?;
+	let downloaded = dir.join("downloaded");
+	std::fs::write(&downloaded, "new")?;
+	link_or_copy(&downloaded, &snapshot)?;
+	assert_eq!(std::fs::read_to_string(&snapshot)?, "new");
+	assert_eq!(std::fs::read_to_string(&published)?, "old");
+	remove_existing(&snapshot)?;
+	remove_existing(&snapshot)?;
+	std::fs::remove_dir_all(&dir)?;
+	Ok(())
+}
@@ -8,7 +8,8 @@ use std::{
 
 use anyhow::{Context, Result, anyhow, bail};
 use base64::{Engine, prelude::BASE64_STANDARD};
-use ed25519_dalek::{Signature, VerifyingKey};
+use chrono::Utc;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey, ed25519::signature::SignerMut};
 use log::{debug, info, warn};
 use sequoia_openpgp::{
 	Cert, KeyHandle, KeyID,
@@ -24,6 +25,10 @@ use sequoia_openpgp::{
 };
 use walkdir::WalkDir;
 
+/// Maximum age of a signed request to endpoints other than /do-sync, in
+/// seconds.
+pub const MAX_REQUEST_AGE: i64 = 300;
+
 struct Helper<'a> {
 	store: &'a PgpKeyringStore,
 }
@@ -31,6 +36,7 @@ struct Helper<'a> {
 static SP: StandardPolicy = StandardPolicy::new();
 
 impl VerificationHelper for Helper<'_> {
+	#[allow(clippy::collapsible_if)]
 	fn get_certs(&mut self, ids: &[KeyHandle]) -> Result<Vec<Cert>> {
 		let mut res = Vec::new();
 		for k in ids {
@@ -67,7 +73,9 @@ pub struct PgpKeyringStoreEnt {
 
 pub type PgpKeyringStore = HashMap<KeyID, PgpKeyringStoreEnt>;
 
-pub async fn init_pgp_keyringstore(keystore_dir: &dyn AsRef<Path>) -> Result<PgpKeyringStore> {
+pub async fn init_pgp_keyringstore(
+	keystore_dir: &(dyn AsRef<Path> + Sync),
+) -> Result<PgpKeyringStore> {
 	info!("Initializing APT trusted keys ...");
 	info!("- Using directory {}", keystore_dir.as_ref().display());
 	let walkdir = WalkDir::new(keystore_dir).max_depth(2).follow_links(true);
@@ -193,6 +201,74 @@ pub fn verify_request_signature(
 	bail!("Unknown signature - Check your public keys");
 }
 
+/// Build the message to sign for the given action. The action is prefixed to
+/// the timestamp, so that a signature for one endpoint can not be replayed
+/// against another one.
+pub fn action_message(action: &str, timestamp: i64) -> String {
+	format!("{}:{}", action, timestamp)
+}
+
+/// Verify a signed request to an endpoint other than /do-sync.
+/// Requests older than [`MAX_REQUEST_AGE`] are rejected.
+pub fn verify_action_signature(
+	action: &str,
+	timestamp: i64,
+	sig: &dyn AsRef<str>,
+	keys: &Vec<VerifyingKey>,
+) -> Result<()> {
+	let age = Utc::now().timestamp() - timestamp;
+	if age.abs() > MAX_REQUEST_AGE {
+		bail!("Request expired, check the clock of both sides");
+	}
+	verify_request_signature(&action_message(action, timestamp), sig, keys)
+}
+
+/// Decode the base64 encoded Ed25519 public keys of the origin servers.
+pub fn decode_pubkeys(keys: &[String]) -> Result<Vec<VerifyingKey>> {
+	let mut pubkeys = Vec::new();
+	for pubkey in keys {
+		let bytes = BASE64_STANDARD
+			.decode(pubkey)
+			.context("Failed to decode server public key as base64 text")?;
+		let bytes: [u8; 32] = bytes.try_into().map_err(|_| anyhow!("Unexpected length; Public keys must be 32 bytes long (that is 45 charaters in base64 with padding"))?;
+		pubkeys.push(VerifyingKey::from_bytes(&bytes)?);
+	}
+	Ok(pubkeys)
+}
+
+/// Decode a base64 encoded Ed25519 private key, as generated by
+/// `sync-invoker genkey`.
+pub fn decode_signing_key(content: &dyn AsRef<str>) -> Result<SigningKey> {
+	let key = BASE64_STANDARD
+		.decode(content.as_ref().trim())
+		.context("Failed to decode the private key as base64 text")?;
+	let bytes = key
+		.try_into()
+		.map_err(|_| anyhow!("Unexpected length; Private keys must be 32 bytes long"))?;
+	Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Sign the given action with the timestamp, returns the signature in base64.
+pub fn sign_action(key: &mut SigningKey, action: &str, timestamp: i64) -> String {
+	BASE64_STANDARD.encode(key
+		.sign(action_message(action, timestamp).as_bytes())
+		.to_bytes())
+}
+
+#[test]
+fn test_action_signature() -> Result<()> {
+	let mut key = SigningKey::from_bytes(&[7u8; 32]);
+	let keys = vec![key.verifying_key()];
+	let now = Utc::now().timestamp();
+	let sig = sign_action(&mut key, "events", now);
+	verify_action_signature("events", now, &sig, &keys)?;
+	assert!(verify_action_signature("cancel", now, &sig, &keys).is_err());
+	assert!(verify_request_signature(&now.to_string(), &sig, &keys).is_err());
+	let sig = sign_action(&mut key, "events", now - MAX_REQUEST_AGE - 1);
+	assert!(verify_action_signature("events", now - MAX_REQUEST_AGE - 1, &sig, &keys).is_err());
+	Ok(())
+}
+
 #[tokio::test]
 async fn test_keystore() -> Result<()> {
 	env_logger::builder()